@@ -0,0 +1,216 @@
+//! A dedicated server, without StereoKit. Run it with the path of its config file, `server.toml`
+//! by default, and type `help` for the admin commands. Every setting is optional:
+//!
+//! ```toml
+//! name = "Workshop"
+//! discoverable = true
+//! bind_address = "0.0.0.0"
+//! port = 6000
+//! tick_rate = 60.0
+//! persistence_path = "world.bin"
+//! save_interval = 30
+//! max_players = 16
+//! ```
+
+use bevy::log::LogPlugin;
+use bevy_app::{App, AppExit};
+use bevy_ecs::event::ManualEventReader;
+use bevy_ecs::prelude::{Events, Resource, World};
+use bevy_quinnet::shared::ClientId;
+use serde::Deserialize;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use stereokit_bevy::networking::admin::{connected_players, ClearWorld, KickClient};
+use stereokit_bevy::networking::config::NetworkConfig;
+use stereokit_bevy::networking::discovery::DiscoveryConfig;
+use stereokit_bevy::networking::persistence::{PersistenceConfig, SaveWorld};
+use stereokit_bevy::networking::StereoKitBevyServerPlugins;
+
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct ServerConfig {
+    /// What the server is listed as on the local network.
+    name: String,
+    /// Whether it answers clients looking for servers on the local network.
+    discoverable: bool,
+    bind_address: String,
+    port: u16,
+    /// Updates per second.
+    tick_rate: f64,
+    /// Models are only saved and loaded with a path.
+    persistence_path: Option<PathBuf>,
+    /// Seconds between saves.
+    save_interval: u64,
+    max_players: Option<usize>,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        let network = NetworkConfig::default();
+        Self {
+            name: DiscoveryConfig::default().name,
+            discoverable: false,
+            bind_address: network.bind_address,
+            port: network.port,
+            tick_rate: 60.0,
+            persistence_path: None,
+            save_interval: PersistenceConfig::default().interval.as_secs(),
+            max_players: None,
+        }
+    }
+}
+
+fn load_config(path: &Path) -> ServerConfig {
+    let text = match std::fs::read_to_string(path) {
+        Err(_) => {
+            println!("no config at {}, using the defaults", path.display());
+            return ServerConfig::default();
+        }
+        Ok(text) => text,
+    };
+    match toml::from_str(&text) {
+        Err(error) => {
+            eprintln!("couldn't read {}: {error}", path.display());
+            std::process::exit(1);
+        }
+        Ok(config) => config,
+    }
+}
+
+/// Lines typed into the console, read on a thread of their own so the server never waits on them.
+#[derive(Resource)]
+struct Console(Mutex<Receiver<String>>);
+
+impl Console {
+    fn open() -> Self {
+        let (sender, receiver) = mpsc::channel();
+        std::thread::spawn(move || {
+            for line in std::io::stdin().lines() {
+                let sent = line.map(|line| sender.send(line).is_ok()).unwrap_or(false);
+                if !sent {
+                    break;
+                }
+            }
+        });
+        Self(Mutex::new(receiver))
+    }
+}
+
+fn run_console(world: &mut World) {
+    let lines: Vec<String> = world.resource::<Console>().0.lock().unwrap().try_iter().collect();
+    for line in lines {
+        run_command(world, &line);
+    }
+}
+
+const HELP: &str = "\
+list                      who is connected, and in which room
+kick <client id> [reason] disconnect a client, it doesn't reconnect
+clear                     remove every model
+save                      save the models now
+quit                      save and stop the server";
+
+fn run_command(world: &mut World, line: &str) {
+    let mut words = line.split_whitespace();
+    match words.next() {
+        None => {}
+        Some("help") => println!("{HELP}"),
+        Some("list") => {
+            let players = connected_players(world);
+            if players.is_empty() {
+                println!("nobody is connected");
+            }
+            for player in players {
+                println!(
+                    "{:>20}  {:<24}  {}",
+                    player.client_id,
+                    player.name,
+                    player.room.as_deref().unwrap_or("-")
+                );
+            }
+        }
+        Some("kick") => {
+            let client_id = match words.next().and_then(|id| id.parse::<ClientId>().ok()) {
+                None => {
+                    println!("usage: kick <client id> [reason]");
+                    return;
+                }
+                Some(client_id) => client_id,
+            };
+            if !connected_players(world)
+                .iter()
+                .any(|player| player.client_id == client_id)
+            {
+                println!("no client {client_id}");
+                return;
+            }
+            let reason = words.collect::<Vec<_>>().join(" ");
+            let reason = if reason.is_empty() {
+                "kicked by the server".to_string()
+            } else {
+                reason
+            };
+            world.send_event(KickClient { client_id, reason });
+            println!("kicked {client_id}");
+        }
+        Some("clear") => {
+            world.send_event(ClearWorld);
+            println!("clearing the world");
+        }
+        Some("save") => {
+            if world.resource::<PersistenceConfig>().path.is_none() {
+                println!("there's no persistence_path to save to");
+                return;
+            }
+            world.send_event(SaveWorld);
+            println!("saving");
+        }
+        Some("quit") => world.send_event(AppExit),
+        Some(command) => println!("unknown command {command}, try help"),
+    }
+}
+
+fn main() {
+    let path = std::env::args().nth(1).unwrap_or_else(|| "server.toml".to_string());
+    let config = load_config(Path::new(&path));
+    let tick = Duration::from_secs_f64(1.0 / config.tick_rate.max(1.0));
+
+    let mut app = App::new();
+    app.insert_resource(NetworkConfig {
+        bind_address: config.bind_address,
+        port: config.port,
+        max_players: config.max_players,
+        ..Default::default()
+    });
+    app.insert_resource(PersistenceConfig {
+        path: config.persistence_path,
+        interval: Duration::from_secs(config.save_interval),
+    });
+    app.insert_resource(DiscoveryConfig {
+        enabled: config.discoverable,
+        name: config.name,
+        ..Default::default()
+    });
+    app.add_plugins(StereoKitBevyServerPlugins);
+    app.add_plugin(LogPlugin::default());
+    app.insert_resource(Console::open());
+    app.add_system(run_console);
+    // Instead of the plugin's loop, which spins as fast as it can.
+    app.set_runner(move |mut app: App| {
+        let mut exit = ManualEventReader::<AppExit>::default();
+        loop {
+            let start = Instant::now();
+            app.update();
+            if exit.iter(app.world.resource::<Events<AppExit>>()).count() > 0 {
+                break;
+            }
+            if let Some(rest) = tick.checked_sub(start.elapsed()) {
+                std::thread::sleep(rest);
+            }
+        }
+    });
+    println!("listening on port {}, type help for the admin commands", config.port);
+    app.run();
+}
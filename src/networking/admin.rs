@@ -0,0 +1,89 @@
+use crate::networking::handshake_server::{AcceptedClients, Rejected};
+use crate::networking::interest::Interest;
+use crate::networking::model_client::ModelMsgClient;
+use crate::networking::model_server::ServerModel;
+use crate::networking::room_server::Rooms;
+use crate::networking::roster_server::ServerRoster;
+use crate::networking::transport::NetServer;
+use crate::networking::OnServer;
+use bevy_ecs::prelude::{Commands, Entity, EventReader, Query, Res, ResMut, With, World};
+use bevy_quinnet::shared::ClientId;
+use bevy_time::Time;
+use leknet::ServerEntity;
+
+/// Send this on the server to disconnect a client. It's told the reason and doesn't reconnect.
+pub struct KickClient {
+    pub client_id: ClientId,
+    pub reason: String,
+}
+
+/// Send this on the server to remove every model, from the server and from every client.
+pub struct ClearWorld;
+
+/// A client that passed the handshake, as the server sees it.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ConnectedPlayer {
+    pub client_id: ClientId,
+    /// Empty until the client sent its profile.
+    pub name: String,
+    pub room: Option<String>,
+}
+
+/// Every client on the server, by id.
+pub fn connected_players(world: &World) -> Vec<ConnectedPlayer> {
+    let roster = world.resource::<ServerRoster>();
+    let rooms = world.resource::<Rooms>();
+    let mut players: Vec<ConnectedPlayer> = world
+        .resource::<AcceptedClients>()
+        .0
+        .iter()
+        .map(|client_id| {
+            let profile = roster.0 .0.get(client_id);
+            ConnectedPlayer {
+                client_id: *client_id,
+                name: profile.map(|profile| profile.name.clone()).unwrap_or_default(),
+                room: rooms.room_of(*client_id).map(str::to_string),
+            }
+        })
+        .collect();
+    players.sort_by_key(|player| player.client_id);
+    players
+}
+
+pub(crate) fn kick_clients(
+    mut kicks: EventReader<KickClient>,
+    mut accepted: ResMut<AcceptedClients>,
+    mut rejected: ResMut<Rejected>,
+    mut server: NetServer,
+    time: Res<Time>,
+) {
+    for kick in kicks.iter() {
+        // It's out of the session right away, whatever it sends before the disconnect is ignored.
+        accepted.0.remove(&kick.client_id);
+        rejected.reject(&mut server, kick.client_id, kick.reason.clone(), time.elapsed());
+    }
+}
+
+pub(crate) fn clear_world(
+    mut clear: EventReader<ClearWorld>,
+    models: Query<(Entity, &ServerModel), With<OnServer>>,
+    interest: Interest,
+    mut server: NetServer,
+    mut commands: Commands,
+) {
+    if clear.iter().count() == 0 {
+        return;
+    }
+    for (entity, model) in &models {
+        commands.entity(entity).despawn();
+        // Clients take the children down along with their root.
+        if model.0.parent.is_some() {
+            continue;
+        }
+        for client_id in server.clients() {
+            if interest.knows(client_id, entity) {
+                server.send(client_id, ModelMsgClient::ModelRemoved(ServerEntity(entity)));
+            }
+        }
+    }
+}
@@ -0,0 +1,325 @@
+use crate::networking::asset_server::AssetMsgServer;
+use crate::networking::transport::{receive_from_server, NetClient};
+use crate::networking::AssetId;
+use crate::ModelInfo;
+use bevy::log::warn;
+use bevy_app::App;
+use bevy_ecs::prelude::{
+    Commands, Component, Entity, EventReader, NonSend, Query, Res, ResMut, Resource, World,
+};
+use bevy_ecs::system::SystemState;
+use bevy_quinnet::shared::channel::ChannelType;
+use glam::Vec3;
+use leknet::{ClientMessage, TypeName};
+use serde::{Deserialize, Serialize};
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::ops::Range;
+use stereokit::{Material, Model, SkDraw, StereoKitMultiThread};
+
+/// How model bytes are split up and how fast they're sent, used by both client and server.
+#[derive(Resource, Clone, Debug)]
+pub struct AssetStreamConfig {
+    pub chunk_size: usize,
+    pub chunks_per_frame: usize,
+    /// Bigger assets are refused, so a peer can't make the other side allocate whatever it likes.
+    pub max_asset_size: usize,
+    /// Uploads the server has going with one client at a time.
+    pub max_uploads_per_client: usize,
+}
+
+impl Default for AssetStreamConfig {
+    fn default() -> Self {
+        Self {
+            chunk_size: 16 * 1024,
+            chunks_per_frame: 8,
+            max_asset_size: 64 * 1024 * 1024,
+            max_uploads_per_client: 4,
+        }
+    }
+}
+
+/// Sent while a model's bytes are downloading.
+pub struct AssetProgress {
+    pub asset: AssetId,
+    pub received: usize,
+    pub total: usize,
+}
+
+/// Sent once a model's bytes are complete, the placeholders using it get swapped right after.
+pub struct AssetLoaded(pub AssetId);
+
+/// On a model that is drawn as a placeholder until its asset arrives.
+#[derive(Component, Clone, Copy, Debug)]
+pub struct PendingAsset(pub AssetId);
+
+/// Model bytes this client has, either its own models' or downloaded.
+#[derive(Resource, Default)]
+pub struct AssetCache(pub HashMap<AssetId, Vec<u8>>);
+
+#[derive(Resource, Default)]
+pub(crate) struct AssetUploads {
+    queue: VecDeque<(AssetId, Vec<u8>, usize)>,
+}
+
+impl AssetUploads {
+    pub(crate) fn queue(&mut self, asset: AssetId, bytes: Vec<u8>) {
+        self.queue.push_back((asset, bytes, 0));
+    }
+}
+
+#[derive(Resource, Default)]
+struct AssetDownloads {
+    requested: HashSet<AssetId>,
+    downloads: HashMap<AssetId, PartialAsset>,
+}
+
+/// An asset's bytes while its chunks arrive, in any order and maybe more than once.
+pub(crate) struct PartialAsset {
+    bytes: Vec<u8>,
+    /// The ranges that arrived so far, sorted and never touching each other.
+    arrived: Vec<Range<usize>>,
+}
+
+impl PartialAsset {
+    /// `None` when `total` is more than `max_size`.
+    pub(crate) fn new(total: usize, max_size: usize) -> Option<Self> {
+        (total <= max_size).then(|| Self {
+            bytes: vec![0; total],
+            arrived: vec![],
+        })
+    }
+
+    pub(crate) fn received(&self) -> usize {
+        self.arrived.iter().map(|range| range.len()).sum()
+    }
+
+    pub(crate) fn is_complete(&self) -> bool {
+        self.received() == self.bytes.len()
+    }
+
+    /// Copies a chunk in. False when it's for an asset of another size or reaches past the end.
+    pub(crate) fn insert(&mut self, total: usize, offset: usize, chunk: &[u8]) -> bool {
+        let end = match offset.checked_add(chunk.len()) {
+            Some(end) if total == self.bytes.len() && end <= self.bytes.len() => end,
+            _ => return false,
+        };
+        self.bytes[offset..end].copy_from_slice(chunk);
+        let mut range = offset..end;
+        self.arrived.retain(|arrived| {
+            if arrived.end < range.start || arrived.start > range.end {
+                return true;
+            }
+            range = range.start.min(arrived.start)..range.end.max(arrived.end);
+            false
+        });
+        let at = self
+            .arrived
+            .partition_point(|arrived| arrived.start < range.start);
+        self.arrived.insert(at, range);
+        true
+    }
+
+    /// The assembled bytes, `None` unless they hash to `asset`.
+    pub(crate) fn finish(self, asset: AssetId) -> Option<Vec<u8>> {
+        (AssetId::new(&self.bytes) == asset).then_some(self.bytes)
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum AssetMsgClient {
+    Chunk {
+        asset: AssetId,
+        total: usize,
+        offset: usize,
+        bytes: Vec<u8>,
+    },
+    /// The server is missing an asset this client announced a model of.
+    RequestUpload(AssetId),
+}
+
+impl TypeName for AssetMsgClient {
+    fn get_type_name() -> String {
+        "stereokit_bevy::networking::AssetMsgClient".to_string()
+    }
+}
+
+impl ClientMessage for AssetMsgClient {
+    fn client(self, world: &mut World) {
+        match self {
+            AssetMsgClient::Chunk {
+                asset,
+                total,
+                offset,
+                bytes,
+            } => chunk_msg(world, asset, total, offset, bytes),
+            AssetMsgClient::RequestUpload(asset) => request_upload_msg(world, asset),
+        }
+    }
+
+    fn _client(world: &mut World, msg_bytes: &[u8]) {
+        if let Some(msg) = receive_from_server::<Self>(world, msg_bytes) {
+            msg.client(world)
+        }
+    }
+
+    fn channel_type(&self) -> ChannelType {
+        match self {
+            // Its own stream for every chunk, so big models don't hold up the ordered messages.
+            AssetMsgClient::Chunk { .. } => ChannelType::UnorderedReliable,
+            AssetMsgClient::RequestUpload(_) => ChannelType::OrderedReliable,
+        }
+    }
+
+    fn plugin(app: &mut App) {
+        app.init_resource::<AssetStreamConfig>();
+        app.init_resource::<AssetCache>();
+        app.init_resource::<AssetUploads>();
+        app.init_resource::<AssetDownloads>();
+        app.add_event::<AssetProgress>();
+        app.add_event::<AssetLoaded>();
+        app.add_system(send_upload_chunks);
+        app.add_system(swap_loaded_models);
+    }
+}
+
+/// Bytes StereoKit can't load are drawn as the placeholder instead.
+pub(crate) fn model_from_mem(sk: &SkDraw, name: &str, mem: &[u8]) -> Model {
+    match sk.model_create_mem(name, mem, None) {
+        Ok(model) => model,
+        Err(error) => {
+            warn!("couldn't load model {name}: {error:?}");
+            placeholder_model(sk)
+        }
+    }
+}
+
+/// A small cube, drawn while a model's bytes are missing.
+pub(crate) fn placeholder_model(sk: &SkDraw) -> Model {
+    sk.model_create_mesh(sk.mesh_gen_cube(Vec3::splat(0.1), 1), Material::DEFAULT)
+}
+
+/// Asks the server for an asset unless it was asked for already.
+pub(crate) fn request_asset(world: &mut World, asset: AssetId) {
+    let mut system_state: SystemState<(ResMut<AssetDownloads>, NetClient)> =
+        SystemState::new(world);
+    let (mut downloads, mut client) = system_state.get_mut(world);
+    if !downloads.requested.insert(asset) {
+        return;
+    }
+    client.send(AssetMsgServer::RequestAsset(asset));
+}
+
+fn request_upload_msg(world: &mut World, asset: AssetId) {
+    let bytes = match world.resource::<AssetCache>().0.get(&asset) {
+        None => return,
+        Some(bytes) => bytes.clone(),
+    };
+    world.resource_mut::<AssetUploads>().queue(asset, bytes);
+}
+
+fn chunk_msg(world: &mut World, asset: AssetId, total: usize, offset: usize, bytes: Vec<u8>) {
+    let mut system_state: SystemState<(
+        Res<AssetStreamConfig>,
+        ResMut<AssetDownloads>,
+        ResMut<AssetCache>,
+    )> = SystemState::new(world);
+    let (config, mut downloads, mut cache) = system_state.get_mut(world);
+    if cache.0.contains_key(&asset) || !downloads.requested.contains(&asset) {
+        return;
+    }
+    let download = match downloads.downloads.entry(asset) {
+        Entry::Occupied(entry) => entry.into_mut(),
+        Entry::Vacant(entry) => match PartialAsset::new(total, config.max_asset_size) {
+            None => {
+                warn!(
+                    "asset {asset:?} is {total} bytes, more than the {} allowed",
+                    config.max_asset_size
+                );
+                return;
+            }
+            Some(download) => entry.insert(download),
+        },
+    };
+    if !download.insert(total, offset, &bytes) {
+        warn!("chunk at {offset} doesn't fit asset {asset:?}, ignored");
+        return;
+    }
+    let received = download.received();
+    let complete = download.is_complete();
+    if complete {
+        let download = downloads.downloads.remove(&asset).unwrap();
+        // Asked for again the next time a model needs it.
+        downloads.requested.remove(&asset);
+        match download.finish(asset) {
+            None => {
+                warn!("asset {asset:?} doesn't match its id, dropped");
+                return;
+            }
+            Some(bytes) => cache.0.insert(asset, bytes),
+        };
+    }
+    world.send_event(AssetProgress {
+        asset,
+        received,
+        total,
+    });
+    if complete {
+        world.send_event(AssetLoaded(asset));
+    }
+}
+
+fn send_upload_chunks(
+    mut uploads: ResMut<AssetUploads>,
+    mut client: NetClient,
+    config: Res<AssetStreamConfig>,
+) {
+    if !client.is_connected() {
+        return;
+    }
+    for _ in 0..config.chunks_per_frame {
+        let (asset, bytes, offset) = match uploads.queue.front_mut() {
+            None => return,
+            Some(upload) => upload,
+        };
+        let end = (*offset + config.chunk_size).min(bytes.len());
+        client.send(AssetMsgServer::Chunk {
+            asset: *asset,
+            total: bytes.len(),
+            offset: *offset,
+            bytes: bytes[*offset..end].to_vec(),
+        });
+        *offset = end;
+        if end >= bytes.len() {
+            uploads.queue.pop_front();
+        }
+    }
+}
+
+fn swap_loaded_models(
+    mut loaded: EventReader<AssetLoaded>,
+    mut query: Query<(Entity, &PendingAsset, &mut ModelInfo)>,
+    cache: Res<AssetCache>,
+    sk: Option<NonSend<SkDraw>>,
+    mut commands: Commands,
+) {
+    for AssetLoaded(asset) in loaded.iter() {
+        let bytes = match cache.0.get(asset) {
+            None => continue,
+            Some(bytes) => bytes,
+        };
+        for (entity, pending, mut model_info) in query.iter_mut() {
+            if pending.0 != *asset {
+                continue;
+            }
+            if let ModelInfo::Mem { name, mem } = &mut *model_info {
+                *mem = bytes.clone();
+                let mut entity_commands = commands.entity(entity);
+                entity_commands.remove::<PendingAsset>();
+                if let Some(sk) = &sk {
+                    entity_commands.insert(model_from_mem(sk, name, bytes));
+                }
+            }
+        }
+    }
+}
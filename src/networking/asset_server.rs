@@ -0,0 +1,278 @@
+use crate::networking::asset_client::{AssetMsgClient, AssetStreamConfig, PartialAsset};
+use crate::networking::model_server::ServerModel;
+use crate::networking::reconnect::ClientResumed;
+use crate::networking::transport::{receive_from_client, NetServer};
+use crate::networking::AssetId;
+use bevy::log::warn;
+use bevy_app::App;
+use bevy_ecs::prelude::{EventReader, Query, Res, ResMut, Resource, World};
+use bevy_ecs::schedule::IntoSystemConfig;
+use bevy_ecs::system::SystemState;
+use bevy_quinnet::server::ConnectionLostEvent;
+use bevy_quinnet::shared::channel::ChannelType;
+use bevy_quinnet::shared::ClientId;
+use leknet::{ServerMessage, TypeName};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet, VecDeque};
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum AssetMsgServer {
+    Chunk {
+        asset: AssetId,
+        total: usize,
+        offset: usize,
+        bytes: Vec<u8>,
+    },
+    RequestAsset(AssetId),
+}
+
+impl TypeName for AssetMsgServer {
+    fn get_type_name() -> String {
+        "stereokit_bevy::networking::AssetMsgServer".to_string()
+    }
+}
+
+impl ServerMessage for AssetMsgServer {
+    fn server(self, world: &mut World, client_id: ClientId) {
+        match self {
+            AssetMsgServer::Chunk {
+                asset,
+                total,
+                offset,
+                bytes,
+            } => chunk_msg(world, client_id, asset, total, offset, bytes),
+            AssetMsgServer::RequestAsset(asset) => request_asset_msg(world, client_id, asset),
+        }
+    }
+
+    fn _server(world: &mut World, msg_bytes: &[u8], client_id: ClientId) {
+        if let Some(msg) = receive_from_client::<Self>(world, msg_bytes, client_id) {
+            msg.server(world, client_id);
+        }
+    }
+
+    fn channel_type(&self) -> ChannelType {
+        match self {
+            AssetMsgServer::Chunk { .. } => ChannelType::UnorderedReliable,
+            AssetMsgServer::RequestAsset(_) => ChannelType::OrderedReliable,
+        }
+    }
+
+    fn plugin(app: &mut App) {
+        app.init_resource::<AssetStreamConfig>();
+        app.init_resource::<ServerAssets>();
+        app.add_system(forget_clients);
+        app.add_system(announce_resumed.after(forget_clients));
+        app.add_system(request_uploads.after(announce_resumed));
+        app.add_system(send_asset_chunks);
+    }
+}
+
+/// Model bytes uploaded by clients, kept so later joiners can download them.
+#[derive(Resource, Default)]
+pub struct ServerAssets {
+    assets: HashMap<AssetId, Vec<u8>>,
+    /// Assets asked of a client, with what arrived of them so far.
+    uploads: HashMap<AssetId, (ClientId, Option<PartialAsset>)>,
+    /// Assets the server is missing that each client announced a model of, and so can upload.
+    announced: HashMap<ClientId, HashSet<AssetId>>,
+    /// Clients that asked for an asset before its upload finished.
+    waiting: HashMap<AssetId, Vec<ClientId>>,
+    sending: VecDeque<(ClientId, AssetId, usize)>,
+}
+
+impl ServerAssets {
+    pub fn get(&self, asset: AssetId) -> Option<&[u8]> {
+        self.assets.get(&asset).map(Vec::as_slice)
+    }
+
+    /// Adds an asset the server already has in full, like one loaded from disk.
+    pub(crate) fn insert(&mut self, asset: AssetId, bytes: Vec<u8>) {
+        self.assets.insert(asset, bytes);
+    }
+
+    /// `client_id` announced a model made of `asset`, it's asked to upload it if the server lacks it.
+    pub(crate) fn announce(&mut self, client_id: ClientId, asset: AssetId) {
+        if !self.assets.contains_key(&asset) {
+            self.announced.entry(client_id).or_default().insert(asset);
+        }
+    }
+
+    /// How many uploads the server has asked of `client_id` and not received yet.
+    pub(crate) fn uploads_from(&self, client_id: ClientId) -> usize {
+        self.uploads.values().filter(|(uploader, _)| *uploader == client_id).count()
+    }
+
+    fn is_announced(&self, asset: AssetId) -> bool {
+        self.announced.values().any(|assets| assets.contains(&asset))
+    }
+
+    fn forget(&mut self, client_id: ClientId) {
+        self.announced.remove(&client_id);
+        self.uploads.retain(|_, (uploader, _)| *uploader != client_id);
+        self.sending.retain(|(receiver, _, _)| *receiver != client_id);
+        for waiting in self.waiting.values_mut() {
+            waiting.retain(|waiting| *waiting != client_id);
+        }
+        let announced = &self.announced;
+        // Nobody left to upload those.
+        self.waiting.retain(|asset, waiting| {
+            !waiting.is_empty() && announced.values().any(|assets| assets.contains(asset))
+        });
+    }
+}
+
+fn chunk_msg(
+    world: &mut World,
+    client_id: ClientId,
+    asset: AssetId,
+    total: usize,
+    offset: usize,
+    bytes: Vec<u8>,
+) {
+    let mut system_state: SystemState<(Res<AssetStreamConfig>, ResMut<ServerAssets>)> =
+        SystemState::new(world);
+    let (config, mut server_assets) = system_state.get_mut(world);
+    // Only what the server asked this client for.
+    let upload = match server_assets.uploads.get_mut(&asset) {
+        Some((uploader, upload)) if *uploader == client_id => upload,
+        _ => return,
+    };
+    if upload.is_none() {
+        match PartialAsset::new(total, config.max_asset_size) {
+            None => {
+                warn!(
+                    "client {client_id} uploads {total} bytes, more than the {} allowed",
+                    config.max_asset_size
+                );
+                server_assets.uploads.remove(&asset);
+                drop_announcement(&mut server_assets, client_id, asset);
+                return;
+            }
+            Some(partial) => *upload = Some(partial),
+        }
+    }
+    let upload = upload.as_mut().unwrap();
+    if !upload.insert(total, offset, &bytes) {
+        warn!("chunk at {offset} from client {client_id} doesn't fit asset {asset:?}, ignored");
+        return;
+    }
+    if !upload.is_complete() {
+        return;
+    }
+    let (_, upload) = server_assets.uploads.remove(&asset).unwrap();
+    match upload.unwrap().finish(asset) {
+        None => {
+            // Somebody else that has it is asked next.
+            warn!("upload from client {client_id} doesn't match asset {asset:?}, dropped");
+            drop_announcement(&mut server_assets, client_id, asset);
+            return;
+        }
+        Some(bytes) => server_assets.assets.insert(asset, bytes),
+    };
+    for announced in server_assets.announced.values_mut() {
+        announced.remove(&asset);
+    }
+    for client_id in server_assets.waiting.remove(&asset).unwrap_or_default() {
+        server_assets.sending.push_back((client_id, asset, 0));
+    }
+}
+
+fn drop_announcement(server_assets: &mut ServerAssets, client_id: ClientId, asset: AssetId) {
+    if let Some(announced) = server_assets.announced.get_mut(&client_id) {
+        announced.remove(&asset);
+    }
+}
+
+fn request_asset_msg(world: &mut World, client_id: ClientId, asset: AssetId) {
+    let mut system_state: SystemState<ResMut<ServerAssets>> = SystemState::new(world);
+    let mut server_assets = system_state.get_mut(world);
+    if server_assets.get(asset).is_some() {
+        server_assets.sending.push_back((client_id, asset, 0));
+    } else if server_assets.is_announced(asset) {
+        let waiting = server_assets.waiting.entry(asset).or_default();
+        if !waiting.contains(&client_id) {
+            waiting.push(client_id);
+        }
+    }
+}
+
+/// Asks clients for the assets they announced and nobody uploads yet, a few at a time per client.
+fn request_uploads(
+    mut server_assets: ResMut<ServerAssets>,
+    mut server: NetServer,
+    config: Res<AssetStreamConfig>,
+) {
+    let server_assets = &mut *server_assets;
+    let clients: Vec<ClientId> = server_assets.announced.keys().copied().collect();
+    for client_id in clients {
+        let mut uploading = server_assets.uploads_from(client_id);
+        let announced: Vec<AssetId> = server_assets.announced[&client_id].iter().copied().collect();
+        for asset in &announced {
+            if uploading >= config.max_uploads_per_client {
+                break;
+            }
+            if server_assets.uploads.contains_key(asset) {
+                continue;
+            }
+            server_assets.uploads.insert(*asset, (client_id, None));
+            server.send(client_id, AssetMsgClient::RequestUpload(*asset));
+            uploading += 1;
+        }
+    }
+}
+
+/// A resumed client doesn't announce its models again, what they still miss is asked of it here.
+fn announce_resumed(
+    mut resumed: EventReader<ClientResumed>,
+    models: Query<&ServerModel>,
+    mut server_assets: ResMut<ServerAssets>,
+) {
+    for resumed in resumed.iter() {
+        for model in models.iter_many(&resumed.owned) {
+            if let Some(asset) = model.0.asset {
+                server_assets.announce(resumed.client_id, asset);
+            }
+        }
+    }
+}
+
+fn forget_clients(mut lost: EventReader<ConnectionLostEvent>, mut server_assets: ResMut<ServerAssets>) {
+    for client in lost.iter() {
+        server_assets.forget(client.id);
+    }
+}
+
+fn send_asset_chunks(
+    mut server_assets: ResMut<ServerAssets>,
+    mut server: NetServer,
+    config: Res<AssetStreamConfig>,
+) {
+    let clients = server.clients();
+    let server_assets = &mut *server_assets;
+    for _ in 0..config.chunks_per_frame {
+        let (client_id, asset, offset) = match server_assets.sending.front_mut() {
+            None => return,
+            Some(sending) => sending,
+        };
+        let bytes = &server_assets.assets[asset];
+        if !clients.contains(client_id) {
+            server_assets.sending.pop_front();
+            continue;
+        }
+        let end = (*offset + config.chunk_size).min(bytes.len());
+        server.send(
+            *client_id,
+            AssetMsgClient::Chunk {
+                asset: *asset,
+                total: bytes.len(),
+                offset: *offset,
+                bytes: bytes[*offset..end].to_vec(),
+            },
+        );
+        *offset = end;
+        if end >= bytes.len() {
+            server_assets.sending.pop_front();
+        }
+    }
+}
@@ -0,0 +1,210 @@
+use crate::networking::asset_client::model_from_mem;
+use crate::networking::hands::PlayerHands;
+use crate::networking::player_client::LocalPlayer;
+use crate::networking::roster_client::Roster;
+use crate::networking::{Player, PlayerId};
+use crate::{ModelBundle, ModelInfo};
+use bevy_ecs::prelude::{Changed, Commands, Component, Entity, Local, NonSend, Or, Query, Res, Resource, With, Without};
+use bevy_hierarchy::{BuildChildren, Children, DespawnRecursiveExt};
+use bevy_transform::prelude::{GlobalTransform, Transform};
+use glam::Vec3;
+use std::collections::HashMap;
+use stereokit::{Color128, Handed, Material, Mesh, Model, RenderLayer, SkDraw, StereoKitDraw, StereoKitMultiThread};
+
+/// What a remote player looks like. Remote players get one from the [`AvatarLibrary`] picked by
+/// their profile, insert your own to change it.
+#[derive(Component, Clone, Debug)]
+pub struct Avatar {
+    pub head: ModelInfo,
+    pub hand: ModelInfo,
+    /// Where the hands rest relative to the head while nothing else moves them.
+    pub hand_offset: Vec3,
+    /// Uses the player's profile color if not set.
+    pub color: Option<Color128>,
+}
+
+impl Default for Avatar {
+    fn default() -> Self {
+        Self {
+            head: ModelInfo::Cube(Vec3::new(0.18, 0.22, 0.2)),
+            hand: ModelInfo::Cube(Vec3::new(0.08, 0.02, 0.1)),
+            hand_offset: Vec3::new(0.2, -0.45, -0.25),
+            color: None,
+        }
+    }
+}
+
+/// Avatars players can pick by name in their [`PlayerProfile`](crate::networking::PlayerProfile).
+/// Unknown names get the `"default"` avatar.
+#[derive(Resource, Clone, Debug)]
+pub struct AvatarLibrary(pub HashMap<String, Avatar>);
+
+impl Default for AvatarLibrary {
+    fn default() -> Self {
+        Self(HashMap::from([("default".to_string(), Avatar::default())]))
+    }
+}
+
+impl AvatarLibrary {
+    pub fn get(&self, name: &str) -> Avatar {
+        self.0
+            .get(name)
+            .or_else(|| self.0.get("default"))
+            .cloned()
+            .unwrap_or_default()
+    }
+}
+
+const JOINT_SIZE: f32 = 0.015;
+
+#[derive(Component)]
+pub struct AvatarHead;
+
+#[derive(Component)]
+pub struct AvatarHand(pub Handed);
+
+/// The library avatar a player got, to tell when its profile picks another one.
+#[derive(Component)]
+struct PickedAvatar(String);
+
+pub(crate) fn attach_avatars(
+    query: Query<
+        (Entity, &PlayerId, Option<&PickedAvatar>, Option<&Avatar>),
+        (With<Player>, Without<LocalPlayer>),
+    >,
+    roster: Res<Roster>,
+    library: Res<AvatarLibrary>,
+    mut commands: Commands,
+) {
+    for (entity, player_id, picked, avatar) in query.iter() {
+        // Wait for the profile so the right avatar is picked.
+        let profile = match roster.0.get(&player_id.0) {
+            None => continue,
+            Some(profile) => profile,
+        };
+        let pick = match (picked, avatar) {
+            (None, None) => true,
+            (Some(picked), _) => picked.0 != profile.avatar,
+            // Inserted by the app, not ours to change.
+            (None, Some(_)) => false,
+        };
+        if pick {
+            commands
+                .entity(entity)
+                .insert((library.get(&profile.avatar), PickedAvatar(profile.avatar.clone())));
+        }
+    }
+}
+
+/// Built avatars take on profile color changes.
+pub(crate) fn recolor_avatars(
+    players: Query<(&Avatar, &PlayerId, &Children)>,
+    mut parts: Query<&mut Color128, Or<(With<AvatarHead>, With<AvatarHand>)>>,
+    roster: Res<Roster>,
+) {
+    if !roster.is_changed() {
+        return;
+    }
+    for (avatar, player_id, children) in players.iter() {
+        let color = match (avatar.color, roster.0.get(&player_id.0)) {
+            (None, Some(profile)) => profile.color,
+            _ => continue,
+        };
+        for child in children.iter() {
+            if let Ok(mut part_color) = parts.get_mut(*child) {
+                *part_color = color;
+            }
+        }
+    }
+}
+
+/// Spawns the head and hands as children of the player, so they follow its replicated transform.
+pub(crate) fn build_avatars(
+    query: Query<(Entity, &Avatar, Option<&PlayerId>, Option<&Children>), Changed<Avatar>>,
+    parts: Query<(), Or<(With<AvatarHead>, With<AvatarHand>)>>,
+    roster: Res<Roster>,
+    sk: Option<NonSend<SkDraw>>,
+    mut commands: Commands,
+) {
+    let sk = match sk {
+        None => return,
+        Some(sk) => sk,
+    };
+    for (entity, avatar, player_id, children) in query.iter() {
+        for child in children.into_iter().flatten() {
+            if parts.contains(*child) {
+                commands.entity(*child).despawn_recursive();
+            }
+        }
+        let color = avatar
+            .color
+            .or_else(|| player_id.and_then(|player_id| roster.0.get(&player_id.0)).map(|profile| profile.color))
+            .unwrap_or(Color128::new(1.0, 1.0, 1.0, 1.0));
+        let head = commands
+            .spawn(part(&sk, &avatar.head, Transform::IDENTITY, color))
+            .insert(AvatarHead)
+            .id();
+        let offset = avatar.hand_offset;
+        let left = commands
+            .spawn(part(&sk, &avatar.hand, Transform::from_translation(offset * Vec3::new(-1.0, 1.0, 1.0)), color))
+            .insert(AvatarHand(Handed::Left))
+            .id();
+        let right = commands
+            .spawn(part(&sk, &avatar.hand, Transform::from_translation(offset), color))
+            .insert(AvatarHand(Handed::Right))
+            .id();
+        commands.entity(entity).push_children(&[head, left, right]);
+    }
+}
+
+fn part(sk: &SkDraw, model_info: &ModelInfo, transform: Transform, color: Color128) -> ModelBundle {
+    let model: Model = match model_info {
+        ModelInfo::Mem { name, mem } => model_from_mem(sk, name, mem),
+        ModelInfo::Cube(size) => sk.model_create_mesh(sk.mesh_gen_cube(*size, 1), Material::DEFAULT),
+    };
+    ModelBundle::new(model, model_info.clone(), transform, color, RenderLayer::LAYER0)
+}
+
+/// Moves the hand models to the replicated hands, or back to rest while a hand isn't tracked.
+pub(crate) fn pose_avatar_hands(
+    players: Query<(&Avatar, &PlayerHands, &Children), Without<LocalPlayer>>,
+    mut hands: Query<(&AvatarHand, &mut Transform)>,
+) {
+    for (avatar, player_hands, children) in players.iter() {
+        for child in children.iter() {
+            let (hand, mut transform) = match hands.get_mut(*child) {
+                Err(_) => continue,
+                Ok(hand) => hand,
+            };
+            let (pose, rest) = match hand.0 {
+                Handed::Left => (&player_hands.left, avatar.hand_offset * Vec3::new(-1.0, 1.0, 1.0)),
+                _ => (&player_hands.right, avatar.hand_offset),
+            };
+            *transform = pose.wrist().unwrap_or(Transform::from_translation(rest));
+        }
+    }
+}
+
+/// Draws a small cube for every tracked finger joint so pointing and pinching come across.
+pub(crate) fn draw_avatar_joints(
+    players: Query<(&Avatar, &PlayerHands, &GlobalTransform, Option<&PlayerId>), Without<LocalPlayer>>,
+    roster: Res<Roster>,
+    sk: Option<NonSend<SkDraw>>,
+    mut mesh: Local<Option<Mesh>>,
+) {
+    let sk = match sk {
+        None => return,
+        Some(sk) => sk,
+    };
+    let mesh = mesh.get_or_insert_with(|| sk.mesh_gen_cube(Vec3::splat(JOINT_SIZE), 1));
+    for (avatar, hands, transform, player_id) in players.iter() {
+        let color = avatar
+            .color
+            .or_else(|| player_id.and_then(|player_id| roster.0.get(&player_id.0)).map(|profile| profile.color))
+            .unwrap_or(Color128::new(1.0, 1.0, 1.0, 1.0));
+        let head = transform.compute_matrix();
+        for joint in hands.left.joints().chain(hands.right.joints()) {
+            sk.mesh_draw(mesh, Material::DEFAULT, head * joint.compute_matrix(), color, RenderLayer::LAYER0);
+        }
+    }
+}
@@ -0,0 +1,191 @@
+use crate::networking::clock_server::ClockMsgServer;
+use crate::networking::transport::{receive_from_server, NetClient};
+use bevy_app::App;
+use bevy_ecs::prelude::{EventReader, Local, Res, ResMut, Resource, World};
+use bevy_quinnet::client::ConnectionLostEvent;
+use bevy_quinnet::shared::channel::ChannelType;
+use leknet::{ClientMessage, TypeName};
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+/// How many recent exchanges the estimate is made from.
+const SAMPLES: usize = 8;
+
+/// How often the clock is synchronized. Until there are enough samples, pings go out faster.
+#[derive(Resource, Clone, Debug)]
+pub struct ClockConfig {
+    pub interval: Duration,
+    pub initial_interval: Duration,
+}
+
+impl Default for ClockConfig {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(2),
+            initial_interval: Duration::from_millis(100),
+        }
+    }
+}
+
+/// The server's clock as seen from this client, kept up to date with ping exchanges.
+/// Times are seconds since the server started.
+#[derive(Resource, Clone, Debug)]
+pub struct NetworkClock {
+    epoch: Instant,
+    offset: f64,
+    rtt: f64,
+    /// Round trip and offset of the latest exchanges.
+    samples: VecDeque<(f64, f64)>,
+    /// Server time of the latest pong and the local time it arrived, echoed in the next ping.
+    last_pong: Option<(f64, f64)>,
+}
+
+impl Default for NetworkClock {
+    fn default() -> Self {
+        Self {
+            epoch: Instant::now(),
+            offset: 0.0,
+            rtt: 0.0,
+            samples: VecDeque::new(),
+            last_pong: None,
+        }
+    }
+}
+
+impl NetworkClock {
+    /// Whether there was at least one exchange with the server yet, before that the time is a guess.
+    pub fn is_synced(&self) -> bool {
+        !self.samples.is_empty()
+    }
+
+    pub fn server_time(&self) -> f64 {
+        self.local_time() + self.offset
+    }
+
+    /// The local time at which the server's clock reads `server_time`.
+    pub fn to_local(&self, server_time: f64) -> f64 {
+        server_time - self.offset
+    }
+
+    pub fn local_time(&self) -> f64 {
+        self.epoch.elapsed().as_secs_f64()
+    }
+
+    /// How far the server's clock is ahead of this client's.
+    pub fn offset(&self) -> f64 {
+        self.offset
+    }
+
+    /// Smoothed round trip time to the server.
+    pub fn rtt(&self) -> Duration {
+        Duration::from_secs_f64(self.rtt)
+    }
+
+    /// Like NTP, the exchange with the shortest round trip gives the offset, since it had the
+    /// least room for the two directions to take different times.
+    fn add_sample(&mut self, sent: f64, server_time: f64, received: f64) {
+        self.last_pong = Some((server_time, received));
+        let rtt = (received - sent).max(0.0);
+        let offset = server_time - (sent + rtt / 2.0);
+        self.rtt = if self.samples.is_empty() {
+            rtt
+        } else {
+            self.rtt * 0.875 + rtt * 0.125
+        };
+        if self.samples.len() == SAMPLES {
+            self.samples.pop_front();
+        }
+        self.samples.push_back((rtt, offset));
+        self.offset = self
+            .samples
+            .iter()
+            .min_by(|a, b| a.0.total_cmp(&b.0))
+            .map(|(_, offset)| *offset)
+            .unwrap();
+    }
+
+    fn reset(&mut self) {
+        self.samples.clear();
+        self.rtt = 0.0;
+        self.last_pong = None;
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum ClockMsgClient {
+    /// The client's time from the ping and the server's time when it answered.
+    Pong { client_time: f64, server_time: f64 },
+}
+
+impl TypeName for ClockMsgClient {
+    fn get_type_name() -> String {
+        "stereokit_bevy::networking::ClockMsgClient".to_string()
+    }
+}
+
+impl ClientMessage for ClockMsgClient {
+    fn client(self, world: &mut World) {
+        match self {
+            ClockMsgClient::Pong {
+                client_time,
+                server_time,
+            } => {
+                let mut clock = world.resource_mut::<NetworkClock>();
+                let received = clock.local_time();
+                clock.add_sample(client_time, server_time, received);
+            }
+        }
+    }
+
+    fn _client(world: &mut World, msg_bytes: &[u8]) {
+        if let Some(msg) = receive_from_server::<Self>(world, msg_bytes) {
+            msg.client(world)
+        }
+    }
+
+    fn channel_type(&self) -> ChannelType {
+        match self {
+            ClockMsgClient::Pong { .. } => ChannelType::Unreliable,
+        }
+    }
+
+    fn plugin(app: &mut App) {
+        app.init_resource::<ClockConfig>();
+        app.init_resource::<NetworkClock>();
+        app.add_system(send_ping);
+    }
+}
+
+fn send_ping(
+    mut lost: EventReader<ConnectionLostEvent>,
+    config: Res<ClockConfig>,
+    mut clock: ResMut<NetworkClock>,
+    mut last_ping: Local<Option<f64>>,
+    mut client: NetClient,
+) {
+    // The server on the other end of a new connection may have restarted with a new clock.
+    if lost.iter().count() > 0 {
+        clock.reset();
+        *last_ping = None;
+    }
+    if !client.is_connected() {
+        return;
+    }
+    let now = clock.local_time();
+    let interval = if clock.samples.len() < SAMPLES {
+        config.initial_interval
+    } else {
+        config.interval
+    };
+    if let Some(last_ping) = *last_ping {
+        if now - last_ping < interval.as_secs_f64() {
+            return;
+        }
+    }
+    *last_ping = Some(now);
+    client.send(ClockMsgServer::Ping {
+        client_time: now,
+        last_pong: clock.last_pong.map(|(server_time, received)| (server_time, now - received)),
+    });
+}
@@ -0,0 +1,92 @@
+use crate::networking::clock_client::ClockMsgClient;
+use crate::networking::diagnostics::{NetworkDiagnostics, Peer};
+use crate::networking::transport::{receive_from_client, NetServer};
+use bevy_app::App;
+use bevy_ecs::prelude::{Resource, World};
+use bevy_ecs::system::SystemState;
+use bevy_quinnet::shared::channel::ChannelType;
+use bevy_quinnet::shared::ClientId;
+use leknet::{ServerMessage, TypeName};
+use serde::{Deserialize, Serialize};
+use std::time::{Duration, Instant};
+
+/// The time base clients synchronize to.
+#[derive(Resource, Clone, Debug)]
+pub(crate) struct ServerClock(pub Instant);
+
+impl Default for ServerClock {
+    fn default() -> Self {
+        Self(Instant::now())
+    }
+}
+
+impl ServerClock {
+    pub fn now(&self) -> f64 {
+        self.0.elapsed().as_secs_f64()
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum ClockMsgServer {
+    /// Hands back the server time of the last pong and how long the client held it since, so the
+    /// server can measure the round trip for its diagnostics.
+    Ping {
+        client_time: f64,
+        last_pong: Option<(f64, f64)>,
+    },
+}
+
+impl TypeName for ClockMsgServer {
+    fn get_type_name() -> String {
+        "stereokit_bevy::networking::ClockMsgServer".to_string()
+    }
+}
+
+/// The round trip of a pong sent at `sent` and echoed back now, minus the time the client held it.
+/// Only the holding time comes from the client, a reply that doesn't add up is thrown away.
+pub(crate) fn measure_rtt(sent: f64, held: f64, now: f64) -> Option<Duration> {
+    let valid = (0.0..=now).contains(&sent) && (0.0..=now - sent).contains(&held);
+    valid.then(|| Duration::from_secs_f64(now - sent - held))
+}
+
+impl ServerMessage for ClockMsgServer {
+    fn server(self, world: &mut World, client_id: ClientId) {
+        match self {
+            ClockMsgServer::Ping {
+                client_time,
+                last_pong,
+            } => {
+                let server_time = world.resource::<ServerClock>().now();
+                let rtt = last_pong.and_then(|(sent, held)| measure_rtt(sent, held, server_time));
+                if let (Some(rtt), Some(mut diagnostics)) = (rtt, world.get_resource_mut::<NetworkDiagnostics>()) {
+                    diagnostics.connection(Peer::Client(client_id)).rtt = Some(rtt);
+                }
+                let mut system_state: SystemState<NetServer> = SystemState::new(world);
+                let mut server = system_state.get_mut(world);
+                server.send(
+                    client_id,
+                    ClockMsgClient::Pong {
+                        client_time,
+                        server_time,
+                    },
+                );
+            }
+        }
+    }
+
+    fn _server(world: &mut World, msg_bytes: &[u8], client_id: ClientId) {
+        if let Some(msg) = receive_from_client::<Self>(world, msg_bytes, client_id) {
+            msg.server(world, client_id);
+        }
+    }
+
+    fn channel_type(&self) -> ChannelType {
+        match self {
+            ClockMsgServer::Ping { .. } => ChannelType::Unreliable,
+        }
+    }
+
+    fn plugin(app: &mut App) {
+        app.init_resource::<ServerClock>();
+    }
+}
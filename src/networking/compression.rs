@@ -0,0 +1,243 @@
+use bevy_ecs::prelude::Component;
+use bevy_transform::prelude::Transform;
+use glam::{Quat, Vec3};
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+use stereokit::{Color128, RenderLayer};
+
+/// Positions are sent in millimeters, which covers a room of +-32 meters. Beyond that they're sent
+/// at full precision.
+const TRANSLATION_STEP: f32 = 0.001;
+/// Scale is sent in thousandths, up to 32 times the original size.
+const SCALE_STEP: f32 = 0.001;
+/// Once a model hasn't changed for this long, all its fields are sent once more over a reliable
+/// channel, so an update lost on the unreliable one can't leave a field stale.
+pub const SETTLE_TIME: Duration = Duration::from_millis(250);
+
+/// Which fields of a model changed since the last update that was sent.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct DirtyMask(pub u8);
+
+impl DirtyMask {
+    pub const TRANSLATION: u8 = 1 << 0;
+    pub const ROTATION: u8 = 1 << 1;
+    pub const SCALE: u8 = 1 << 2;
+    pub const COLOR: u8 = 1 << 3;
+    pub const RENDER_LAYER: u8 = 1 << 4;
+    pub const ALL: DirtyMask = DirtyMask(0b11111);
+
+    pub fn contains(self, field: u8) -> bool {
+        self.0 & field != 0
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct QuantizedVec3([i16; 3]);
+
+impl QuantizedVec3 {
+    pub fn new(value: Vec3, step: f32) -> Self {
+        let quantize = |v: f32| (v / step).round().clamp(i16::MIN as f32, i16::MAX as f32) as i16;
+        Self([quantize(value.x), quantize(value.y), quantize(value.z)])
+    }
+
+    /// `None` where [`new`](Self::new) would have to clamp.
+    pub fn try_new(value: Vec3, step: f32) -> Option<Self> {
+        let range = i16::MIN as f32..=i16::MAX as f32;
+        (value / step)
+            .round()
+            .to_array()
+            .iter()
+            .all(|v| range.contains(v))
+            .then(|| Self::new(value, step))
+    }
+
+    pub fn get(self, step: f32) -> Vec3 {
+        Vec3::new(self.0[0] as f32, self.0[1] as f32, self.0[2] as f32) * step
+    }
+}
+
+/// A rotation packed into 32 bits with the smallest three encoding: the largest component is
+/// dropped and rebuilt from the unit length, the other three get 10 bits each.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct QuantizedQuat(u32);
+
+impl QuantizedQuat {
+    const BITS: u32 = 10;
+    const MAX: f32 = ((1 << Self::BITS) - 1) as f32;
+    const RANGE: f32 = std::f32::consts::FRAC_1_SQRT_2;
+
+    pub fn new(rotation: Quat) -> Self {
+        let rotation = rotation.normalize().to_array();
+        let largest = (0..4)
+            .max_by(|a, b| rotation[*a].abs().total_cmp(&rotation[*b].abs()))
+            .unwrap();
+        // q and -q are the same rotation, so flip it to make the dropped component positive.
+        let sign = if rotation[largest] < 0.0 { -1.0 } else { 1.0 };
+        let mut packed = largest as u32;
+        for (i, component) in rotation.iter().enumerate() {
+            if i == largest {
+                continue;
+            }
+            let normalized = (component * sign / Self::RANGE + 1.0) * 0.5;
+            let quantized = (normalized.clamp(0.0, 1.0) * Self::MAX).round() as u32;
+            packed = (packed << Self::BITS) | quantized;
+        }
+        Self(packed)
+    }
+
+    pub fn get(self) -> Quat {
+        let largest = (self.0 >> (Self::BITS * 3)) as usize;
+        let mut rotation = [0.0; 4];
+        let mut sum = 0.0;
+        let mut shift = Self::BITS * 3;
+        for (i, component) in rotation.iter_mut().enumerate() {
+            if i == largest {
+                continue;
+            }
+            shift -= Self::BITS;
+            let quantized = (self.0 >> shift) & ((1 << Self::BITS) - 1);
+            *component = ((quantized as f32 / Self::MAX) * 2.0 - 1.0) * Self::RANGE;
+            sum += *component * *component;
+        }
+        rotation[largest] = (1.0 - sum).max(0.0).sqrt();
+        Quat::from_array(rotation).normalize()
+    }
+}
+
+/// A model change with only the fields that changed, the transform quantized.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct ModelDelta {
+    translation: Option<QuantizedVec3>,
+    /// Instead of `translation` for models too far out for it.
+    far_translation: Option<Vec3>,
+    rotation: Option<QuantizedQuat>,
+    scale: Option<QuantizedVec3>,
+    color128: Option<Color128>,
+    render_layer: Option<RenderLayer>,
+}
+
+impl ModelDelta {
+    pub fn new(mask: DirtyMask, transform: &Transform, color128: Color128, render_layer: RenderLayer) -> Self {
+        let (translation, far_translation) = if !mask.contains(DirtyMask::TRANSLATION) {
+            (None, None)
+        } else {
+            match QuantizedVec3::try_new(transform.translation, TRANSLATION_STEP) {
+                None => (None, Some(transform.translation)),
+                translation => (translation, None),
+            }
+        };
+        Self {
+            translation,
+            far_translation,
+            rotation: mask
+                .contains(DirtyMask::ROTATION)
+                .then(|| QuantizedQuat::new(transform.rotation)),
+            scale: mask
+                .contains(DirtyMask::SCALE)
+                .then(|| QuantizedVec3::new(transform.scale, SCALE_STEP)),
+            color128: mask.contains(DirtyMask::COLOR).then_some(color128),
+            render_layer: mask.contains(DirtyMask::RENDER_LAYER).then_some(render_layer),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.translation.is_none()
+            && self.far_translation.is_none()
+            && self.rotation.is_none()
+            && self.scale.is_none()
+            && self.color128.is_none()
+            && self.render_layer.is_none()
+    }
+
+    /// Applies the fields that were sent on top of the last known transform.
+    pub fn apply_transform(&self, transform: &mut Transform) {
+        if let Some(translation) = self.translation {
+            transform.translation = translation.get(TRANSLATION_STEP);
+        }
+        if let Some(translation) = self.far_translation {
+            transform.translation = translation;
+        }
+        if let Some(rotation) = self.rotation {
+            transform.rotation = rotation.get();
+        }
+        if let Some(scale) = self.scale {
+            transform.scale = scale.get(SCALE_STEP);
+        }
+    }
+
+    pub fn has_transform(&self) -> bool {
+        self.translation.is_some() || self.far_translation.is_some() || self.rotation.is_some() || self.scale.is_some()
+    }
+
+    pub fn color128(&self) -> Option<Color128> {
+        self.color128
+    }
+
+    pub fn render_layer(&self) -> Option<RenderLayer> {
+        self.render_layer
+    }
+}
+
+/// What was last sent for a locally owned model, to work out the dirty mask of the next update.
+#[derive(Component, Clone, Debug)]
+pub struct LastSent {
+    /// In steps, unclamped so far models still count as moved.
+    translation: Vec3,
+    rotation: QuantizedQuat,
+    scale: QuantizedVec3,
+    color128: Color128,
+    render_layer: RenderLayer,
+    sent_at: Duration,
+    /// Whether everything since the last reliable update was sent reliably too.
+    settled: bool,
+}
+
+impl LastSent {
+    pub fn new(transform: &Transform, color128: Color128, render_layer: RenderLayer) -> Self {
+        Self {
+            translation: (transform.translation / TRANSLATION_STEP).round(),
+            rotation: QuantizedQuat::new(transform.rotation),
+            scale: QuantizedVec3::new(transform.scale, SCALE_STEP),
+            color128,
+            render_layer,
+            sent_at: Duration::ZERO,
+            settled: true,
+        }
+    }
+
+    /// Which fields differ from what was last sent.
+    pub fn peek(&self, transform: &Transform, color128: Color128, render_layer: RenderLayer) -> DirtyMask {
+        let next = Self::new(transform, color128, render_layer);
+        let mut mask = 0;
+        if next.translation != self.translation {
+            mask |= DirtyMask::TRANSLATION;
+        }
+        if next.rotation != self.rotation {
+            mask |= DirtyMask::ROTATION;
+        }
+        if next.scale != self.scale {
+            mask |= DirtyMask::SCALE;
+        }
+        if next.color128 != self.color128 {
+            mask |= DirtyMask::COLOR;
+        }
+        if next.render_layer != self.render_layer {
+            mask |= DirtyMask::RENDER_LAYER;
+        }
+        DirtyMask(mask)
+    }
+
+    /// Whether the last update went out unreliably at least [`SETTLE_TIME`] before `now`.
+    pub fn needs_settling(&self, now: Duration) -> bool {
+        !self.settled && now.saturating_sub(self.sent_at) >= SETTLE_TIME
+    }
+
+    /// Records that the given state was sent at `now`, over a reliable channel if `reliable`.
+    pub fn update(&mut self, transform: &Transform, color128: Color128, render_layer: RenderLayer, now: Duration, reliable: bool) {
+        *self = Self {
+            sent_at: now,
+            settled: reliable,
+            ..Self::new(transform, color128, render_layer)
+        };
+    }
+}
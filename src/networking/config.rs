@@ -0,0 +1,136 @@
+use crate::networking::memory::MemoryClient;
+use bevy::log::error;
+use bevy_ecs::prelude::{EventWriter, Res, ResMut, Resource};
+use bevy_quinnet::client::certificate::{CertificateVerificationMode, TrustOnFirstUseConfig};
+use bevy_quinnet::client::{Client, ClientConfigurationData, ConnectionEvent};
+use bevy_quinnet::server::certificate::CertificateRetrievalMode;
+use bevy_quinnet::server::{Server, ServerConfigurationData};
+
+/// How the server proves who it is, and how clients check it.
+#[derive(Clone, Debug)]
+pub enum CertificateMode {
+    /// The server generates a new certificate every start and clients don't verify it.
+    SelfSigned,
+    /// The server loads its certificate from disk, clients expect it to be signed by a known authority.
+    Files { cert_file: String, key_file: String },
+    /// The server generates a certificate, clients remember it the first time and reject changes.
+    TrustOnFirstUse,
+}
+
+/// Where the server listens and where clients connect to. Insert it before adding
+/// [`StereoKitBevyServerPlugins`](crate::networking::StereoKitBevyServerPlugins) or
+/// [`StereoKitBevyClientPlugins`](crate::networking::StereoKitBevyClientPlugins) to change it.
+#[derive(Resource, Clone, Debug)]
+pub struct NetworkConfig {
+    /// The address the server binds to.
+    pub bind_address: String,
+    /// The port the server listens on and clients connect to.
+    pub port: u16,
+    /// The host clients connect to.
+    pub server_host: String,
+    pub certificates: CertificateMode,
+    /// Clients past this many are turned away by the handshake.
+    pub max_players: Option<usize>,
+}
+
+impl Default for NetworkConfig {
+    fn default() -> Self {
+        Self {
+            bind_address: "0.0.0.0".to_string(),
+            port: 6000,
+            server_host: "127.0.0.1".to_string(),
+            certificates: CertificateMode::SelfSigned,
+            max_players: None,
+        }
+    }
+}
+
+/// The server couldn't start listening, or the client couldn't open a connection. Says why.
+#[derive(Clone, Debug)]
+pub struct EndpointFailed(pub String);
+
+/// A server on a [`MemoryNetwork`](crate::networking::memory::MemoryNetwork) has no endpoint to start.
+pub(crate) fn start_server(
+    config: Res<NetworkConfig>,
+    server: Option<ResMut<Server>>,
+    mut failed: EventWriter<EndpointFailed>,
+) {
+    let mut server = match server {
+        None => return,
+        Some(server) => server,
+    };
+    let certificate_mode = match &config.certificates {
+        CertificateMode::SelfSigned | CertificateMode::TrustOnFirstUse => {
+            CertificateRetrievalMode::GenerateSelfSigned
+        }
+        CertificateMode::Files {
+            cert_file,
+            key_file,
+        } => CertificateRetrievalMode::LoadFromFile {
+            cert_file: cert_file.clone(),
+            key_file: key_file.clone(),
+        },
+    };
+    let started = server.start_endpoint(
+        ServerConfigurationData::new(
+            config.server_host.clone(),
+            config.port,
+            config.bind_address.clone(),
+        ),
+        certificate_mode,
+    );
+    if let Err(error) = started {
+        error!("couldn't start the server on port {}: {error}", config.port);
+        failed.send(EndpointFailed(error.to_string()));
+    }
+}
+
+pub(crate) fn connect_to_server(
+    config: Res<NetworkConfig>,
+    client: Option<ResMut<Client>>,
+    memory: Option<ResMut<MemoryClient>>,
+    mut connected: EventWriter<ConnectionEvent>,
+    mut failed: EventWriter<EndpointFailed>,
+) {
+    if let Some(mut memory) = memory {
+        connect_in_memory(&mut memory, &mut connected);
+    } else if let Some(mut client) = client {
+        if let Err(error) = open_connection(&config, &mut client) {
+            failed.send(error);
+        }
+    }
+}
+
+/// Connects right away, there's nothing to wait for.
+pub(crate) fn connect_in_memory(memory: &mut MemoryClient, connected: &mut EventWriter<ConnectionEvent>) {
+    let id = memory.connect();
+    connected.send(ConnectionEvent { id });
+}
+
+/// Logs why it failed, if it did.
+pub(crate) fn open_connection(config: &NetworkConfig, client: &mut Client) -> Result<(), EndpointFailed> {
+    let verification_mode = match &config.certificates {
+        CertificateMode::SelfSigned => CertificateVerificationMode::SkipVerification,
+        CertificateMode::Files { .. } => CertificateVerificationMode::SignedByCertificateAuthority,
+        CertificateMode::TrustOnFirstUse => {
+            CertificateVerificationMode::TrustOnFirstUse(TrustOnFirstUseConfig::default())
+        }
+    };
+    let connection = client
+        .open_connection(
+            ClientConfigurationData::new(
+                config.server_host.clone(),
+                config.port,
+                "0.0.0.0".to_string(),
+                0,
+            ),
+            verification_mode,
+        )
+        .map_err(|error| {
+            error!("couldn't connect to {}:{}: {error}", config.server_host, config.port);
+            EndpointFailed(error.to_string())
+        })?;
+    // After a reconnect, the new connection is the one everything is sent on.
+    client.set_default_connection(connection);
+    Ok(())
+}
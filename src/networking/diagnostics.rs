@@ -0,0 +1,242 @@
+use crate::networking::clock_client::NetworkClock;
+use crate::networking::recording::{record_message, Direction};
+use bevy_app::App;
+use bevy_ecs::prelude::{EventReader, Local, NonSend, Res, ResMut, Resource, World};
+use bevy_quinnet::server::ConnectionLostEvent;
+use bevy_quinnet::shared::channel::ChannelType;
+use bevy_quinnet::shared::ClientId;
+use bevy_time::Time;
+use glam::{Mat4, Quat, Vec3};
+use leknet::TypeName;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::time::Duration;
+use stereokit::{Color128, SkDraw, StereoKitDraw, StereoKitMultiThread, TextAlign, TextStyle};
+
+/// The other end of a connection.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Peer {
+    Server,
+    Client(ClientId),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Channel {
+    OrderedReliable,
+    UnorderedReliable,
+    Unreliable,
+}
+
+impl From<ChannelType> for Channel {
+    fn from(channel_type: ChannelType) -> Self {
+        match channel_type {
+            ChannelType::OrderedReliable => Channel::OrderedReliable,
+            ChannelType::UnorderedReliable => Channel::UnorderedReliable,
+            ChannelType::Unreliable => Channel::Unreliable,
+        }
+    }
+}
+
+/// Messages of one type on one channel. The rates cover the last second.
+#[derive(Clone, Debug, Default)]
+pub struct Traffic {
+    pub messages: u64,
+    pub bytes: u64,
+    pub messages_per_second: f64,
+    pub bytes_per_second: f64,
+    window_messages: u64,
+    window_bytes: u64,
+}
+
+impl Traffic {
+    fn record(&mut self, bytes: usize) {
+        self.messages += 1;
+        self.bytes += bytes as u64;
+        self.window_messages += 1;
+        self.window_bytes += bytes as u64;
+    }
+
+    fn end_window(&mut self, seconds: f64) {
+        self.messages_per_second = self.window_messages as f64 / seconds;
+        self.bytes_per_second = self.window_bytes as f64 / seconds;
+        self.window_messages = 0;
+        self.window_bytes = 0;
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct ConnectionDiagnostics {
+    pub rtt: Option<Duration>,
+    /// By message type name and channel.
+    pub sent: BTreeMap<(String, Channel), Traffic>,
+    pub received: BTreeMap<(String, Channel), Traffic>,
+    /// Messages that couldn't be sent, and updates thrown away because a newer one was already there.
+    pub dropped: u64,
+}
+
+impl ConnectionDiagnostics {
+    pub fn bytes_sent_per_second(&self) -> f64 {
+        self.sent.values().map(|traffic| traffic.bytes_per_second).sum()
+    }
+
+    pub fn bytes_received_per_second(&self) -> f64 {
+        self.received.values().map(|traffic| traffic.bytes_per_second).sum()
+    }
+}
+
+/// Traffic on every connection of this app. Messages between the halves of a host aren't counted,
+/// they never touch the network.
+#[derive(Resource, Clone, Debug, Default)]
+pub struct NetworkDiagnostics {
+    pub connections: BTreeMap<Peer, ConnectionDiagnostics>,
+}
+
+impl NetworkDiagnostics {
+    pub fn connection(&mut self, peer: Peer) -> &mut ConnectionDiagnostics {
+        self.connections.entry(peer).or_default()
+    }
+
+    pub(crate) fn record_sent<M: TypeName>(&mut self, peer: Peer, channel_type: ChannelType, bytes: usize) {
+        self.connection(peer)
+            .sent
+            .entry((M::get_type_name(), channel_type.into()))
+            .or_default()
+            .record(bytes);
+    }
+}
+
+/// Called by every message as it's decoded, with the bytes it came in as.
+pub(crate) fn record_received<M: TypeName>(world: &mut World, peer: Peer, channel_type: ChannelType, msg_bytes: &[u8]) {
+    if let Some(mut diagnostics) = world.get_resource_mut::<NetworkDiagnostics>() {
+        diagnostics
+            .connection(peer)
+            .received
+            .entry((M::get_type_name(), channel_type.into()))
+            .or_default()
+            .record(msg_bytes.len());
+    }
+    if let Peer::Client(client_id) = peer {
+        record_message::<M>(world, Direction::ToServer, client_id, msg_bytes);
+    }
+}
+
+pub(crate) fn record_dropped(world: &mut World, peer: Peer) {
+    if let Some(mut diagnostics) = world.get_resource_mut::<NetworkDiagnostics>() {
+        diagnostics.connection(peer).dropped += 1;
+    }
+}
+
+/// Draws the diagnostics on a panel that follows the head.
+#[derive(Resource, Clone, Debug)]
+pub struct DiagnosticsOverlay {
+    pub enabled: bool,
+    /// Where the panel is, relative to the head.
+    pub offset: Vec3,
+    /// How many message types are listed per connection, the busiest first.
+    pub top_messages: usize,
+}
+
+impl Default for DiagnosticsOverlay {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            offset: Vec3::new(-0.2, 0.15, -0.6),
+            top_messages: 5,
+        }
+    }
+}
+
+/// A host adds both halves, the systems only go in once.
+pub(crate) fn add_diagnostics(app: &mut App) {
+    if app.world.contains_resource::<NetworkDiagnostics>() {
+        return;
+    }
+    app.init_resource::<NetworkDiagnostics>();
+    app.init_resource::<DiagnosticsOverlay>();
+    app.add_system(update_rates);
+    app.add_system(draw_overlay);
+}
+
+fn update_rates(
+    time: Res<Time>,
+    clock: Option<Res<NetworkClock>>,
+    mut diagnostics: ResMut<NetworkDiagnostics>,
+    mut window_start: Local<Duration>,
+) {
+    if let Some(clock) = clock {
+        if clock.is_synced() {
+            diagnostics.connection(Peer::Server).rtt = Some(clock.rtt());
+        }
+    }
+    let seconds = (time.elapsed() - *window_start).as_secs_f64();
+    if seconds < 1.0 {
+        return;
+    }
+    *window_start = time.elapsed();
+    for connection in diagnostics.connections.values_mut() {
+        for traffic in connection.sent.values_mut().chain(connection.received.values_mut()) {
+            traffic.end_window(seconds);
+        }
+    }
+}
+
+pub(crate) fn forget_clients(mut lost: EventReader<ConnectionLostEvent>, mut diagnostics: ResMut<NetworkDiagnostics>) {
+    for client in lost.iter() {
+        diagnostics.connections.remove(&Peer::Client(client.id));
+    }
+}
+
+fn draw_overlay(sk: Option<NonSend<SkDraw>>, overlay: Res<DiagnosticsOverlay>, diagnostics: Res<NetworkDiagnostics>) {
+    let sk = match sk {
+        Some(sk) if overlay.enabled => sk,
+        _ => return,
+    };
+    let mut text = String::new();
+    for (peer, connection) in &diagnostics.connections {
+        let rtt = connection
+            .rtt
+            .map(|rtt| format!("{} ms", rtt.as_millis()))
+            .unwrap_or_else(|| "?".to_string());
+        let _ = writeln!(
+            text,
+            "{peer:?}  rtt {rtt}  up {:.1} kB/s  down {:.1} kB/s  dropped {}",
+            connection.bytes_sent_per_second() / 1000.0,
+            connection.bytes_received_per_second() / 1000.0,
+            connection.dropped,
+        );
+        let mut busiest: Vec<_> = connection
+            .sent
+            .iter()
+            .map(|(key, traffic)| ("up", key, traffic))
+            .chain(connection.received.iter().map(|(key, traffic)| ("down", key, traffic)))
+            .collect();
+        busiest.sort_by(|a, b| b.2.bytes_per_second.total_cmp(&a.2.bytes_per_second));
+        for (direction, (name, channel), traffic) in busiest.into_iter().take(overlay.top_messages) {
+            let name = name.rsplit("::").next().unwrap_or(name);
+            let _ = writeln!(
+                text,
+                "  {direction} {name} {channel:?}  {:.0} msg/s  {:.1} kB/s",
+                traffic.messages_per_second,
+                traffic.bytes_per_second / 1000.0,
+            );
+        }
+    }
+    if text.is_empty() {
+        text.push_str("not connected");
+    }
+    let head = sk.input_head();
+    let position = head.position + head.orientation * overlay.offset;
+    // Turned around to face the head, like the name tags.
+    let facing = head.orientation * Quat::from_rotation_y(std::f32::consts::PI);
+    sk.text_add_at(
+        &text,
+        Mat4::from_rotation_translation(facing, position),
+        TextStyle::default(),
+        TextAlign::TopLeft,
+        TextAlign::TopLeft,
+        0.0,
+        0.0,
+        0.0,
+        Color128::new(1.0, 1.0, 1.0, 1.0),
+    );
+}
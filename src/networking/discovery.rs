@@ -0,0 +1,203 @@
+use crate::networking::config::NetworkConfig;
+use crate::networking::handshake_client::PROTOCOL_VERSION;
+use crate::networking::handshake_server::AcceptedClients;
+use crate::networking::room_server::Rooms;
+use bevy::log::warn;
+use bevy_ecs::prelude::{Commands, Local, Res, ResMut, Resource};
+use bevy_time::Time;
+use serde::{Deserialize, Serialize};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, UdpSocket};
+use std::time::Duration;
+
+/// Keeps stray packets on the port from being taken for a server.
+const MAGIC: u32 = 0x534b_4256;
+
+/// Longer server names are cut, so an answer always fits in one packet.
+pub const MAX_NAME_LEN: usize = 64;
+
+/// Big enough for any packet, with a name of at most [`MAX_NAME_LEN`].
+const PACKET_SIZE: usize = 512;
+
+/// How servers are found on the local network. Clients send a query to `query_address` and every
+/// server listening on `port` there answers. It's off unless `enabled` is set.
+#[derive(Resource, Clone, Debug)]
+pub struct DiscoveryConfig {
+    pub enabled: bool,
+    /// The name a server is listed under, cut to [`MAX_NAME_LEN`] bytes.
+    pub name: String,
+    /// The port servers listen for queries on. It's not the port clients connect to.
+    pub port: u16,
+    /// The broadcast address reaches every server on the network.
+    pub query_address: IpAddr,
+    pub interval: Duration,
+    /// Servers that haven't answered for this long are dropped from the list.
+    pub timeout: Duration,
+}
+
+impl Default for DiscoveryConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            name: "StereoKit Bevy server".to_string(),
+            port: 6001,
+            query_address: IpAddr::V4(Ipv4Addr::BROADCAST),
+            interval: Duration::from_secs(1),
+            timeout: Duration::from_secs(5),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+enum DiscoveryPacket {
+    Query { magic: u32 },
+    Answer { magic: u32, server: ServerAnswer },
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+struct ServerAnswer {
+    name: String,
+    port: u16,
+    rooms: usize,
+    players: usize,
+    protocol_version: u32,
+}
+
+/// A server that answered on the local network.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DiscoveredServer {
+    pub name: String,
+    /// Where to connect to it.
+    pub address: SocketAddr,
+    pub rooms: usize,
+    pub players: usize,
+    pub protocol_version: u32,
+    last_seen: Duration,
+}
+
+impl DiscoveredServer {
+    /// Whether this build can join it, otherwise the handshake turns it away.
+    pub fn is_compatible(&self) -> bool {
+        self.protocol_version == PROTOCOL_VERSION
+    }
+}
+
+/// The servers found on the local network, in the order they were found.
+#[derive(Resource, Clone, Debug, Default)]
+pub struct DiscoveredServers(pub Vec<DiscoveredServer>);
+
+#[derive(Resource)]
+pub(crate) struct DiscoveryResponder(UdpSocket);
+
+#[derive(Resource)]
+pub(crate) struct DiscoverySocket(UdpSocket);
+
+fn truncate_name(name: &str) -> String {
+    let mut end = name.len().min(MAX_NAME_LEN);
+    while !name.is_char_boundary(end) {
+        end -= 1;
+    }
+    name[..end].to_string()
+}
+
+pub(crate) fn listen_for_queries(config: Res<DiscoveryConfig>, mut commands: Commands) {
+    if !config.enabled {
+        return;
+    }
+    let socket = match UdpSocket::bind((Ipv4Addr::UNSPECIFIED, config.port)) {
+        Err(error) => {
+            warn!("couldn't listen for discovery queries on port {}: {error}", config.port);
+            return;
+        }
+        Ok(socket) => socket,
+    };
+    socket.set_nonblocking(true).unwrap();
+    commands.insert_resource(DiscoveryResponder(socket));
+}
+
+pub(crate) fn answer_queries(
+    config: Res<DiscoveryConfig>,
+    network: Res<NetworkConfig>,
+    responder: Option<Res<DiscoveryResponder>>,
+    rooms: Res<Rooms>,
+    accepted: Res<AcceptedClients>,
+) {
+    let socket = match responder {
+        Some(responder) if config.enabled => responder,
+        _ => return,
+    };
+    let mut buffer = [0; PACKET_SIZE];
+    while let Ok((len, from)) = socket.0.recv_from(&mut buffer) {
+        match bincode::deserialize(&buffer[..len]) {
+            Ok(DiscoveryPacket::Query { magic: MAGIC }) => {}
+            _ => continue,
+        }
+        let answer = DiscoveryPacket::Answer {
+            magic: MAGIC,
+            server: ServerAnswer {
+                name: truncate_name(&config.name),
+                port: network.port,
+                rooms: rooms.list().len(),
+                players: accepted.0.len(),
+                protocol_version: PROTOCOL_VERSION,
+            },
+        };
+        socket.0.send_to(&bincode::serialize(&answer).unwrap(), from).ok();
+    }
+}
+
+/// Clients query from a port of their own, so any number of them can run on one machine.
+pub(crate) fn open_discovery(config: Res<DiscoveryConfig>, mut commands: Commands) {
+    if !config.enabled {
+        return;
+    }
+    let socket = match UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)) {
+        Err(error) => {
+            warn!("couldn't open a socket for discovery: {error}");
+            return;
+        }
+        Ok(socket) => socket,
+    };
+    socket.set_nonblocking(true).unwrap();
+    socket.set_broadcast(true).ok();
+    commands.insert_resource(DiscoverySocket(socket));
+}
+
+pub(crate) fn discover_servers(
+    config: Res<DiscoveryConfig>,
+    socket: Option<Res<DiscoverySocket>>,
+    mut servers: ResMut<DiscoveredServers>,
+    time: Res<Time>,
+    mut last_query: Local<Option<Duration>>,
+) {
+    let socket = match socket {
+        Some(socket) if config.enabled => socket,
+        _ => return,
+    };
+    let now = time.elapsed();
+    if last_query.map_or(true, |last_query| now - last_query >= config.interval) {
+        *last_query = Some(now);
+        let query = bincode::serialize(&DiscoveryPacket::Query { magic: MAGIC }).unwrap();
+        socket.0.send_to(&query, (config.query_address, config.port)).ok();
+    }
+    let mut buffer = [0; PACKET_SIZE];
+    while let Ok((len, from)) = socket.0.recv_from(&mut buffer) {
+        let answer = match bincode::deserialize(&buffer[..len]) {
+            Ok(DiscoveryPacket::Answer { magic: MAGIC, server }) => server,
+            _ => continue,
+        };
+        let server = DiscoveredServer {
+            name: answer.name,
+            address: SocketAddr::new(from.ip(), answer.port),
+            rooms: answer.rooms,
+            players: answer.players,
+            protocol_version: answer.protocol_version,
+            last_seen: now,
+        };
+        match servers.0.iter_mut().find(|known| known.address == server.address) {
+            Some(known) => *known = server,
+            None => servers.0.push(server),
+        }
+    }
+    let timeout = config.timeout;
+    servers.0.retain(|server| now - server.last_seen < timeout);
+}
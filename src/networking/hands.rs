@@ -0,0 +1,126 @@
+use crate::networking::compression::{QuantizedQuat, QuantizedVec3};
+use crate::networking::interpolation::Sequence;
+use bevy_ecs::prelude::{Component, Resource};
+use bevy_transform::prelude::Transform;
+use glam::{Quat, Vec3};
+use serde::{Deserialize, Serialize};
+use stereokit::{ButtonState, Controller, Hand, HandSource};
+
+/// Joint positions are sent in millimeters relative to the head.
+const JOINT_STEP: f32 = 0.001;
+/// Finger joints of a tracked hand, five per finger.
+pub const JOINTS: usize = 25;
+
+#[derive(Resource, Clone, Debug)]
+pub struct HandsConfig {
+    /// Hand updates per second for the local player.
+    pub rate: f32,
+}
+
+impl Default for HandsConfig {
+    fn default() -> Self {
+        Self { rate: 15.0 }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CompressedPose(QuantizedVec3, QuantizedQuat);
+
+impl CompressedPose {
+    pub fn new(position: Vec3, orientation: Quat) -> Self {
+        Self(QuantizedVec3::new(position, JOINT_STEP), QuantizedQuat::new(orientation))
+    }
+
+    pub fn transform(self) -> Transform {
+        Transform::from_translation(self.0.get(JOINT_STEP)).with_rotation(self.1.get())
+    }
+}
+
+/// One hand, relative to the player's head.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub enum HandPose {
+    #[default]
+    Untracked,
+    /// Only a controller is tracked, its grip pose stands in for the hand.
+    Controller(CompressedPose),
+    /// The wrist and the 25 finger joints, five per finger from the thumb out.
+    Tracked {
+        wrist: CompressedPose,
+        joints: Vec<CompressedPose>,
+    },
+}
+
+impl HandPose {
+    /// Prefers articulated hands and falls back to the controller, `head` is the player's transform.
+    pub fn capture(head: &Transform, hand: &Hand, source: HandSource, controller: &Controller) -> Self {
+        let relative = |position: Vec3, orientation: Quat| {
+            let inverse = head.rotation.inverse();
+            CompressedPose::new(inverse * (position - head.translation), inverse * orientation)
+        };
+        if matches!(source, HandSource::Articulated) && hand.tracked_state.contains(ButtonState::ACTIVE) {
+            HandPose::Tracked {
+                wrist: relative(hand.wrist.position, hand.wrist.orientation),
+                joints: hand
+                    .fingers
+                    .iter()
+                    .flatten()
+                    .map(|joint| relative(joint.position, joint.orientation))
+                    .collect(),
+            }
+        } else if controller.tracked.contains(ButtonState::ACTIVE) {
+            HandPose::Controller(relative(controller.pose.position, controller.pose.orientation))
+        } else {
+            HandPose::Untracked
+        }
+    }
+
+    /// Where the hand model goes relative to the head, nothing if the hand isn't tracked.
+    pub fn wrist(&self) -> Option<Transform> {
+        match self {
+            HandPose::Untracked => None,
+            HandPose::Controller(pose) => Some(pose.transform()),
+            HandPose::Tracked { wrist, .. } => Some(wrist.transform()),
+        }
+    }
+
+    pub fn joints(&self) -> impl Iterator<Item = Transform> + '_ {
+        let joints: &[CompressedPose] = match self {
+            HandPose::Tracked { joints, .. } => joints,
+            _ => &[],
+        };
+        joints.iter().map(|joint| joint.transform())
+    }
+}
+
+/// The hands of a player. Captured for the local player, replicated for remote ones.
+#[derive(Component, Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct PlayerHands {
+    pub left: HandPose,
+    pub right: HandPose,
+}
+
+impl PlayerHands {
+    /// Whether neither hand has more joints than a real one, the server drops hands that do.
+    pub fn is_valid(&self) -> bool {
+        [&self.left, &self.right].iter().all(|hand| match hand {
+            HandPose::Tracked { joints, .. } => joints.len() <= JOINTS,
+            _ => true,
+        })
+    }
+}
+
+/// The sequence of the hands a remote player last got, older updates are dropped.
+#[derive(Component, Clone, Copy, Debug)]
+pub struct HandsSequence(pub Sequence);
+
+impl From<Sequence> for HandsSequence {
+    fn from(sequence: Sequence) -> Self {
+        Self(sequence)
+    }
+}
+
+impl From<HandsSequence> for Sequence {
+    fn from(sequence: HandsSequence) -> Self {
+        sequence.0
+    }
+}
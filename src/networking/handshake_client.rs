@@ -0,0 +1,156 @@
+use crate::networking::handshake_server::HandshakeMsgServer;
+use crate::networking::reconnect::{resync, ReconnectConfig, Reconnected};
+use crate::networking::transport::{receive_from_server, NetClient};
+use bevy_app::App;
+use bevy_ecs::prelude::{EventReader, Res, ResMut, Resource, World};
+use bevy_quinnet::client::{ConnectionEvent, ConnectionLostEvent};
+use bevy_quinnet::shared::channel::ChannelType;
+use leknet::{ClientMessage, TypeName};
+use serde::{Deserialize, Serialize};
+use serde_reflection::{Tracer, TracerConfig};
+
+/// Bump this whenever the way messages are exchanged changes in a way the schema hashes can't see.
+pub const PROTOCOL_VERSION: u32 = 2;
+
+/// Every message type this build can send or receive, with a hash of its layout.
+#[derive(Resource, Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MessageRegistry(pub Vec<(String, u64)>);
+
+impl MessageRegistry {
+    pub fn register<M: TypeName + for<'de> Deserialize<'de>>(&mut self) {
+        let name = M::get_type_name();
+        if self.0.iter().any(|(registered, _)| *registered == name) {
+            return;
+        }
+        self.0.push((name, schema_hash::<M>()));
+        self.0.sort();
+    }
+
+    /// Why `other` can't talk to this registry, if it can't.
+    pub fn incompatibility(&self, other: &MessageRegistry) -> Option<String> {
+        for (name, hash) in &self.0 {
+            match other.0.iter().find(|(other_name, _)| other_name == name) {
+                None => return Some(format!("missing message type {name}")),
+                Some((_, other_hash)) if other_hash != hash => {
+                    return Some(format!("message type {name} has a different layout"))
+                }
+                _ => {}
+            }
+        }
+        other
+            .0
+            .iter()
+            .find(|(name, _)| !self.0.iter().any(|(own_name, _)| own_name == name))
+            .map(|(name, _)| format!("unknown message type {name}"))
+    }
+}
+
+/// Hashes the fields and variants of `M` and of every type in it, by name and in order. Two builds
+/// agree when they encode `M` the same way, whatever platform or compiler they came from.
+fn schema_hash<M: for<'de> Deserialize<'de>>() -> u64 {
+    let mut tracer = Tracer::new(TracerConfig::default());
+    let (format, _) = tracer
+        .trace_simple_type::<M>()
+        .unwrap_or_else(|error| panic!("couldn't trace {}: {error}", std::any::type_name::<M>()));
+    let registry = tracer
+        .registry()
+        .unwrap_or_else(|error| panic!("couldn't trace {}: {error}", std::any::type_name::<M>()));
+    let description = bincode::serialize(&(format, registry)).unwrap();
+    let hash = blake3::hash(&description);
+    u64::from_le_bytes(hash.as_bytes()[..8].try_into().unwrap())
+}
+
+/// Where this client is in the handshake. Nothing but the handshake is sent until it's accepted.
+#[derive(Resource, Clone, Debug, Default, PartialEq, Eq)]
+pub enum HandshakeState {
+    #[default]
+    Pending,
+    Accepted,
+    Rejected(String),
+}
+
+/// Sent when the server turns this client away.
+pub struct HandshakeRejected(pub String);
+
+/// Identifies a client's session on the server, so a client that lost its connection can take it back.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct SessionToken(pub u64);
+
+/// The session this client was last accepted into.
+#[derive(Resource, Clone, Debug, Default)]
+pub struct ResumeToken(pub Option<SessionToken>);
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum HandshakeMsgClient {
+    Accepted { token: SessionToken, resumed: bool },
+    Rejected(String),
+}
+
+impl TypeName for HandshakeMsgClient {
+    // The handshake has to decode on every version, so this message never changes.
+    fn get_type_name() -> String {
+        "stereokit_bevy::networking::HandshakeMsgClient::v2".to_string()
+    }
+}
+
+impl ClientMessage for HandshakeMsgClient {
+    fn client(self, world: &mut World) {
+        match self {
+            HandshakeMsgClient::Accepted { token, resumed } => {
+                *world.resource_mut::<HandshakeState>() = HandshakeState::Accepted;
+                let previous = world.resource_mut::<ResumeToken>().0.replace(token);
+                if previous.is_some() {
+                    resync(world, resumed);
+                }
+            }
+            HandshakeMsgClient::Rejected(reason) => {
+                *world.resource_mut::<HandshakeState>() = HandshakeState::Rejected(reason.clone());
+                world.send_event(HandshakeRejected(reason));
+            }
+        }
+    }
+
+    fn _client(world: &mut World, msg_bytes: &[u8]) {
+        if let Some(msg) = receive_from_server::<Self>(world, msg_bytes) {
+            msg.client(world)
+        }
+    }
+
+    fn channel_type(&self) -> ChannelType {
+        match self {
+            HandshakeMsgClient::Accepted { .. } => ChannelType::OrderedReliable,
+            HandshakeMsgClient::Rejected(_) => ChannelType::OrderedReliable,
+        }
+    }
+
+    fn plugin(app: &mut App) {
+        app.init_resource::<HandshakeState>();
+        app.init_resource::<ResumeToken>();
+        app.init_resource::<ReconnectConfig>();
+        app.add_event::<Reconnected>();
+        app.add_event::<HandshakeRejected>();
+        app.add_system(send_hello);
+    }
+}
+
+fn send_hello(
+    mut connected: EventReader<ConnectionEvent>,
+    mut lost: EventReader<ConnectionLostEvent>,
+    mut state: ResMut<HandshakeState>,
+    registry: Res<MessageRegistry>,
+    resume: Res<ResumeToken>,
+    mut client: NetClient,
+) {
+    // A rejection sticks, so nobody tries again with the same build.
+    if lost.iter().count() > 0 && !matches!(*state, HandshakeState::Rejected(_)) {
+        *state = HandshakeState::Pending;
+    }
+    for _ in connected.iter() {
+        *state = HandshakeState::Pending;
+        client.send(HandshakeMsgServer::Hello {
+            protocol_version: PROTOCOL_VERSION,
+            messages: registry.clone(),
+            resume: resume.0,
+        });
+    }
+}
@@ -0,0 +1,157 @@
+use crate::networking::config::NetworkConfig;
+use crate::networking::handshake_client::{HandshakeMsgClient, MessageRegistry, SessionToken, PROTOCOL_VERSION};
+use crate::networking::reconnect::{expire_sessions, ClientResumed, SessionConfig, Sessions};
+use crate::networking::transport::{receive_handshake_from_client, NetServer};
+use bevy_app::App;
+use bevy_ecs::prelude::{EventReader, Res, ResMut, Resource, World};
+use bevy_ecs::system::SystemState;
+use bevy_quinnet::server::ConnectionLostEvent;
+use bevy_quinnet::shared::channel::ChannelType;
+use bevy_quinnet::shared::ClientId;
+use bevy_time::Time;
+use leknet::{ServerMessage, TypeName};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::time::Duration;
+
+/// Clients that passed the handshake. Only these get messages from the server.
+#[derive(Resource, Default, Debug)]
+pub struct AcceptedClients(pub HashSet<ClientId>);
+
+/// Rejected clients get a moment to receive the reason before they're disconnected, until the
+/// [`Time::elapsed`] next to them.
+#[derive(Resource, Default, Debug)]
+pub(crate) struct Rejected(Vec<(ClientId, Duration)>);
+
+impl Rejected {
+    /// Tells the client why, it stops trying to reconnect once it knows.
+    pub(crate) fn reject(&mut self, server: &mut NetServer, client_id: ClientId, reason: String, now: Duration) {
+        server.send(client_id, HandshakeMsgClient::Rejected(reason));
+        self.0.push((client_id, now + Duration::from_secs(1)));
+    }
+}
+
+/// Sent on the server when a client passed the handshake and is part of the session.
+pub struct ClientJoined(pub ClientId);
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum HandshakeMsgServer {
+    Hello {
+        protocol_version: u32,
+        messages: MessageRegistry,
+        /// The session to take back after a lost connection.
+        resume: Option<SessionToken>,
+    },
+}
+
+impl TypeName for HandshakeMsgServer {
+    // The handshake has to decode on every version, so this message never changes.
+    fn get_type_name() -> String {
+        "stereokit_bevy::networking::HandshakeMsgServer::v2".to_string()
+    }
+}
+
+impl ServerMessage for HandshakeMsgServer {
+    fn server(self, world: &mut World, client_id: ClientId) {
+        match self {
+            HandshakeMsgServer::Hello {
+                protocol_version,
+                messages,
+                resume,
+            } => hello_msg(world, client_id, protocol_version, messages, resume),
+        }
+    }
+
+    fn _server(world: &mut World, msg_bytes: &[u8], client_id: ClientId) {
+        if let Some(msg) = receive_handshake_from_client::<Self>(world, msg_bytes, client_id) {
+            msg.server(world, client_id);
+        }
+    }
+
+    fn channel_type(&self) -> ChannelType {
+        match self {
+            HandshakeMsgServer::Hello { .. } => ChannelType::OrderedReliable,
+        }
+    }
+
+    fn plugin(app: &mut App) {
+        app.init_resource::<AcceptedClients>();
+        app.add_event::<ClientJoined>();
+        app.init_resource::<Rejected>();
+        app.init_resource::<Sessions>();
+        app.init_resource::<SessionConfig>();
+        app.add_event::<ClientResumed>();
+        app.add_system(client_left);
+        app.add_system(disconnect_rejected);
+        app.add_system(expire_sessions);
+    }
+}
+
+fn hello_msg(
+    world: &mut World,
+    client_id: ClientId,
+    protocol_version: u32,
+    messages: MessageRegistry,
+    resume: Option<SessionToken>,
+) {
+    let rejection = if protocol_version != PROTOCOL_VERSION {
+        Some(format!(
+            "protocol version {protocol_version} doesn't match the server's {PROTOCOL_VERSION}"
+        ))
+    } else {
+        world.resource::<MessageRegistry>().incompatibility(&messages)
+    };
+    let rejection = rejection.or_else(|| {
+        let max_players = world.resource::<NetworkConfig>().max_players?;
+        let players = world.resource::<AcceptedClients>().0.len();
+        (players >= max_players).then(|| "the server is full".to_string())
+    });
+    let mut system_state: SystemState<(
+        NetServer,
+        ResMut<AcceptedClients>,
+        ResMut<Rejected>,
+        ResMut<Sessions>,
+        Res<Time>,
+    )> = SystemState::new(world);
+    let (mut server, mut accepted, mut rejected, mut sessions, time) = system_state.get_mut(world);
+    match rejection {
+        Some(reason) => rejected.reject(&mut server, client_id, reason, time.elapsed()),
+        None => {
+            accepted.0.insert(client_id);
+            let resumed = resume.and_then(|token| Some((token, sessions.resume(token, client_id)?)));
+            let token = match &resumed {
+                Some((token, _)) => *token,
+                None => sessions.start(client_id),
+            };
+            server.send(
+                client_id,
+                HandshakeMsgClient::Accepted {
+                    token,
+                    resumed: resumed.is_some(),
+                },
+            );
+            system_state.apply(world);
+            if let Some((_, resumed)) = resumed {
+                world.send_event(resumed);
+            }
+            world.send_event(ClientJoined(client_id));
+        }
+    }
+}
+
+fn client_left(mut lost: EventReader<ConnectionLostEvent>, mut accepted: ResMut<AcceptedClients>) {
+    for client in lost.iter() {
+        accepted.0.remove(&client.id);
+    }
+}
+
+fn disconnect_rejected(mut rejected: ResMut<Rejected>, mut server: NetServer, time: Res<Time>) {
+    let now = time.elapsed();
+    rejected.0.retain(|(client_id, at)| {
+        if *at > now {
+            return true;
+        }
+        server.disconnect(*client_id);
+        false
+    });
+}
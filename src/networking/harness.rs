@@ -0,0 +1,145 @@
+use crate::networking::interpolation::InterpolationConfig;
+use crate::networking::memory::{MemoryClient, MemoryClientPlugin, MemoryNetwork, MemoryServerPlugin};
+use crate::networking::{StereoKitBevyHeadlessClientPlugins, StereoKitBevyServerPlugins};
+use crate::ModelInfo;
+use bevy_app::{App, PluginGroup};
+use bevy_ecs::prelude::{Entity, With};
+use bevy_quinnet::client::QuinnetClientPlugin;
+use bevy_quinnet::server::QuinnetServerPlugin;
+use bevy_quinnet::shared::ClientId;
+use bevy_time::TimeUpdateStrategy;
+use bevy_transform::prelude::Transform;
+use bevy_transform::TransformBundle;
+use glam::Vec3;
+use leknet::{LeknetClient, LeknetServer, Networked};
+use std::time::Duration;
+use stereokit::{Color128, RenderLayer};
+
+/// How far every app's clock moves in one step.
+const STEP_TIME: Duration = Duration::from_millis(16);
+
+/// A server and any number of headless clients in one process, connected by a [`MemoryNetwork`].
+/// Every [`step`](Self::step) updates the server and then each client once, in that order. Nothing
+/// goes through a socket and time moves by the same amount every step, so every run is the same.
+pub struct LoopbackHarness {
+    pub server: App,
+    pub clients: Vec<App>,
+    network: MemoryNetwork,
+}
+
+impl LoopbackHarness {
+    pub fn new(clients: usize) -> Self {
+        Self::with_apps(clients, |_| {}, |_, _| {})
+    }
+
+    /// Like [`new`](Self::new), with a chance to add systems or resources to every app before it starts.
+    pub fn with_apps(clients: usize, server_setup: impl FnOnce(&mut App), mut client_setup: impl FnMut(usize, &mut App)) -> Self {
+        let network = MemoryNetwork::default();
+        let mut server = App::new();
+        server.add_plugins(
+            StereoKitBevyServerPlugins
+                .build()
+                .disable::<LeknetServer>()
+                .disable::<QuinnetServerPlugin>(),
+        );
+        server.add_plugin(MemoryServerPlugin(network.clone()));
+        server.insert_resource(TimeUpdateStrategy::ManualDuration(STEP_TIME));
+        server_setup(&mut server);
+        server.update();
+        let clients = (0..clients)
+            .map(|i| {
+                let mut client = App::new();
+                client.add_plugins(
+                    StereoKitBevyHeadlessClientPlugins
+                        .build()
+                        .disable::<LeknetClient>()
+                        .disable::<QuinnetClientPlugin>(),
+                );
+                client.add_plugin(MemoryClientPlugin(network.clone()));
+                client.insert_resource(TimeUpdateStrategy::ManualDuration(STEP_TIME));
+                // Remote transforms show up right away so tests don't need to wait on the clock.
+                client.insert_resource(InterpolationConfig {
+                    delay: 0.0,
+                    ..Default::default()
+                });
+                client_setup(i, &mut client);
+                client
+            })
+            .collect();
+        Self {
+            server,
+            clients,
+            network,
+        }
+    }
+
+    pub fn client(&mut self, i: usize) -> &mut App {
+        &mut self.clients[i]
+    }
+
+    /// The id the server knows client `i` by, `None` while it's not connected.
+    pub fn client_id(&self, i: usize) -> Option<ClientId> {
+        self.clients[i].world.resource::<MemoryClient>().id()
+    }
+
+    /// Drops the connection of client `i`, like a network outage would.
+    pub fn disconnect(&mut self, i: usize) {
+        if let Some(client_id) = self.client_id(i) {
+            self.network.disconnect(client_id);
+        }
+    }
+
+    pub fn step(&mut self) {
+        self.server.update();
+        for client in &mut self.clients {
+            client.update();
+        }
+    }
+
+    pub fn steps(&mut self, steps: usize) {
+        for _ in 0..steps {
+            self.step();
+        }
+    }
+
+    /// Steps until `condition` holds, at most `max_steps` times. Returns whether it held.
+    pub fn step_until(&mut self, max_steps: usize, mut condition: impl FnMut(&mut Self) -> bool) -> bool {
+        for _ in 0..max_steps {
+            if condition(self) {
+                return true;
+            }
+            self.step();
+        }
+        condition(self)
+    }
+
+    /// Spawns a small networked cube on client `i`.
+    pub fn spawn_cube(&mut self, i: usize, position: Vec3) -> Entity {
+        self.clients[i]
+            .world
+            .spawn((
+                ModelInfo::Cube(Vec3::splat(0.1)),
+                TransformBundle::from(Transform::from_translation(position)),
+                Color128::new(1.0, 1.0, 1.0, 1.0),
+                RenderLayer::LAYER1,
+                Networked,
+            ))
+            .id()
+    }
+
+    /// Whether client `i` has a model within `tolerance` of `position`.
+    pub fn client_sees_model_at(&mut self, i: usize, position: Vec3, tolerance: f32) -> bool {
+        let world = &mut self.clients[i].world;
+        let mut query = world.query_filtered::<&Transform, With<ModelInfo>>();
+        query
+            .iter(world)
+            .any(|transform| transform.translation.distance(position) <= tolerance)
+    }
+
+    /// How many models client `i` has, its own included.
+    pub fn client_model_count(&mut self, i: usize) -> usize {
+        let world = &mut self.clients[i].world;
+        let mut query = world.query_filtered::<(), With<ModelInfo>>();
+        query.iter(world).count()
+    }
+}
@@ -0,0 +1,187 @@
+use crate::networking::model_client::ModelMsgClient;
+use crate::networking::model_server::ServerModel;
+use crate::networking::player_server::ServerPlayer;
+use crate::networking::room_server::ClientJoinedRoom;
+use crate::networking::transport::NetServer;
+use crate::networking::{InRoom, OnServer, Owner, PlayerId};
+use bevy_ecs::prelude::{Entity, EventReader, Local, Query, Res, ResMut, Resource, With};
+use bevy_ecs::system::SystemParam;
+use bevy_quinnet::server::ConnectionLostEvent;
+use bevy_quinnet::shared::ClientId;
+use bevy_time::Time;
+use glam::Vec3;
+use leknet::ServerEntity;
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Which models a client hears about, judged by where its player's head is.
+#[derive(Clone)]
+pub enum AreaOfInterest {
+    Everything,
+    /// Models closer to the head than this.
+    Radius(f32),
+    /// Models in the head's cell of a grid with cells this big, or in one of the cells around it.
+    Cells(f32),
+    /// Called with the head and the model's position.
+    Custom(Arc<dyn Fn(Vec3, Vec3) -> bool + Send + Sync>),
+}
+
+impl AreaOfInterest {
+    pub fn contains(&self, head: Vec3, position: Vec3) -> bool {
+        match self {
+            AreaOfInterest::Everything => true,
+            AreaOfInterest::Radius(radius) => head.distance_squared(position) <= radius * radius,
+            AreaOfInterest::Cells(size) => {
+                let cell = |position: Vec3| (position / *size).floor();
+                let offset = (cell(position) - cell(head)).abs();
+                offset.max_element() <= 1.0
+            }
+            AreaOfInterest::Custom(contains) => contains(head, position),
+        }
+    }
+}
+
+/// Models move in and out of a client's area of interest, this is how often that gets checked.
+#[derive(Resource, Clone)]
+pub struct InterestConfig {
+    pub area: AreaOfInterest,
+    pub interval: Duration,
+}
+
+impl Default for InterestConfig {
+    fn default() -> Self {
+        Self {
+            area: AreaOfInterest::Everything,
+            interval: Duration::from_millis(500),
+        }
+    }
+}
+
+/// The models each client has been sent and not told to drop since.
+#[derive(Resource, Default, Debug)]
+pub(crate) struct KnownModels(HashMap<ClientId, HashSet<Entity>>);
+
+/// What a client knows about and whether it should, for the server's model messages.
+#[derive(SystemParam)]
+pub(crate) struct Interest<'w, 's> {
+    config: Res<'w, InterestConfig>,
+    known: ResMut<'w, KnownModels>,
+    players: Query<'w, 's, (&'static PlayerId, &'static ServerPlayer)>,
+}
+
+impl<'w, 's> Interest<'w, 's> {
+    /// Clients without a player yet are treated as standing at the origin, where players spawn.
+    fn head(&self, client_id: ClientId) -> Vec3 {
+        self.players
+            .iter()
+            .find(|(player_id, _)| player_id.0 == client_id)
+            .map(|(_, player)| player.0.translation)
+            .unwrap_or(Vec3::ZERO)
+    }
+
+    pub fn wants(&self, client_id: ClientId, position: Vec3) -> bool {
+        self.config.area.contains(self.head(client_id), position)
+    }
+
+    pub fn knows(&self, client_id: ClientId, entity: Entity) -> bool {
+        self.known
+            .0
+            .get(&client_id)
+            .map(|known| known.contains(&entity))
+            .unwrap_or(false)
+    }
+
+    pub fn mark_known(&mut self, client_id: ClientId, entity: Entity) {
+        self.known.0.entry(client_id).or_default().insert(entity);
+    }
+}
+
+/// Sends models that came into a client's area of interest and drops the ones that left it.
+/// A child goes with its root, so nobody ends up with half a hierarchy.
+pub(crate) fn update_interest(
+    mut joined: EventReader<ClientJoinedRoom>,
+    mut lost: EventReader<ConnectionLostEvent>,
+    mut interest: Interest,
+    models: Query<(Entity, &ServerModel, &InRoom, Option<&Owner>), With<OnServer>>,
+    mut server: NetServer,
+    time: Res<Time>,
+    mut last_update: Local<Duration>,
+) {
+    for client in lost.iter() {
+        interest.known.0.remove(&client.id);
+    }
+    // A client entering a room starts out knowing nothing and is caught up right away.
+    let mut due: HashSet<ClientId> = HashSet::new();
+    for joined in joined.iter() {
+        interest.known.0.remove(&joined.client_id);
+        due.insert(joined.client_id);
+    }
+    if time.elapsed() - *last_update >= interest.config.interval {
+        *last_update = time.elapsed();
+        due.extend(server.clients());
+    }
+    if due.is_empty() {
+        return;
+    }
+    let root = |mut entity: Entity| {
+        let mut depth = 0;
+        while let Some(parent) = models.get(entity).ok().and_then(|(_, model, _, _)| model.0.parent) {
+            if models.get(parent.0).is_err() {
+                break;
+            }
+            entity = parent.0;
+            depth += 1;
+            // A broken loop of parents must not hang the server.
+            if depth > models.iter().len() {
+                break;
+            }
+        }
+        (entity, depth)
+    };
+    let mut sorted: Vec<_> = models
+        .iter()
+        .map(|(entity, model, room, owner)| {
+            let (root, depth) = root(entity);
+            (entity, model, room, owner, root, depth)
+        })
+        .collect();
+    // Parents are sent before their children and children dropped before their parents.
+    sorted.sort_by_key(|(_, _, _, _, _, depth)| *depth);
+    for client_id in due {
+        let room = match server.room_of(client_id) {
+            None => continue,
+            Some(room) => room,
+        };
+        let head = interest.head(client_id);
+        let area = interest.config.area.clone();
+        let known = interest.known.0.entry(client_id).or_default();
+        known.retain(|entity| models.contains(*entity));
+        let mut leaving = vec![];
+        for (entity, model, in_room, owner, root, _) in &sorted {
+            // Models move rooms along with their owner.
+            if in_room.0 != room {
+                if known.remove(entity) {
+                    leaving.push(*entity);
+                }
+                continue;
+            }
+            // Clients always keep their own models.
+            let owned = owner.map(|owner| owner.0 == client_id).unwrap_or(false);
+            let position = models
+                .get(*root)
+                .map(|(_, root, _, _)| root.0.transform.translation)
+                .unwrap();
+            let wanted = owned || area.contains(head, position);
+            if wanted && !known.contains(entity) {
+                known.insert(*entity);
+                server.send(client_id, ModelMsgClient::ModelAdded(ServerEntity(*entity), model.0.clone()));
+            } else if !wanted && known.remove(entity) {
+                leaving.push(*entity);
+            }
+        }
+        for entity in leaving.into_iter().rev() {
+            server.send(client_id, ModelMsgClient::ModelRemoved(ServerEntity(entity)));
+        }
+    }
+}
@@ -0,0 +1,170 @@
+use crate::networking::diagnostics::{record_dropped, Peer};
+use crate::networking::HasAuthority;
+use bevy_ecs::prelude::{Component, Entity, Query, Res, Resource, Without, World};
+use bevy_quinnet::shared::ClientId;
+use bevy_time::Time;
+use bevy_transform::prelude::Transform;
+use leknet::ServerEntity;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+
+/// Sequence number stamped on every unreliable update so stale packets can be dropped.
+/// Comparison wraps around so a long session doesn't stop accepting updates.
+#[derive(Clone, Copy, Component, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Sequence(pub u32);
+
+impl Sequence {
+    pub fn is_newer_than(self, other: Sequence) -> bool {
+        let diff = self.0.wrapping_sub(other.0);
+        diff != 0 && diff < u32::MAX / 2
+    }
+}
+
+/// Hands out the sequence numbers for outgoing updates. The server restamps everything it
+/// relays with its own counter so ownership changes don't make updates look old.
+#[derive(Resource, Default)]
+pub struct SequenceCounter(u32);
+
+impl SequenceCounter {
+    pub fn next(&mut self) -> Sequence {
+        self.0 = self.0.wrapping_add(1);
+        Sequence(self.0)
+    }
+}
+
+/// How far behind the newest received state remote transforms are rendered.
+/// A larger delay hides more jitter at the cost of latency, zero snaps to the newest state.
+#[derive(Resource, Clone, Debug)]
+pub struct InterpolationConfig {
+    pub delay: f64,
+    pub max_snapshots: usize,
+}
+
+impl Default for InterpolationConfig {
+    fn default() -> Self {
+        Self {
+            delay: 0.1,
+            max_snapshots: 32,
+        }
+    }
+}
+
+/// Received transforms of a remote entity, oldest first.
+#[derive(Component, Default, Debug)]
+pub struct SnapshotBuffer {
+    last_sequence: Option<Sequence>,
+    snapshots: VecDeque<(f64, Transform)>,
+}
+
+impl SnapshotBuffer {
+    /// Returns false and drops the snapshot if it arrived after a newer one.
+    pub fn push(&mut self, sequence: Sequence, time: f64, transform: Transform, max_snapshots: usize) -> bool {
+        if let Some(last_sequence) = self.last_sequence {
+            if !sequence.is_newer_than(last_sequence) {
+                return false;
+            }
+        }
+        self.last_sequence = Some(sequence);
+        self.snapshots.push_back((time, transform));
+        while self.snapshots.len() > max_snapshots.max(1) {
+            self.snapshots.pop_front();
+        }
+        true
+    }
+
+    /// The newest transform received.
+    pub fn latest(&self) -> Option<Transform> {
+        self.snapshots.back().map(|(_, transform)| *transform)
+    }
+
+    /// The transform at `time`, blending between the two snapshots around it.
+    pub fn sample(&mut self, time: f64) -> Option<Transform> {
+        while self.snapshots.len() > 2 && self.snapshots[1].0 <= time {
+            self.snapshots.pop_front();
+        }
+        let (from_time, from) = *self.snapshots.front()?;
+        let (to_time, to) = match self.snapshots.get(1) {
+            None => return Some(from),
+            Some(to) => *to,
+        };
+        if time <= from_time {
+            return Some(from);
+        }
+        if time >= to_time {
+            return Some(to);
+        }
+        let t = ((time - from_time) / (to_time - from_time)) as f32;
+        Some(Transform {
+            translation: from.translation.lerp(to.translation, t),
+            rotation: from.rotation.slerp(to.rotation, t),
+            scale: from.scale.lerp(to.scale, t),
+        })
+    }
+}
+
+/// Queues a received transform on `entity`, creating the buffer the first time.
+pub(crate) fn push_snapshot(world: &mut World, entity: Entity, sequence: Sequence, transform: Transform) {
+    let time = world.resource::<Time>().elapsed_seconds_f64();
+    let max_snapshots = world.resource::<InterpolationConfig>().max_snapshots;
+    let mut world_entity = world.entity_mut(entity);
+    let accepted = match world_entity.get_mut::<SnapshotBuffer>() {
+        Some(mut buffer) => buffer.push(sequence, time, transform, max_snapshots),
+        None => {
+            let mut buffer = SnapshotBuffer::default();
+            buffer.push(sequence, time, transform, max_snapshots);
+            world_entity.insert(buffer);
+            true
+        }
+    };
+    if !accepted {
+        record_dropped(world, Peer::Server);
+    }
+}
+
+/// Server side check that an update from the owner isn't older than one already relayed.
+pub(crate) fn accept_sequence(
+    world: &mut World,
+    client_id: ClientId,
+    server_entity: ServerEntity,
+    sequence: Sequence,
+) -> bool {
+    accept_sequence_of::<Sequence>(world, client_id, server_entity, sequence)
+}
+
+/// Like [`accept_sequence`] for messages numbered apart from the entity's changes, like hands or
+/// voice. The last accepted sequence is kept in an `S` on the entity.
+pub(crate) fn accept_sequence_of<S: Component + Copy + From<Sequence> + Into<Sequence>>(
+    world: &mut World,
+    client_id: ClientId,
+    server_entity: ServerEntity,
+    sequence: Sequence,
+) -> bool {
+    let mut world_entity = match world.get_entity_mut(server_entity.0) {
+        None => return false,
+        Some(world_entity) => world_entity,
+    };
+    match world_entity.get::<S>().map(|last| (*last).into()) {
+        Some(last) if !sequence.is_newer_than(last) => {}
+        _ => {
+            world_entity.insert(S::from(sequence));
+            return true;
+        }
+    }
+    record_dropped(world, Peer::Client(client_id));
+    false
+}
+
+pub(crate) fn interpolate_transforms(
+    time: Res<Time>,
+    config: Res<InterpolationConfig>,
+    mut query: Query<(&mut SnapshotBuffer, &mut Transform), Without<HasAuthority>>,
+) {
+    let render_time = time.elapsed_seconds_f64() - config.delay;
+    for (mut buffer, mut transform) in query.iter_mut() {
+        if let Some(sampled) = buffer.sample(render_time) {
+            if *transform != sampled {
+                *transform = sampled;
+            }
+        }
+    }
+}
@@ -0,0 +1,191 @@
+use crate::networking::transport::MessageHandlers;
+use bevy_app::{App, CoreSet, Plugin};
+use bevy_ecs::prelude::{Resource, World};
+use bevy_ecs::schedule::IntoSystemConfig;
+use bevy_quinnet::client::{ConnectionEvent, ConnectionLostEvent};
+use bevy_quinnet::server::ConnectionLostEvent as ServerConnectionLostEvent;
+use bevy_quinnet::shared::ClientId;
+use leknet::{EntityMap, TypeName};
+use serde::Serialize;
+use std::collections::{BTreeSet, HashMap};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+enum ToServer {
+    Message(ClientId, String, Vec<u8>),
+    Lost(ClientId),
+}
+
+enum ToClient {
+    Message(String, Vec<u8>),
+    Lost,
+}
+
+#[derive(Default)]
+struct Queues {
+    next_client_id: ClientId,
+    connected: BTreeSet<ClientId>,
+    to_server: Vec<ToServer>,
+    to_clients: HashMap<ClientId, Vec<ToClient>>,
+}
+
+/// Connects a server and clients in one process without sockets. Whatever is sent during an
+/// update arrives in the receiver's next update, in order and without loss, so tests run the same
+/// every time. Clone it into [`MemoryServerPlugin`] and a [`MemoryClientPlugin`] per client.
+#[derive(Clone, Default)]
+pub struct MemoryNetwork(Arc<Mutex<Queues>>);
+
+impl MemoryNetwork {
+    fn lock(&self) -> MutexGuard<Queues> {
+        self.0.lock().unwrap()
+    }
+
+    fn connect(&self) -> ClientId {
+        let mut queues = self.lock();
+        queues.next_client_id += 1;
+        let client_id = queues.next_client_id;
+        queues.connected.insert(client_id);
+        queues.to_clients.insert(client_id, vec![]);
+        client_id
+    }
+
+    /// Drops the connection of `client_id`, both sides hear it's lost.
+    pub fn disconnect(&self, client_id: ClientId) {
+        let mut queues = self.lock();
+        if !queues.connected.remove(&client_id) {
+            return;
+        }
+        queues.to_server.push(ToServer::Lost(client_id));
+        if let Some(queue) = queues.to_clients.get_mut(&client_id) {
+            queue.push(ToClient::Lost);
+        }
+    }
+
+    pub(crate) fn clients(&self) -> Vec<ClientId> {
+        self.lock().connected.iter().copied().collect()
+    }
+
+    pub(crate) fn send_to_server<M: TypeName + Serialize>(&self, client_id: ClientId, msg: &M) {
+        let mut queues = self.lock();
+        if queues.connected.contains(&client_id) {
+            let bytes = bincode::serialize(msg).unwrap();
+            queues.to_server.push(ToServer::Message(client_id, M::get_type_name(), bytes));
+        }
+    }
+
+    pub(crate) fn send_to_client<M: TypeName + Serialize>(&self, client_id: ClientId, msg: &M) {
+        let mut queues = self.lock();
+        if !queues.connected.contains(&client_id) {
+            return;
+        }
+        let bytes = bincode::serialize(msg).unwrap();
+        if let Some(queue) = queues.to_clients.get_mut(&client_id) {
+            queue.push(ToClient::Message(M::get_type_name(), bytes));
+        }
+    }
+}
+
+/// The server's end of a [`MemoryNetwork`].
+#[derive(Resource, Clone)]
+pub struct MemoryServer(pub MemoryNetwork);
+
+/// A client's end of a [`MemoryNetwork`], connected once it has an id.
+#[derive(Resource, Clone)]
+pub struct MemoryClient {
+    network: MemoryNetwork,
+    id: Option<ClientId>,
+}
+
+impl MemoryClient {
+    /// The id the server knows this client by.
+    pub fn id(&self) -> Option<ClientId> {
+        self.id
+    }
+
+    pub(crate) fn connect(&mut self) -> ClientId {
+        let client_id = self.network.connect();
+        self.id = Some(client_id);
+        client_id
+    }
+
+    pub(crate) fn send<M: TypeName + Serialize>(&self, msg: &M) {
+        if let Some(client_id) = self.id {
+            self.network.send_to_server(client_id, msg);
+        }
+    }
+}
+
+/// Serves over a [`MemoryNetwork`]. It takes the place of `LeknetServer` and `QuinnetServerPlugin`,
+/// disable those in [`StereoKitBevyServerPlugins`](crate::networking::StereoKitBevyServerPlugins).
+pub struct MemoryServerPlugin(pub MemoryNetwork);
+
+impl Plugin for MemoryServerPlugin {
+    fn build(&self, app: &mut App) {
+        app.insert_resource(MemoryServer(self.0.clone()));
+        app.add_event::<ServerConnectionLostEvent>();
+        app.init_resource::<EntityMap>();
+        app.add_system(receive_on_server.in_base_set(CoreSet::PreUpdate));
+    }
+}
+
+/// Connects over a [`MemoryNetwork`]. It takes the place of `LeknetClient` and `QuinnetClientPlugin`,
+/// disable those in [`StereoKitBevyHeadlessClientPlugins`](crate::networking::StereoKitBevyHeadlessClientPlugins).
+pub struct MemoryClientPlugin(pub MemoryNetwork);
+
+impl Plugin for MemoryClientPlugin {
+    fn build(&self, app: &mut App) {
+        app.insert_resource(MemoryClient {
+            network: self.0.clone(),
+            id: None,
+        });
+        app.add_event::<ConnectionEvent>();
+        app.add_event::<ConnectionLostEvent>();
+        app.init_resource::<EntityMap>();
+        app.add_system(receive_on_client.in_base_set(CoreSet::PreUpdate));
+    }
+}
+
+fn receive_on_server(world: &mut World) {
+    let received = std::mem::take(&mut world.resource::<MemoryServer>().0.lock().to_server);
+    for received in received {
+        match received {
+            ToServer::Message(client_id, type_name, bytes) => {
+                let handler = world.resource::<MessageHandlers>().server.get(&type_name).copied();
+                if let Some(handler) = handler {
+                    handler(world, &bytes, client_id);
+                }
+            }
+            ToServer::Lost(client_id) => world.send_event(ServerConnectionLostEvent { id: client_id }),
+        }
+    }
+}
+
+fn receive_on_client(world: &mut World) {
+    let memory = world.resource::<MemoryClient>().clone();
+    let client_id = match memory.id {
+        None => return,
+        Some(client_id) => client_id,
+    };
+    let received = memory
+        .network
+        .lock()
+        .to_clients
+        .get_mut(&client_id)
+        .map(std::mem::take)
+        .unwrap_or_default();
+    for received in received {
+        match received {
+            ToClient::Message(type_name, bytes) => {
+                let handler = world.resource::<MessageHandlers>().client.get(&type_name).copied();
+                if let Some(handler) = handler {
+                    handler(world, &bytes);
+                }
+            }
+            ToClient::Lost => {
+                world.resource_mut::<MemoryClient>().id = None;
+                memory.network.lock().to_clients.remove(&client_id);
+                world.send_event(ConnectionLostEvent { id: client_id });
+                return;
+            }
+        }
+    }
+}
@@ -1,14 +1,56 @@
 use crate::{model_draw, ModelInfo};
-use bevy_app::{App, Plugin, PluginGroup, PluginGroupBuilder};
-use bevy_ecs::prelude::{Component, Schedules};
+use bevy_app::{App, CoreSet, Plugin, PluginGroup, PluginGroupBuilder};
+use bevy_ecs::prelude::{Component, Resource, Schedules};
+use bevy_quinnet::shared::ClientId;
 use bevy_transform::prelude::Transform;
 use bevy_transform::systems::{propagate_transforms, sync_simple_transforms};
-use leknet::{ClientMessage, LeknetClient, LeknetServer, ServerMessage};
+use crate::networking::compression::ModelDelta;
+use crate::networking::config::NetworkConfig;
+use crate::networking::handshake_client::MessageRegistry;
+use crate::networking::interpolation::{InterpolationConfig, Sequence, SequenceCounter};
+use crate::networking::replication::{ReplicationConfig, SendBudget};
+use crate::networking::transport::{HostQueue, MessageHandlers};
+use bevy_ecs::schedule::IntoSystemConfig;
+use leknet::{ClientMessage, LeknetClient, LeknetServer, ServerEntity, ServerMessage};
 use serde::{Deserialize, Serialize};
 use stereokit::{Color128, RenderLayer, Settings};
 
+pub mod admin;
+pub mod asset_client;
+mod asset_server;
+pub mod avatar;
+pub mod clock_client;
+mod clock_server;
+pub mod compression;
+pub mod config;
+pub mod diagnostics;
+pub mod discovery;
+pub mod handshake_client;
+mod handshake_server;
+pub mod hands;
+pub mod harness;
+pub mod interest;
+pub mod interpolation;
+pub mod memory;
 mod model_client;
 mod model_server;
+pub mod network_event_client;
+mod network_event_server;
+pub mod ownership_client;
+pub mod persistence;
+pub mod recording;
+pub mod reconnect;
+pub mod replication;
+pub mod room_client;
+mod room_server;
+pub mod roster_client;
+mod roster_server;
+pub mod simulator;
+pub mod transport;
+pub mod voice;
+pub mod voice_client;
+mod voice_server;
+mod ownership_server;
 #[cfg(test)]
 mod tests;
 pub mod player_client;
@@ -17,21 +59,102 @@ mod player_server;
 #[derive(Clone, Copy, Component, Debug, Serialize, Deserialize)]
 pub struct Player;
 
+/// The client a player entity stands for. Unlike [`Owner`] this never changes.
+#[derive(Clone, Copy, Component, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct PlayerId(pub ClientId);
+
+/// How this client shows up to others. Change the resource at any time, others see the change.
+#[derive(Resource, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct PlayerProfile {
+    pub name: String,
+    pub color: Color128,
+    /// Which avatar others draw for this player.
+    pub avatar: String,
+}
+
+impl Default for PlayerProfile {
+    fn default() -> Self {
+        Self {
+            name: "Player".to_string(),
+            color: Color128::new(1.0, 1.0, 1.0, 1.0),
+            avatar: "default".to_string(),
+        }
+    }
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct ModelData {
     model_info: ModelInfo,
+    asset: Option<AssetId>,
     transform: Transform,
     color128: Color128,
     render_layer: RenderLayer,
+    /// The transform is relative to this entity when set.
+    parent: Option<ServerEntity>,
+}
+
+impl ModelData {
+    /// Model bytes are left out, they're streamed separately under the returned asset id.
+    pub fn new(model_info: &ModelInfo, transform: Transform, color128: Color128, render_layer: RenderLayer) -> Self {
+        let (model_info, asset) = match model_info {
+            ModelInfo::Mem { name, mem } => (
+                ModelInfo::Mem {
+                    name: name.clone(),
+                    mem: vec![],
+                },
+                Some(AssetId::new(mem)),
+            ),
+            model_info => (model_info.clone(), None),
+        };
+        Self {
+            model_info,
+            asset,
+            transform,
+            color128,
+            render_layer,
+            parent: None,
+        }
+    }
+
+    pub fn with_parent(mut self, parent: Option<ServerEntity>) -> Self {
+        self.parent = parent;
+        self
+    }
+}
+
+/// Identifies the bytes of a [`ModelInfo::Mem`] model, the same bytes always get the same id, on
+/// every machine and in every build. It's their blake3 hash, so received bytes can be checked against it.
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AssetId(pub [u8; 32]);
+
+impl AssetId {
+    pub fn new(bytes: &[u8]) -> Self {
+        Self(*blake3::hash(bytes).as_bytes())
+    }
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct ModelData2 {
-    transform: Transform,
-    color128: Color128,
-    render_layer: RenderLayer,
+    sequence: Sequence,
+    delta: ModelDelta,
+    /// The whole state of a model that stopped changing, sent over a reliable channel.
+    settled: bool,
 }
 
+/// The client allowed to change a networked entity. Tracked by the server and mirrored to clients.
+#[derive(Clone, Copy, Component, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Owner(pub ClientId);
+
+/// Names a model for as long as the client that made it runs, so the server can tell a model
+/// announced again after a lost session or a restart from a new one. Only the server and the
+/// client that made the model ever see it.
+#[derive(Clone, Copy, Component, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct ModelKey(pub u64);
+
+/// Marks an entity this client currently owns, only these entities get their changes sent.
+#[derive(Component)]
+pub struct HasAuthority;
+
 #[derive(Component)]
 pub struct IgnoreModelAdd;
 #[derive(Component)]
@@ -41,29 +164,169 @@ pub struct IgnorePlayerAdd;
 #[derive(Component)]
 pub struct IgnorePlayerChanged;
 
+/// On entities the server spawns to stand for a [`ServerEntity`](leknet::ServerEntity), so they
+/// can be told apart from client entities when a host runs both in one world.
+#[derive(Component)]
+pub struct OnServer;
+
+/// The room a server entity lives in, only that room's members hear about it.
+#[derive(Component, Clone, Debug, PartialEq, Eq)]
+pub struct InRoom(pub String);
+
 pub struct StereoKitBevyClient;
 pub struct StereoKitBevyServer;
+/// A client without StereoKit, nothing is drawn and the local player stays where it spawned.
+/// Call [`App::update`] yourself, there is no runner.
+pub struct StereoKitBevyHeadlessClient;
+/// Runs the server and a local client in one StereoKit app, see [`StereoKitBevyHostPlugins`].
+pub struct StereoKitBevyHost;
+
+/// The message types both sides must agree on. The handshake messages aren't listed, they never change.
+fn register_messages(registry: &mut MessageRegistry) {
+    registry.register::<model_client::ModelMsgClient>();
+    registry.register::<model_server::ModelMsgServer>();
+    registry.register::<player_client::PlayerMsgClient>();
+    registry.register::<player_server::PlayerMsgServer>();
+    registry.register::<ownership_client::OwnershipMsgClient>();
+    registry.register::<ownership_server::OwnershipMsgServer>();
+    registry.register::<asset_client::AssetMsgClient>();
+    registry.register::<asset_server::AssetMsgServer>();
+    registry.register::<roster_client::RosterMsgClient>();
+    registry.register::<roster_server::RosterMsgServer>();
+    registry.register::<voice_client::VoiceMsgClient>();
+    registry.register::<voice_server::VoiceMsgServer>();
+    registry.register::<room_client::RoomMsgClient>();
+    registry.register::<room_server::RoomMsgServer>();
+    registry.register::<clock_client::ClockMsgClient>();
+    registry.register::<clock_server::ClockMsgServer>();
+}
+
+/// Decoders for the same message types, for replays and the in-memory transport.
+fn register_handlers(handlers: &mut MessageHandlers) {
+    handlers.register::<handshake_client::HandshakeMsgClient, handshake_server::HandshakeMsgServer>();
+    handlers.register::<model_client::ModelMsgClient, model_server::ModelMsgServer>();
+    handlers.register::<player_client::PlayerMsgClient, player_server::PlayerMsgServer>();
+    handlers.register::<ownership_client::OwnershipMsgClient, ownership_server::OwnershipMsgServer>();
+    handlers.register::<asset_client::AssetMsgClient, asset_server::AssetMsgServer>();
+    handlers.register::<roster_client::RosterMsgClient, roster_server::RosterMsgServer>();
+    handlers.register::<voice_client::VoiceMsgClient, voice_server::VoiceMsgServer>();
+    handlers.register::<room_client::RoomMsgClient, room_server::RoomMsgServer>();
+    handlers.register::<clock_client::ClockMsgClient, clock_server::ClockMsgServer>();
+}
+
+/// Added once per app, a host has both halves.
+fn add_handlers(app: &mut App) {
+    register_handlers(&mut app.world.get_resource_or_insert_with(MessageHandlers::default));
+    app.add_system(recording::replay);
+}
+
+fn add_client(app: &mut App) {
+    handshake_client::HandshakeMsgClient::add_plugin_client(app);
+    register_messages(&mut app.world.get_resource_or_insert_with(MessageRegistry::default));
+    model_client::ModelMsgClient::add_plugin_client(app);
+    player_client::PlayerMsgClient::add_plugin_client(app);
+    ownership_client::OwnershipMsgClient::add_plugin_client(app);
+    asset_client::AssetMsgClient::add_plugin_client(app);
+    roster_client::RosterMsgClient::add_plugin_client(app);
+    voice_client::VoiceMsgClient::add_plugin_client(app);
+    room_client::RoomMsgClient::add_plugin_client(app);
+    clock_client::ClockMsgClient::add_plugin_client(app);
+    diagnostics::add_diagnostics(app);
+    app.init_resource::<discovery::DiscoveryConfig>();
+    app.init_resource::<discovery::DiscoveredServers>();
+    app.add_startup_system(discovery::open_discovery);
+    app.add_system(discovery::discover_servers);
+    app.init_resource::<InterpolationConfig>();
+    app.init_resource::<SequenceCounter>();
+    app.add_system(interpolation::interpolate_transforms);
+    app.init_resource::<ReplicationConfig>();
+    app.init_resource::<SendBudget>();
+    app.add_system(replication::refill_budget.before(replication::accumulate_priority));
+    app.add_system(replication::accumulate_priority);
+    app.init_resource::<NetworkConfig>();
+    app.add_event::<config::EndpointFailed>();
+    app.init_resource::<avatar::AvatarLibrary>();
+    app.add_system(avatar::attach_avatars);
+    app.add_system(avatar::build_avatars.after(avatar::attach_avatars));
+    app.add_system(avatar::recolor_avatars.after(avatar::build_avatars));
+    app.add_system(avatar::pose_avatar_hands);
+    app.add_system(avatar::draw_avatar_joints);
+}
+
+fn add_stereokit(app: &mut App) {
+    app.insert_resource(unsafe { stereokit::Sk::create_unsafe() });
+    app.insert_non_send_resource(unsafe { stereokit::SkDraw::create_unsafe() });
+    app.add_system(model_draw);
+}
+
+fn add_server(app: &mut App) {
+    handshake_server::HandshakeMsgServer::add_plugin_server(app);
+    register_messages(&mut app.world.get_resource_or_insert_with(MessageRegistry::default));
+    model_server::ModelMsgServer::add_plugin_server(app);
+    player_server::PlayerMsgServer::add_plugin_server(app);
+    ownership_server::OwnershipMsgServer::add_plugin_server(app);
+    asset_server::AssetMsgServer::add_plugin_server(app);
+    roster_server::RosterMsgServer::add_plugin_server(app);
+    voice_server::VoiceMsgServer::add_plugin_server(app);
+    room_server::RoomMsgServer::add_plugin_server(app);
+    clock_server::ClockMsgServer::add_plugin_server(app);
+    diagnostics::add_diagnostics(app);
+    app.add_system(diagnostics::forget_clients);
+    app.init_resource::<recording::RecordingConfig>();
+    app.add_startup_system(recording::start_recording);
+    app.add_system(recording::flush_recording);
+    app.init_resource::<discovery::DiscoveryConfig>();
+    app.add_startup_system(discovery::listen_for_queries);
+    app.add_system(discovery::answer_queries);
+    app.init_resource::<SequenceCounter>();
+    app.init_resource::<NetworkConfig>();
+    app.add_event::<config::EndpointFailed>();
+    app.add_startup_system(config::start_server);
+    app.init_resource::<persistence::PersistenceConfig>();
+    app.add_startup_system(persistence::load_world);
+    app.add_event::<persistence::SaveWorld>();
+    // Last, so it sees an AppExit sent during the update it's the end of.
+    app.add_system(persistence::save_world.in_base_set(CoreSet::Last));
+    app.add_event::<admin::KickClient>();
+    app.add_event::<admin::ClearWorld>();
+    app.add_system(admin::kick_clients);
+    app.add_system(admin::clear_world);
+    app.add_system(
+        reconnect::leave_sessions
+            .before(room_server::client_left)
+            .before(ownership_server::client_disconnected),
+    );
+}
+
+fn stereokit_loop(mut app: App) {
+    Settings::default()
+        .init()
+        .unwrap()
+        .run(|_| app.update(), |_| ());
+}
 
 impl Plugin for StereoKitBevyClient {
     fn build(&self, app: &mut App) {
-        model_client::ModelMsgClient::add_plugin_client(app);
-        player_client::PlayerMsgClient::add_plugin_client(app);
-        fn stereokit_loop(mut app: App) {
-            Settings::default()
-                .init()
-                .unwrap()
-                .run(|_| app.update(), |_| ());
-        }
+        add_client(app);
+        add_stereokit(app);
+        add_handlers(app);
         app.set_runner(stereokit_loop);
-        app.insert_resource(unsafe { stereokit::Sk::create_unsafe() });
-        app.insert_non_send_resource(unsafe { stereokit::SkDraw::create_unsafe() });
-        app.add_system(model_draw);
+        app.add_startup_system(config::connect_to_server);
+        app.add_system(reconnect::reconnect);
+    }
+}
+impl Plugin for StereoKitBevyHeadlessClient {
+    fn build(&self, app: &mut App) {
+        add_client(app);
+        add_handlers(app);
+        app.add_startup_system(config::connect_to_server);
+        app.add_system(reconnect::reconnect);
     }
 }
 impl Plugin for StereoKitBevyServer {
     fn build(&self, app: &mut App) {
-        model_server::ModelMsgServer::add_plugin_server(app);
-        player_server::PlayerMsgServer::add_plugin_server(app);
+        add_server(app);
+        add_handlers(app);
         fn server_loop(mut app: App) {
             loop {
                 app.update()
@@ -72,9 +335,24 @@ impl Plugin for StereoKitBevyServer {
         app.set_runner(server_loop);
     }
 }
+impl Plugin for StereoKitBevyHost {
+    fn build(&self, app: &mut App) {
+        add_client(app);
+        add_stereokit(app);
+        add_server(app);
+        add_handlers(app);
+        app.set_runner(stereokit_loop);
+        // The local client talks to the server through this instead of a connection.
+        app.init_resource::<HostQueue>();
+        app.add_system(transport::run_host_messages);
+    }
+}
 
 pub struct StereoKitBevyClientPlugins;
 pub struct StereoKitBevyServerPlugins;
+pub struct StereoKitBevyHeadlessClientPlugins;
+/// A StereoKit app that hosts the session others on the network connect to.
+pub struct StereoKitBevyHostPlugins;
 
 impl PluginGroup for StereoKitBevyClientPlugins {
     fn build(self) -> PluginGroupBuilder {
@@ -92,6 +370,21 @@ impl PluginGroup for StereoKitBevyClientPlugins {
     }
 }
 
+impl PluginGroup for StereoKitBevyHeadlessClientPlugins {
+    fn build(self) -> PluginGroupBuilder {
+        PluginGroupBuilder::start::<Self>()
+            .add(StereoKitBevyHeadlessClient)
+            .add(LeknetClient)
+            .add(bevy_transform::TransformPlugin)
+            .add(bevy_hierarchy::HierarchyPlugin)
+            .add(bevy_core::TaskPoolPlugin::default())
+            .add(bevy_core::TypeRegistrationPlugin)
+            .add(bevy_core::FrameCountPlugin)
+            .add(bevy_time::TimePlugin)
+            .add(bevy_quinnet::client::QuinnetClientPlugin::default())
+    }
+}
+
 impl PluginGroup for StereoKitBevyServerPlugins {
     fn build(self) -> PluginGroupBuilder {
         PluginGroupBuilder::start::<Self>()
@@ -101,3 +394,20 @@ impl PluginGroup for StereoKitBevyServerPlugins {
             .add(bevy_quinnet::server::QuinnetServerPlugin::default())
     }
 }
+
+impl PluginGroup for StereoKitBevyHostPlugins {
+    fn build(self) -> PluginGroupBuilder {
+        PluginGroupBuilder::start::<Self>()
+            .add(StereoKitBevyHost)
+            .add(LeknetClient)
+            .add(LeknetServer)
+            .add(bevy_transform::TransformPlugin)
+            .add(bevy_hierarchy::HierarchyPlugin)
+            .add(bevy_core::TaskPoolPlugin::default())
+            .add(bevy_core::TypeRegistrationPlugin)
+            .add(bevy_core::FrameCountPlugin)
+            .add(bevy_time::TimePlugin)
+            .add(bevy_quinnet::client::QuinnetClientPlugin::default())
+            .add(bevy_quinnet::server::QuinnetServerPlugin::default())
+    }
+}
@@ -1,28 +1,44 @@
+use crate::networking::asset_client::{
+    model_from_mem, placeholder_model, request_asset, AssetCache, PendingAsset,
+};
+use crate::networking::compression::{DirtyMask, LastSent, ModelDelta};
+use crate::networking::interpolation::{push_snapshot, SequenceCounter, SnapshotBuffer};
 use crate::networking::model_server::ModelMsgServer;
-use crate::networking::{IgnoreModelAdd, IgnoreModelChanged, ModelData, ModelData2};
+use crate::networking::player_client::player_changed;
+use crate::networking::transport::{receive_from_server, NetClient};
+use crate::networking::replication::{
+    accumulate_priority, due, ReplicationConfig, ReplicationRate, ReplicationState, SendBudget,
+};
+use bevy_ecs::schedule::IntoSystemConfig;
+use crate::networking::{AssetId, HasAuthority, IgnoreModelAdd, ModelData, ModelData2, ModelKey};
 use crate::{ModelBundle, ModelInfo};
 use bevy_app::App;
 use bevy_ecs::prelude::{
-    Added, Changed, Commands, Entity, NonSend, Or, Query, Res, ResMut, With, World,
+    Changed, Commands, Component, Entity, Local, NonSend, Or, Query, Ref, Res, ResMut, With, World,
 };
+use bevy_hierarchy::{BuildChildren, BuildWorldChildren, Children, DespawnRecursiveExt, Parent};
 use bevy_ecs::query::Without;
 use bevy_ecs::system::SystemState;
-use bevy_quinnet::client::Client;
 use bevy_quinnet::shared::channel::ChannelType;
-use bevy_quinnet::shared::ClientId;
+use bevy_time::Time;
 use bevy_transform::prelude::Transform;
+use bevy_transform::TransformBundle;
 use leknet::{
-    ClientEntity, ClientMessage, EntityMap, LekClient, Networked, ServerEntity, TypeName,
+    ClientEntity, ClientMessage, EntityMap, Networked, ServerEntity, TypeName,
 };
 use serde::{Deserialize, Serialize};
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hash, Hasher};
 use stereokit::{Color128, Material, Model, RenderLayer, SkDraw, StereoKitMultiThread};
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub enum ModelMsgClient {
     ModelAdded(ServerEntity, ModelData),
     ModelChanged(ServerEntity, ModelData2),
+    ParentChanged(ServerEntity, Option<ServerEntity>),
+    /// The model left this client's area of interest.
+    ModelRemoved(ServerEntity),
     EntityMap(ServerEntity, ClientEntity),
-    GetAllModelData(ClientId),
 }
 
 impl TypeName for ModelMsgClient {
@@ -40,70 +56,46 @@ impl ClientMessage for ModelMsgClient {
             ModelMsgClient::ModelChanged(server_entity, model_data) => {
                 model_changed_msg(world, server_entity, model_data)
             }
+            ModelMsgClient::ParentChanged(server_entity, parent) => {
+                if let Some(client_entity) = world.resource::<EntityMap>().get_by_right(&server_entity).cloned() {
+                    set_parent(world, client_entity.0, parent);
+                }
+            }
+            ModelMsgClient::ModelRemoved(server_entity) => model_removed_msg(world, server_entity),
             ModelMsgClient::EntityMap(server_entity, client_entity) => {
-                let mut system_state: SystemState<ResMut<EntityMap>> = SystemState::new(world);
-                let mut entity_map: ResMut<EntityMap> = system_state.get_mut(world);
-                entity_map.0.insert(client_entity, server_entity);
+                entity_map_msg(world, server_entity, client_entity)
             }
-            ModelMsgClient::GetAllModelData(client_id) => get_all_model_data_msg(world, client_id),
         }
     }
 
     fn _client(world: &mut World, msg_bytes: &[u8]) {
-        bincode::deserialize::<Self>(msg_bytes)
-            .unwrap()
-            .client(world)
+        if let Some(msg) = receive_from_server::<Self>(world, msg_bytes) {
+            msg.client(world)
+        }
     }
 
     fn channel_type(&self) -> ChannelType {
         match self {
             ModelMsgClient::ModelAdded(_, _) => ChannelType::OrderedReliable,
+            ModelMsgClient::ModelChanged(_, model_data) if model_data.settled => ChannelType::OrderedReliable,
             ModelMsgClient::ModelChanged(_, _) => ChannelType::Unreliable,
+            ModelMsgClient::ParentChanged(_, _) => ChannelType::OrderedReliable,
+            ModelMsgClient::ModelRemoved(_) => ChannelType::OrderedReliable,
             ModelMsgClient::EntityMap(_, _) => ChannelType::OrderedReliable,
-            ModelMsgClient::GetAllModelData(_) => ChannelType::OrderedReliable,
         }
     }
 
     fn plugin(app: &mut App) {
         app.add_system(model_added);
-        app.add_system(model_changed);
+        app.add_system(model_dirty.before(accumulate_priority));
+        app.add_system(model_settle.before(accumulate_priority));
+        // Players spend the send budget they share first, a stuck head is worse than a stuck model.
+        app.add_system(model_changed.after(accumulate_priority).after(player_changed));
+        app.add_system(model_parent_changed.after(model_added));
+        app.add_system(resolve_pending_parents);
     }
 }
 
-fn get_all_model_data_msg(world: &mut World, client_id: ClientId) {
-    let mut system_state: SystemState<(
-        Query<
-            (Entity, &ModelInfo, &Transform, &Color128, &RenderLayer),
-            (With<Networked>, Without<IgnoreModelChanged>),
-        >,
-        ResMut<Client>,
-        Res<EntityMap>,
-    )> = SystemState::new(world);
-    let (query, mut client, entity_map) = system_state.get_mut(world);
-    let mut client: ResMut<Client> = client;
-    let entity_map: Res<EntityMap> = entity_map;
-    let mut models = vec![];
-    for (entity, model_info, transform, color128, render_layer) in query.iter() {
-        let server_entity = match entity_map.get_by_left(&ClientEntity(entity)) {
-            None => continue,
-            Some(server_entity) => server_entity.clone(),
-        };
-        models.push((
-            server_entity,
-            ModelData {
-                model_info: model_info.clone(),
-                transform: *transform,
-                color128: *color128,
-                render_layer: *render_layer,
-            },
-        ))
-    }
-    client
-        .connection_mut()
-        .send_lek_msg(ModelMsgServer::AllModelData(client_id, models))
-        .unwrap();
-}
-
 fn model_changed_msg(world: &mut World, server_entity: ServerEntity, model_data: ModelData2) {
     let mut client_entity = None;
     {
@@ -113,99 +105,360 @@ fn model_changed_msg(world: &mut World, server_entity: ServerEntity, model_data:
     }
     if let Some(client_entity) = client_entity {
         let mut world_entity = world.entity_mut(client_entity.0);
+        if world_entity.contains::<HasAuthority>() {
+            return;
+        }
         match model_data {
-            ModelData2 {
-                transform,
-                color128,
-                render_layer,
-            } => {
-                *world_entity.get_mut().unwrap() = transform;
-                *world_entity.get_mut().unwrap() = color128;
-                *world_entity.get_mut().unwrap() = render_layer;
+            ModelData2 { sequence, delta, .. } => {
+                if let Some(color128) = delta.color128() {
+                    *world_entity.get_mut().unwrap() = color128;
+                }
+                if let Some(render_layer) = delta.render_layer() {
+                    *world_entity.get_mut().unwrap() = render_layer;
+                }
+                if delta.has_transform() {
+                    let mut transform = world_entity
+                        .get::<SnapshotBuffer>()
+                        .and_then(|buffer| buffer.latest())
+                        .unwrap_or_else(|| *world_entity.get::<Transform>().unwrap());
+                    delta.apply_transform(&mut transform);
+                    push_snapshot(world, client_entity.0, sequence, transform);
+                }
             }
         }
     }
 }
 
+/// The server took back a model announced again after a lost session, and may have sent it as
+/// somebody else's already. That copy makes way for this client's own entity.
+fn entity_map_msg(world: &mut World, server_entity: ServerEntity, client_entity: ClientEntity) {
+    let copy = world.resource_mut::<EntityMap>().0.remove_by_right(&server_entity);
+    world.resource_mut::<EntityMap>().0.insert(client_entity, server_entity);
+    let copy = match copy {
+        Some((copy, _)) if copy != client_entity => copy.0,
+        _ => return,
+    };
+    let children: Vec<Entity> = world
+        .get::<Children>(copy)
+        .map(|children| children.iter().copied().collect())
+        .unwrap_or_default();
+    if let Some(mut entity) = world.get_entity_mut(client_entity.0) {
+        entity.push_children(&children);
+    }
+    if let Some(copy) = world.get_entity_mut(copy) {
+        copy.despawn_recursive();
+    }
+}
+
+fn model_removed_msg(world: &mut World, server_entity: ServerEntity) {
+    let client_entity = world.resource_mut::<EntityMap>().0.remove_by_right(&server_entity);
+    if let Some((client_entity, _)) = client_entity {
+        if let Some(entity) = world.get_entity_mut(client_entity.0) {
+            entity.despawn_recursive();
+        }
+    }
+}
+
 fn model_added_msg(world: &mut World, server_entity: ServerEntity, model_data: ModelData) {
-    let mut system_state: SystemState<(ResMut<EntityMap>, Commands, NonSend<SkDraw>)> =
-        SystemState::new(world);
-    let (entity_map, commands, sk) = system_state.get_mut(world);
+    // This client's own models come back like this after resuming a session.
+    if world.resource::<EntityMap>().0.contains_right(&server_entity) {
+        return;
+    }
+    let mut system_state: SystemState<(
+        ResMut<EntityMap>,
+        Commands,
+        Option<NonSend<SkDraw>>,
+        Res<AssetCache>,
+    )> = SystemState::new(world);
+    let (entity_map, commands, sk, cache) = system_state.get_mut(world);
     let mut entity_map: ResMut<EntityMap> = entity_map;
     let mut commands: Commands = commands;
-    let sk: NonSend<SkDraw> = sk;
-    let model = match model_data.model_info.clone() {
-        ModelInfo::Mem { .. } => {
-            todo!()
+    let sk: Option<NonSend<SkDraw>> = sk;
+    let cache: Res<AssetCache> = cache;
+    let mut model_info = model_data.model_info;
+    let mut missing_asset = None;
+    if let (ModelInfo::Mem { mem, .. }, Some(asset)) = (&mut model_info, model_data.asset) {
+        match cache.0.get(&asset) {
+            Some(bytes) => *mem = bytes.clone(),
+            None => missing_asset = Some(asset),
         }
-        ModelInfo::Cube(size) => sk.model_create_mesh(sk.mesh_gen_cube(size, 1), Material::DEFAULT),
+    }
+    // Headless clients have no StereoKit to make models with, they only keep the data.
+    let model = sk.map(|sk| match &model_info {
+        // Drawn as a small cube until the bytes arrive.
+        ModelInfo::Mem { .. } if missing_asset.is_some() => placeholder_model(&sk),
+        ModelInfo::Mem { name, mem } => model_from_mem(&sk, name, mem),
+        ModelInfo::Cube(size) => sk.model_create_mesh(sk.mesh_gen_cube(*size, 1), Material::DEFAULT),
+    });
+    let mut entity_commands = match model {
+        Some(model) => commands.spawn(ModelBundle::new(
+            model,
+            model_info,
+            model_data.transform,
+            model_data.color128,
+            model_data.render_layer,
+        )),
+        None => commands.spawn((
+            model_info,
+            TransformBundle::from(model_data.transform),
+            model_data.color128,
+            model_data.render_layer,
+        )),
     };
-    let client_entity = ClientEntity(
-        commands
-            .spawn(ModelBundle::new(
-                model,
-                model_data.model_info,
-                model_data.transform,
-                model_data.color128,
-                model_data.render_layer,
-            ))
-            .insert(IgnoreModelAdd)
-            .id(),
-    );
+    entity_commands.insert(IgnoreModelAdd);
+    if let Some(asset) = missing_asset {
+        entity_commands.insert(PendingAsset(asset));
+    }
+    let client_entity = ClientEntity(entity_commands.id());
     entity_map.insert(client_entity, server_entity);
     system_state.apply(world);
+    if model_data.parent.is_some() {
+        set_parent(world, client_entity.0, model_data.parent);
+    }
+    if let Some(asset) = missing_asset {
+        request_asset(world, asset);
+    }
 }
 
+/// The parent as the server knows it, `None` if it has no parent or the parent isn't networked yet.
+fn server_parent(entity_map: &EntityMap, parent: Option<&Parent>) -> Option<ServerEntity> {
+    parent.and_then(|parent| entity_map.get_by_left(&ClientEntity(parent.get())).copied())
+}
+
+/// Parents `entity` under the client entity of `parent`, or waits for it if it hasn't arrived yet.
+fn set_parent(world: &mut World, entity: Entity, parent: Option<ServerEntity>) {
+    let client_parent = parent.map(|parent| world.resource::<EntityMap>().get_by_right(&parent).cloned());
+    let mut world_entity = world.entity_mut(entity);
+    world_entity.remove::<PendingParent>();
+    match client_parent {
+        None => {
+            world_entity.remove_parent();
+        }
+        Some(Some(client_parent)) => {
+            world_entity.set_parent(client_parent.0);
+        }
+        Some(None) => {
+            world_entity.insert(PendingParent(parent.unwrap()));
+        }
+    }
+}
+
+/// Hands out [`ModelKey`]s, random so other clients can't guess them.
+#[derive(Default)]
+struct ModelKeys {
+    hasher: RandomState,
+    issued: u64,
+}
+
+impl ModelKeys {
+    fn next(&mut self) -> ModelKey {
+        self.issued += 1;
+        let mut hasher = self.hasher.build_hasher();
+        self.issued.hash(&mut hasher);
+        ModelKey(hasher.finish())
+    }
+}
+
+/// Entities spawned before the server accepted this client are announced once it does.
 fn model_added(
     query: Query<
-        (Entity, &ModelInfo, &Transform, &Color128, &RenderLayer),
-        (Added<Networked>, Without<IgnoreModelAdd>),
+        (
+            Entity,
+            Ref<Networked>,
+            &ModelInfo,
+            &Transform,
+            &Color128,
+            &RenderLayer,
+            Option<&Parent>,
+            Option<&ModelKey>,
+        ),
+        Without<IgnoreModelAdd>,
     >,
-    mut client: ResMut<Client>,
+    mut client: NetClient,
+    entity_map: Res<EntityMap>,
+    mut was_connected: Local<bool>,
+    mut commands: Commands,
+    mut cache: ResMut<AssetCache>,
+    mut keys: Local<ModelKeys>,
 ) {
-    if let Some(connection) = client.get_connection_mut() {
-        for (entity, model_info, transform, color128, render_layer) in query.iter() {
-            connection
-                .send_lek_msg(ModelMsgServer::ModelAdded(
-                    ClientEntity(entity),
-                    ModelData {
-                        model_info: model_info.clone(),
-                        transform: *transform,
-                        color128: *color128,
-                        render_layer: *render_layer,
-                    },
-                ))
-                .unwrap()
+    let just_connected = client.is_connected() && !*was_connected;
+    *was_connected = client.is_connected();
+    if client.is_connected() {
+        for (entity, networked, model_info, transform, color128, render_layer, parent, key) in query.iter() {
+            if !just_connected && !networked.is_added() {
+                continue;
+            }
+            // Kept through a resumed session, the server still has it.
+            if entity_map.0.contains_left(&ClientEntity(entity)) {
+                continue;
+            }
+            let parent = server_parent(&entity_map, parent);
+            // The same key every time, so a server that still has the model takes it back.
+            let key = key.copied().unwrap_or_else(|| keys.next());
+            // Uploaded from here when the server asks for it.
+            if let ModelInfo::Mem { mem, .. } = model_info {
+                cache.0.entry(AssetId::new(mem)).or_insert_with(|| mem.clone());
+            }
+            commands
+                .entity(entity)
+                .insert((
+                    HasAuthority,
+                    ReplicationState::default(),
+                    LastSent::new(transform, *color128, *render_layer),
+                    SentParent(parent),
+                    key,
+                ));
+            client.send(ModelMsgServer::ModelAdded(
+                ClientEntity(entity),
+                key,
+                ModelData::new(model_info, *transform, *color128, *render_layer).with_parent(parent),
+            ));
         }
     }
 }
 
-fn model_changed(
-    query: Query<
-        (Entity, &ModelInfo, &Transform, &Color128, &RenderLayer),
+fn model_dirty(
+    mut query: Query<
+        &mut ReplicationState,
         (
             Or<(Changed<Transform>, Changed<Color128>, Changed<RenderLayer>)>,
-            Without<IgnoreModelAdd>,
+            With<ModelInfo>,
+            With<HasAuthority>,
             With<Networked>,
         ),
     >,
-    mut client: ResMut<Client>,
+) {
+    for mut state in query.iter_mut() {
+        state.mark_dirty();
+    }
+}
+
+/// Models that stopped changing are due for their last, reliable update.
+fn model_settle(
+    mut query: Query<(&mut ReplicationState, &LastSent), (With<ModelInfo>, With<HasAuthority>, With<Networked>)>,
+    time: Res<Time>,
+) {
+    for (mut state, last_sent) in query.iter_mut() {
+        if last_sent.needs_settling(time.elapsed()) {
+            state.mark_dirty();
+        }
+    }
+}
+
+fn model_changed(
+    mut query: Query<
+        (
+            Entity,
+            &Transform,
+            &Color128,
+            &RenderLayer,
+            &mut ReplicationState,
+            Option<&ReplicationRate>,
+            Option<&mut LastSent>,
+        ),
+        (With<ModelInfo>, With<HasAuthority>, With<Networked>),
+    >,
+    mut client: NetClient,
     entity_map: Res<EntityMap>,
+    mut sequence_counter: ResMut<SequenceCounter>,
+    config: Res<ReplicationConfig>,
+    mut budget: ResMut<SendBudget>,
+    time: Res<Time>,
+    mut commands: Commands,
 ) {
-    if let Some(connection) = client.get_connection_mut() {
-        for (entity, _, transform, color128, render_layer) in query.iter() {
-            if let Some(server_entity) = entity_map.get_by_left(&ClientEntity(entity)) {
-                connection
-                    .send_lek_msg(ModelMsgServer::ModelChanged(
-                        *server_entity,
-                        ModelData2 {
-                            transform: *transform,
-                            color128: *color128,
-                            render_layer: *render_layer,
-                        },
-                    ))
-                    .unwrap()
+    if client.is_connected() {
+        let due = due(
+            &config,
+            query
+                .iter()
+                .map(|(entity, _, _, _, state, rate, _)| (entity, state, rate)),
+        );
+        for entity in due {
+            let (_, transform, color128, render_layer, mut state, _, last_sent) =
+                query.get_mut(entity).unwrap();
+            let server_entity = match entity_map.get_by_left(&ClientEntity(entity)) {
+                None => continue,
+                Some(server_entity) => *server_entity,
+            };
+            let settling = last_sent
+                .as_ref()
+                .map(|last_sent| last_sent.needs_settling(time.elapsed()))
+                .unwrap_or(false);
+            let mask = match &last_sent {
+                Some(_) if settling => DirtyMask::ALL,
+                Some(last_sent) => last_sent.peek(transform, *color128, *render_layer),
+                // Just got ownership of it, nobody knows what we last sent.
+                None => DirtyMask::ALL,
+            };
+            let delta = ModelDelta::new(mask, transform, *color128, *render_layer);
+            if delta.is_empty() {
+                state.sent();
+                continue;
+            }
+            let msg = ModelMsgServer::ModelChanged(
+                server_entity,
+                ModelData2 {
+                    sequence: sequence_counter.next(),
+                    delta,
+                    settled: settling,
+                },
+            );
+            if !budget.try_spend(bincode::serialized_size(&msg).unwrap()) {
+                break;
+            }
+            client.send(msg);
+            state.sent();
+            match last_sent {
+                Some(mut last_sent) => {
+                    last_sent.update(transform, *color128, *render_layer, time.elapsed(), settling);
+                }
+                None => {
+                    let mut last_sent = LastSent::new(transform, *color128, *render_layer);
+                    last_sent.update(transform, *color128, *render_layer, time.elapsed(), settling);
+                    commands.entity(entity).insert(last_sent);
+                }
             }
         }
     }
 }
+
+/// The parent last sent for an entity this client has authority over.
+#[derive(Component)]
+pub(crate) struct SentParent(Option<ServerEntity>);
+
+/// A parent the server told us about that hasn't been added here yet.
+#[derive(Component)]
+pub(crate) struct PendingParent(ServerEntity);
+
+/// Sends reparenting, and parents that only got a server entity after their children were added.
+fn model_parent_changed(
+    mut query: Query<(Entity, Option<&Parent>, &mut SentParent), (With<HasAuthority>, With<Networked>)>,
+    entity_map: Res<EntityMap>,
+    mut client: NetClient,
+) {
+    if !client.is_connected() {
+        return;
+    }
+    for (entity, parent, mut sent) in query.iter_mut() {
+        let parent = server_parent(&entity_map, parent);
+        if parent == sent.0 {
+            continue;
+        }
+        if let Some(server_entity) = entity_map.get_by_left(&ClientEntity(entity)) {
+            client.send(ModelMsgServer::ParentChanged(*server_entity, parent));
+            sent.0 = parent;
+        }
+    }
+}
+
+fn resolve_pending_parents(
+    query: Query<(Entity, &PendingParent)>,
+    entity_map: Res<EntityMap>,
+    mut commands: Commands,
+) {
+    for (entity, pending) in query.iter() {
+        if let Some(parent) = entity_map.get_by_right(&pending.0) {
+            commands.entity(entity).remove::<PendingParent>().set_parent(parent.0);
+        }
+    }
+}
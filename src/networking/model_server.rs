@@ -1,19 +1,30 @@
+use crate::networking::asset_server::ServerAssets;
+use crate::networking::compression::{DirtyMask, ModelDelta};
 use crate::networking::model_client::ModelMsgClient;
-use crate::networking::{ModelData, ModelData2};
+use crate::networking::interest::{update_interest, Interest, InterestConfig, KnownModels};
+use crate::networking::interpolation::{accept_sequence, SequenceCounter};
+use crate::networking::ownership_server::is_owner;
+use crate::networking::transport::{receive_from_client, NetServer};
+use crate::networking::ownership_client::OwnershipMsgClient;
+use crate::networking::{InRoom, ModelData, ModelData2, ModelKey, OnServer, Owner};
 use bevy_app::App;
-use bevy_ecs::prelude::{Commands, EventReader, ResMut, World};
+use bevy_ecs::prelude::{Commands, Component, Entity, ResMut, With, World};
 use bevy_ecs::system::SystemState;
-use bevy_quinnet::server::{ConnectionEvent, Server};
+use crate::networking::room_server::entity_room;
 use bevy_quinnet::shared::channel::ChannelType;
 use bevy_quinnet::shared::ClientId;
-use leknet::{ClientEntity, LekServer, ServerEntity, ServerMessage, TypeName};
+use leknet::{ClientEntity, ServerEntity, ServerMessage, TypeName};
 use serde::{Deserialize, Serialize};
 
+/// The server's copy of a model, for clients that join later and for saving.
+#[derive(Component, Clone, Debug)]
+pub(crate) struct ServerModel(pub ModelData);
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub enum ModelMsgServer {
-    ModelAdded(ClientEntity, ModelData),
+    ModelAdded(ClientEntity, ModelKey, ModelData),
     ModelChanged(ServerEntity, ModelData2),
-    AllModelData(ClientId, Vec<(ServerEntity, ModelData)>),
+    ParentChanged(ServerEntity, Option<ServerEntity>),
 }
 
 impl TypeName for ModelMsgServer {
@@ -25,112 +36,238 @@ impl TypeName for ModelMsgServer {
 impl ServerMessage for ModelMsgServer {
     fn server(self, world: &mut World, client_id: ClientId) {
         match self {
-            ModelMsgServer::ModelAdded(client_entity, model_data) => {
-                model_added_msg(world, client_id, client_entity, model_data)
+            ModelMsgServer::ModelAdded(client_entity, key, model_data) => {
+                model_added_msg(world, client_id, client_entity, key, model_data)
             }
             ModelMsgServer::ModelChanged(server_entity, model_data) => {
                 model_changed_msg(world, client_id, server_entity, model_data)
             }
-            ModelMsgServer::AllModelData(client_id, all_model_data) => {
-                let mut endpoint: SystemState<ResMut<Server>> = SystemState::new(world);
-                let mut endpoint = endpoint.get_mut(world);
-                let endpoint = endpoint.endpoint_mut();
-                for (entity, model_data) in all_model_data {
-                    endpoint
-                        .send_lek_msg(
-                            client_id.clone(),
-                            ModelMsgClient::ModelAdded(entity, model_data),
-                        )
-                        .unwrap();
-                }
+            ModelMsgServer::ParentChanged(server_entity, parent) => {
+                parent_changed_msg(world, client_id, server_entity, parent)
             }
         }
     }
 
     fn _server(world: &mut World, msg_bytes: &[u8], client_id: ClientId) {
-        bincode::deserialize::<Self>(msg_bytes)
-            .unwrap()
-            .server(world, client_id);
+        if let Some(msg) = receive_from_client::<Self>(world, msg_bytes, client_id) {
+            msg.server(world, client_id);
+        }
     }
 
     fn channel_type(&self) -> ChannelType {
         match self {
-            ModelMsgServer::ModelAdded(_, _) => ChannelType::OrderedReliable,
+            ModelMsgServer::ModelAdded(_, _, _) => ChannelType::OrderedReliable,
+            ModelMsgServer::ModelChanged(_, model_data) if model_data.settled => ChannelType::OrderedReliable,
             ModelMsgServer::ModelChanged(_, _) => ChannelType::Unreliable,
-            ModelMsgServer::AllModelData(_, _) => ChannelType::OrderedReliable,
+            ModelMsgServer::ParentChanged(_, _) => ChannelType::OrderedReliable,
         }
     }
 
     fn plugin(app: &mut App) {
-        app.add_system(new_client_connected);
+        app.init_resource::<InterestConfig>();
+        app.init_resource::<KnownModels>();
+        app.add_system(update_interest);
     }
 }
 
-fn model_changed_msg(world: &mut World, client_id: ClientId, server_entity: ServerEntity, model_data: ModelData2) {
-    let mut system_state: SystemState<ResMut<Server>> = SystemState::new(world);
-    let mut server = system_state.get_mut(world);
-    let endpoint = server.endpoint_mut();
-    for client_id2 in endpoint.clients() {
-        if client_id2 == client_id {
-            continue;
+fn model_changed_msg(world: &mut World, client_id: ClientId, server_entity: ServerEntity, mut model_data: ModelData2) {
+    if !is_owner(world, client_id, server_entity) {
+        return;
+    }
+    if !accept_sequence(world, client_id, server_entity, model_data.sequence) {
+        return;
+    }
+    let room = match entity_room(world, server_entity) {
+        None => return,
+        Some(room) => room,
+    };
+    let mut system_state: SystemState<(NetServer, ResMut<SequenceCounter>, Interest)> = SystemState::new(world);
+    let (mut server, mut sequence_counter, interest) = system_state.get_mut(world);
+    model_data.sequence = sequence_counter.next();
+    for member in server.room_members(&room) {
+        if member != client_id && interest.knows(member, server_entity.0) {
+            server.send(member, ModelMsgClient::ModelChanged(server_entity, model_data.clone()));
+        }
+    }
+    if let Some(mut model) = world.get_mut::<ServerModel>(server_entity.0) {
+        let delta = model_data.delta;
+        delta.apply_transform(&mut model.0.transform);
+        if let Some(color128) = delta.color128() {
+            model.0.color128 = color128;
+        }
+        if let Some(render_layer) = delta.render_layer() {
+            model.0.render_layer = render_layer;
         }
-        endpoint
-            .send_lek_msg(
-                client_id2.clone(),
-                ModelMsgClient::ModelChanged(
-                    server_entity,
-                    model_data.clone(),
-                ),
-            )
-            .unwrap();
     }
 }
 
-fn model_added_msg(world: &mut World, client_id: ClientId, client_entity: ClientEntity, model_data: ModelData) {
-    let mut system_state: SystemState<(ResMut<Server>, Commands)> =
+/// Whether `client_id` may put a model of `room` under `parent`. It has to own the parent, which
+/// must be a model in the same room, and `child` can't end up among its own ancestors.
+fn valid_parent(world: &World, client_id: ClientId, room: &str, child: Option<ServerEntity>, parent: ServerEntity) -> bool {
+    if !is_owner(world, client_id, parent) || entity_room(world, parent).as_deref() != Some(room) {
+        return false;
+    }
+    let mut ancestor = Some(parent);
+    let mut depth = 0;
+    while let Some(entity) = ancestor {
+        if Some(entity) == child {
+            return false;
+        }
+        ancestor = world.get::<ServerModel>(entity.0).and_then(|model| model.0.parent);
+        depth += 1;
+        // Loops can't be made through here, but a limit keeps a broken save from hanging the server.
+        if depth > 1024 {
+            return false;
+        }
+    }
+    true
+}
+
+fn parent_changed_msg(world: &mut World, client_id: ClientId, server_entity: ServerEntity, parent: Option<ServerEntity>) {
+    if !is_owner(world, client_id, server_entity) {
+        return;
+    }
+    let room = match entity_room(world, server_entity) {
+        None => return,
+        Some(room) => room,
+    };
+    let mut system_state: SystemState<(NetServer, Interest)> = SystemState::new(world);
+    if let Some(new_parent) = parent {
+        if !valid_parent(world, client_id, &room, Some(server_entity), new_parent) {
+            // Puts it back where the server has it.
+            let current = world.get::<ServerModel>(server_entity.0).and_then(|model| model.0.parent);
+            let mut server = system_state.get_mut(world).0;
+            server.send(client_id, ModelMsgClient::ParentChanged(server_entity, current));
+            return;
+        }
+    }
+    let (mut server, interest) = system_state.get_mut(world);
+    for member in server.room_members(&room) {
+        if member != client_id && interest.knows(member, server_entity.0) {
+            server.send(member, ModelMsgClient::ParentChanged(server_entity, parent));
+        }
+    }
+    if let Some(mut model) = world.get_mut::<ServerModel>(server_entity.0) {
+        model.0.parent = parent;
+    }
+}
+
+fn model_added_msg(
+    world: &mut World,
+    client_id: ClientId,
+    client_entity: ClientEntity,
+    key: ModelKey,
+    model_data: ModelData,
+) {
+    let room = {
+        let mut system_state: SystemState<NetServer> = SystemState::new(world);
+        match system_state.get_mut(world).room_of(client_id) {
+            None => return,
+            Some(room) => room,
+        }
+    };
+    let mut query = world.query_filtered::<(Entity, &ModelKey), With<OnServer>>();
+    let existing = query
+        .iter(world)
+        .find(|(_, existing)| **existing == key)
+        .map(|(entity, _)| ServerEntity(entity));
+    let model_data = match model_data.parent {
+        Some(parent) if !valid_parent(world, client_id, &room, existing, parent) => model_data.with_parent(None),
+        _ => model_data,
+    };
+    if let Some(asset) = model_data.asset {
+        world.resource_mut::<ServerAssets>().announce(client_id, asset);
+    }
+    if let Some(server_entity) = existing {
+        rebind_model(world, client_id, client_entity, server_entity, room, model_data);
+        return;
+    }
+    let mut system_state: SystemState<(NetServer, Commands, Interest)> =
         SystemState::new(world);
-    let (mut server, mut commands) = system_state.get_mut(world);
-    let mut server: ResMut<Server> = server;
+    let (mut server, mut commands, mut interest) = system_state.get_mut(world);
     let mut commands: Commands = commands;
-    let server_entity = ServerEntity(commands.spawn_empty().id());
-    let endpoint = server.get_endpoint_mut().expect("no server endpoint");
-    endpoint
-        .send_lek_msg(
-            client_id,
-            ModelMsgClient::EntityMap(server_entity, client_entity),
-        )
-        .unwrap();
-    for client_id2 in endpoint.clients() {
-        if client_id2 == client_id {
+    let server_entity = ServerEntity(
+        commands
+            .spawn((
+                OnServer,
+                Owner(client_id),
+                key,
+                ServerModel(model_data.clone()),
+                InRoom(room.clone()),
+            ))
+            .id(),
+    );
+    server.send(
+        client_id,
+        ModelMsgClient::EntityMap(server_entity, client_entity),
+    );
+    interest.mark_known(client_id, server_entity.0);
+    for member in server.room_members(&room) {
+        if member == client_id {
             continue;
         }
-        endpoint
-            .send_lek_msg(
-                client_id2.clone(),
-                ModelMsgClient::ModelAdded(
-                    server_entity,
-                    model_data.clone(),
-                ),
-            )
-            .unwrap()
+        // A child is only of interest where its parent is.
+        let wanted = match model_data.parent {
+            Some(parent) => interest.knows(member, parent.0),
+            None => interest.wants(member, model_data.transform.translation),
+        };
+        if wanted {
+            interest.mark_known(member, server_entity.0);
+            server.send(member, ModelMsgClient::ModelAdded(server_entity, model_data.clone()));
+        }
     }
     system_state.apply(world);
 }
 
-fn new_client_connected(mut connected: EventReader<ConnectionEvent>, mut server: ResMut<Server>) {
-    let endpoint = server.endpoint_mut();
-    for client in connected.iter() {
-        let client_id: ClientId = client.id.clone();
-        for client_id2 in endpoint.clients() {
-            if client_id2 == client_id {
-                continue;
-            }
-            endpoint
-                .send_lek_msg(
-                    client_id2,
-                    ModelMsgClient::GetAllModelData(client_id.clone()),
-                )
-                .unwrap();
+/// The server still has a model its client announced again, after its session expired or the
+/// server restarted. It takes the client's current state and moves to the client's room instead of
+/// being spawned twice, and goes back to the client unless somebody else took it meanwhile.
+fn rebind_model(
+    world: &mut World,
+    client_id: ClientId,
+    client_entity: ClientEntity,
+    server_entity: ServerEntity,
+    room: String,
+    model_data: ModelData,
+) {
+    let owner = world.get::<Owner>(server_entity.0).copied();
+    let mut system_state: SystemState<(NetServer, ResMut<SequenceCounter>, Interest)> = SystemState::new(world);
+    let (mut server, mut sequence_counter, mut interest) = system_state.get_mut(world);
+    server.send(client_id, ModelMsgClient::EntityMap(server_entity, client_entity));
+    interest.mark_known(client_id, server_entity.0);
+    match owner {
+        Some(owner) if owner.0 != client_id => {
+            server.send(client_id, OwnershipMsgClient::OwnerChanged(server_entity, Some(owner)));
+        }
+        _ => {}
+    }
+    let changed = ModelData2 {
+        sequence: sequence_counter.next(),
+        delta: ModelDelta::new(
+            DirtyMask::ALL,
+            &model_data.transform,
+            model_data.color128,
+            model_data.render_layer,
+        ),
+        settled: true,
+    };
+    // Members that don't know it yet get it from the interest update, like any other model.
+    for member in server.room_members(&room) {
+        if member == client_id || !interest.knows(member, server_entity.0) {
+            continue;
+        }
+        server.send(member, ModelMsgClient::ModelChanged(server_entity, changed.clone()));
+        if owner.is_none() {
+            server.send(member, OwnershipMsgClient::OwnerChanged(server_entity, Some(Owner(client_id))));
         }
     }
+    let mut entity = world.entity_mut(server_entity.0);
+    if owner.is_none() {
+        entity.insert(Owner(client_id));
+    }
+    entity.insert(InRoom(room));
+    if let Some(mut model) = entity.get_mut::<ServerModel>() {
+        let parent = model_data.parent.or(model.0.parent);
+        model.0 = model_data.with_parent(parent);
+    }
 }
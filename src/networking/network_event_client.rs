@@ -0,0 +1,154 @@
+use crate::networking::handshake_client::MessageRegistry;
+use crate::networking::network_event_server::NetworkEventMsgServer;
+use crate::networking::transport::{receive_from_server, HostQueue, MessageHandlers, NetClient, NetServer};
+use bevy_app::App;
+use bevy_ecs::prelude::{EventReader, Res, World};
+use bevy_quinnet::shared::channel::ChannelType;
+use bevy_quinnet::shared::ClientId;
+use leknet::{ClientMessage, ServerMessage, TypeName};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::fmt::Debug;
+use std::ops::Deref;
+
+/// Anything that can be sent with [`NetworkEventAppExt::add_network_event`].
+pub trait NetworkEventData: Clone + Debug + Serialize + DeserializeOwned + Send + Sync + 'static {}
+
+impl<E: Clone + Debug + Serialize + DeserializeOwned + Send + Sync + 'static> NetworkEventData for E {}
+
+/// An event that came over the network.
+#[derive(Clone, Debug)]
+pub struct NetworkEvent<E> {
+    /// The client that sent it, `None` if the server did.
+    pub sender: Option<ClientId>,
+    pub event: E,
+}
+
+impl<E> Deref for NetworkEvent<E> {
+    type Target = E;
+
+    fn deref(&self) -> &E {
+        &self.event
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum EventTarget {
+    /// Everyone in the sender's room but the sender, the server included.
+    Everyone,
+    Server,
+    Client(ClientId),
+}
+
+/// Send this instead of a plain `E` to pick who gets it, a plain `E` goes to [`EventTarget::Everyone`].
+#[derive(Clone, Debug)]
+pub struct SendNetworkEvent<E> {
+    pub target: EventTarget,
+    pub event: E,
+}
+
+pub trait NetworkEventAppExt {
+    /// Every `E` sent with an `EventWriter<E>` is delivered to the other apps that added `E`, as
+    /// [`NetworkEvent<E>`]. Call this on the server and the clients, before or after the networking plugins.
+    fn add_network_event<E: NetworkEventData>(&mut self) -> &mut Self;
+}
+
+impl NetworkEventAppExt for App {
+    fn add_network_event<E: NetworkEventData>(&mut self) -> &mut Self {
+        self.add_event::<E>();
+        self.add_event::<SendNetworkEvent<E>>();
+        self.add_event::<NetworkEvent<E>>();
+        let mut registry = self.world.get_resource_or_insert_with(MessageRegistry::default);
+        registry.register::<NetworkEventMsgClient<E>>();
+        registry.register::<NetworkEventMsgServer<E>>();
+        self.world
+            .get_resource_or_insert_with(MessageHandlers::default)
+            .register::<NetworkEventMsgClient<E>, NetworkEventMsgServer<E>>();
+        // Both directions, whichever side this app turns out to be only sends from that side.
+        NetworkEventMsgClient::<E>::add_plugin_client(self);
+        NetworkEventMsgServer::<E>::add_plugin_server(self);
+        self.add_system(send_from_client::<E>);
+        self.add_system(send_from_server::<E>);
+        self
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum NetworkEventMsgClient<E> {
+    Event(Option<ClientId>, E),
+}
+
+impl<E: NetworkEventData> TypeName for NetworkEventMsgClient<E> {
+    fn get_type_name() -> String {
+        format!(
+            "stereokit_bevy::networking::NetworkEventMsgClient<{}>",
+            std::any::type_name::<E>()
+        )
+    }
+}
+
+impl<E: NetworkEventData> ClientMessage for NetworkEventMsgClient<E> {
+    fn client(self, world: &mut World) {
+        match self {
+            NetworkEventMsgClient::Event(sender, event) => {
+                world.send_event(NetworkEvent { sender, event });
+            }
+        }
+    }
+
+    fn _client(world: &mut World, msg_bytes: &[u8]) {
+        if let Some(msg) = receive_from_server::<Self>(world, msg_bytes) {
+            msg.client(world)
+        }
+    }
+
+    fn channel_type(&self) -> ChannelType {
+        ChannelType::OrderedReliable
+    }
+
+    fn plugin(_app: &mut App) {}
+}
+
+fn send_from_client<E: NetworkEventData>(
+    mut events: EventReader<E>,
+    mut targeted: EventReader<SendNetworkEvent<E>>,
+    mut client: NetClient,
+) {
+    // Servers have no client half, a host sends its own events as its client.
+    if !client.is_connected() {
+        return;
+    }
+    for event in events.iter() {
+        client.send(NetworkEventMsgServer::Event(EventTarget::Everyone, event.clone()));
+    }
+    for SendNetworkEvent { target, event } in targeted.iter() {
+        client.send(NetworkEventMsgServer::Event(*target, event.clone()));
+    }
+}
+
+fn send_from_server<E: NetworkEventData>(
+    mut events: EventReader<E>,
+    mut targeted: EventReader<SendNetworkEvent<E>>,
+    mut server: NetServer,
+    host: Option<Res<HostQueue>>,
+) {
+    // A host's go out from its client. Clients have no server half, nobody to send to here.
+    if host.is_some() {
+        return;
+    }
+    for event in events.iter() {
+        server.broadcast(None, NetworkEventMsgClient::Event(None, event.clone()));
+    }
+    for SendNetworkEvent { target, event } in targeted.iter() {
+        let msg = NetworkEventMsgClient::Event(None, event.clone());
+        match target {
+            EventTarget::Everyone => server.broadcast(None, msg),
+            EventTarget::Server => {}
+            EventTarget::Client(client_id) => {
+                if server.clients().contains(client_id) {
+                    server.send(*client_id, msg);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,73 @@
+use crate::networking::network_event_client::{EventTarget, NetworkEvent, NetworkEventData, NetworkEventMsgClient};
+use crate::networking::transport::{receive_from_client, HostQueue, NetServer};
+use bevy_app::App;
+use bevy_ecs::prelude::World;
+use bevy_ecs::system::SystemState;
+use bevy_quinnet::shared::channel::ChannelType;
+use bevy_quinnet::shared::ClientId;
+use leknet::{ServerMessage, TypeName};
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum NetworkEventMsgServer<E> {
+    Event(EventTarget, E),
+}
+
+impl<E: NetworkEventData> TypeName for NetworkEventMsgServer<E> {
+    fn get_type_name() -> String {
+        format!(
+            "stereokit_bevy::networking::NetworkEventMsgServer<{}>",
+            std::any::type_name::<E>()
+        )
+    }
+}
+
+impl<E: NetworkEventData> ServerMessage for NetworkEventMsgServer<E> {
+    fn server(self, world: &mut World, client_id: ClientId) {
+        match self {
+            NetworkEventMsgServer::Event(target, event) => event_msg(world, client_id, target, event),
+        }
+    }
+
+    fn _server(world: &mut World, msg_bytes: &[u8], client_id: ClientId) {
+        if let Some(msg) = receive_from_client::<Self>(world, msg_bytes, client_id) {
+            msg.server(world, client_id);
+        }
+    }
+
+    fn channel_type(&self) -> ChannelType {
+        ChannelType::OrderedReliable
+    }
+
+    fn plugin(_app: &mut App) {}
+}
+
+fn event_msg<E: NetworkEventData>(world: &mut World, client_id: ClientId, target: EventTarget, event: E) {
+    // A host's server and client share one world, relaying to the client delivers it there already.
+    let is_host = world.contains_resource::<HostQueue>();
+    let deliver_here = match target {
+        EventTarget::Everyone => !is_host,
+        EventTarget::Server => true,
+        EventTarget::Client(_) => false,
+    };
+    {
+        let mut system_state: SystemState<NetServer> = SystemState::new(world);
+        let mut server = system_state.get_mut(world);
+        let msg = NetworkEventMsgClient::Event(Some(client_id), event.clone());
+        match target {
+            EventTarget::Everyone => server.broadcast_from(client_id, msg),
+            EventTarget::Server => {}
+            EventTarget::Client(target) => {
+                if server.clients().contains(&target) {
+                    server.send(target, msg);
+                }
+            }
+        }
+    }
+    if deliver_here {
+        world.send_event(NetworkEvent {
+            sender: Some(client_id),
+            event,
+        });
+    }
+}
@@ -0,0 +1,117 @@
+use crate::networking::ownership_server::OwnershipMsgServer;
+use crate::networking::transport::{receive_from_server, NetClient};
+use crate::networking::compression::LastSent;
+use crate::networking::replication::ReplicationState;
+use crate::networking::{HasAuthority, Owner};
+use bevy_app::App;
+use bevy_ecs::prelude::{Entity, EventReader, Res, ResMut, World};
+use bevy_ecs::system::SystemState;
+use bevy_quinnet::shared::channel::ChannelType;
+use leknet::{ClientEntity, ClientMessage, EntityMap, ServerEntity, TypeName};
+use serde::{Deserialize, Serialize};
+
+/// Send this to ask the server for the right to change a networked entity.
+pub struct RequestOwnership(pub Entity);
+/// Send this to give up ownership of a networked entity so others can grab it.
+pub struct ReleaseOwnership(pub Entity);
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum OwnershipMsgClient {
+    OwnershipGranted(ServerEntity),
+    OwnershipDenied(ServerEntity),
+    OwnerChanged(ServerEntity, Option<Owner>),
+}
+
+impl TypeName for OwnershipMsgClient {
+    fn get_type_name() -> String {
+        "stereokit_bevy::networking::OwnershipMsgClient".to_string()
+    }
+}
+
+impl ClientMessage for OwnershipMsgClient {
+    fn client(self, world: &mut World) {
+        match self {
+            OwnershipMsgClient::OwnershipGranted(server_entity) => {
+                if let Some(client_entity) = client_entity(world, server_entity) {
+                    world
+                        .entity_mut(client_entity.0)
+                        .insert((HasAuthority, ReplicationState::dirty()));
+                }
+            }
+            OwnershipMsgClient::OwnershipDenied(server_entity) => {
+                if let Some(client_entity) = client_entity(world, server_entity) {
+                    world.entity_mut(client_entity.0).remove::<HasAuthority>();
+                }
+            }
+            OwnershipMsgClient::OwnerChanged(server_entity, owner) => {
+                if let Some(client_entity) = client_entity(world, server_entity) {
+                    let mut world_entity = world.entity_mut(client_entity.0);
+                    world_entity.remove::<HasAuthority>().remove::<LastSent>();
+                    match owner {
+                        None => {
+                            world_entity.remove::<Owner>();
+                        }
+                        Some(owner) => {
+                            world_entity.insert(owner);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    fn _client(world: &mut World, msg_bytes: &[u8]) {
+        if let Some(msg) = receive_from_server::<Self>(world, msg_bytes) {
+            msg.client(world)
+        }
+    }
+
+    fn channel_type(&self) -> ChannelType {
+        match self {
+            OwnershipMsgClient::OwnershipGranted(_) => ChannelType::OrderedReliable,
+            OwnershipMsgClient::OwnershipDenied(_) => ChannelType::OrderedReliable,
+            OwnershipMsgClient::OwnerChanged(_, _) => ChannelType::OrderedReliable,
+        }
+    }
+
+    fn plugin(app: &mut App) {
+        app.add_event::<RequestOwnership>();
+        app.add_event::<ReleaseOwnership>();
+        app.add_system(request_ownership);
+        app.add_system(release_ownership);
+    }
+}
+
+fn client_entity(world: &mut World, server_entity: ServerEntity) -> Option<ClientEntity> {
+    let mut system_state: SystemState<Res<EntityMap>> = SystemState::new(world);
+    let entity_map = system_state.get(world);
+    entity_map.get_by_right(&server_entity).copied()
+}
+
+fn request_ownership(
+    mut requests: EventReader<RequestOwnership>,
+    mut client: NetClient,
+    entity_map: Res<EntityMap>,
+) {
+    if client.is_connected() {
+        for RequestOwnership(entity) in requests.iter() {
+            if let Some(server_entity) = entity_map.get_by_left(&ClientEntity(*entity)) {
+                client.send(OwnershipMsgServer::RequestOwnership(*server_entity));
+            }
+        }
+    }
+}
+
+fn release_ownership(
+    mut releases: EventReader<ReleaseOwnership>,
+    mut client: NetClient,
+    entity_map: Res<EntityMap>,
+) {
+    if client.is_connected() {
+        for ReleaseOwnership(entity) in releases.iter() {
+            if let Some(server_entity) = entity_map.get_by_left(&ClientEntity(*entity)) {
+                client.send(OwnershipMsgServer::ReleaseOwnership(*server_entity));
+            }
+        }
+    }
+}
@@ -0,0 +1,177 @@
+use crate::networking::ownership_client::OwnershipMsgClient;
+use crate::networking::interpolation::Sequence;
+use crate::networking::model_server::ServerModel;
+use crate::networking::reconnect::ClientResumed;
+use crate::networking::room_server::entity_room;
+use crate::networking::transport::{receive_from_client, NetServer};
+use crate::networking::{InRoom, OnServer, Owner};
+use bevy_app::App;
+use bevy_ecs::prelude::{Commands, Entity, EventReader, Query, With, World};
+use bevy_ecs::system::SystemState;
+use bevy_quinnet::server::ConnectionLostEvent;
+use bevy_quinnet::shared::channel::ChannelType;
+use bevy_quinnet::shared::ClientId;
+use leknet::{ServerEntity, ServerMessage, TypeName};
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum OwnershipMsgServer {
+    RequestOwnership(ServerEntity),
+    ReleaseOwnership(ServerEntity),
+}
+
+impl TypeName for OwnershipMsgServer {
+    fn get_type_name() -> String {
+        "stereokit_bevy::networking::OwnershipMsgServer".to_string()
+    }
+}
+
+impl ServerMessage for OwnershipMsgServer {
+    fn server(self, world: &mut World, client_id: ClientId) {
+        match self {
+            OwnershipMsgServer::RequestOwnership(server_entity) => {
+                request_ownership_msg(world, client_id, server_entity)
+            }
+            OwnershipMsgServer::ReleaseOwnership(server_entity) => {
+                release_ownership_msg(world, client_id, server_entity)
+            }
+        }
+    }
+
+    fn _server(world: &mut World, msg_bytes: &[u8], client_id: ClientId) {
+        if let Some(msg) = receive_from_client::<Self>(world, msg_bytes, client_id) {
+            msg.server(world, client_id);
+        }
+    }
+
+    fn channel_type(&self) -> ChannelType {
+        match self {
+            OwnershipMsgServer::RequestOwnership(_) => ChannelType::OrderedReliable,
+            OwnershipMsgServer::ReleaseOwnership(_) => ChannelType::OrderedReliable,
+        }
+    }
+
+    fn plugin(app: &mut App) {
+        app.add_system(client_disconnected);
+        app.add_system(client_resumed);
+    }
+}
+
+/// Returns true if `client_id` is allowed to change `server_entity`.
+/// Entities without an owner can't be changed until somebody requests them.
+pub(crate) fn is_owner(world: &World, client_id: ClientId, server_entity: ServerEntity) -> bool {
+    world
+        .get_entity(server_entity.0)
+        .and_then(|entity| entity.get::<Owner>())
+        .map(|owner| owner.0 == client_id)
+        .unwrap_or(false)
+}
+
+fn request_ownership_msg(world: &mut World, client_id: ClientId, server_entity: ServerEntity) {
+    let mut system_state: SystemState<NetServer> = SystemState::new(world);
+    let room = system_state.get_mut(world).room_of(client_id);
+    // Only models can change hands, and only within the requester's room. Players never do.
+    let previous_owner = match world.get_entity(server_entity.0) {
+        Some(entity) if entity.contains::<ServerModel>() && entity.contains::<OnServer>() => {
+            entity.get::<Owner>().copied()
+        }
+        _ => return deny_ownership(world, client_id, server_entity),
+    };
+    if room.is_none() || entity_room(world, server_entity) != room {
+        return deny_ownership(world, client_id, server_entity);
+    }
+    match previous_owner {
+        Some(Owner(owner)) if owner != client_id => deny_ownership(world, client_id, server_entity),
+        _ => {
+            // The new owner counts its updates from its own sequence.
+            world
+                .entity_mut(server_entity.0)
+                .insert(Owner(client_id))
+                .remove::<Sequence>();
+            broadcast_owner(world, server_entity, Some(client_id));
+        }
+    }
+}
+
+fn deny_ownership(world: &mut World, client_id: ClientId, server_entity: ServerEntity) {
+    let mut system_state: SystemState<NetServer> = SystemState::new(world);
+    let mut server = system_state.get_mut(world);
+    server.send(client_id, OwnershipMsgClient::OwnershipDenied(server_entity));
+}
+
+fn release_ownership_msg(world: &mut World, client_id: ClientId, server_entity: ServerEntity) {
+    if !is_owner(world, client_id, server_entity) {
+        return;
+    }
+    world
+        .entity_mut(server_entity.0)
+        .remove::<Owner>()
+        .remove::<Sequence>();
+    broadcast_owner(world, server_entity, None);
+}
+
+fn broadcast_owner(world: &mut World, server_entity: ServerEntity, owner: Option<ClientId>) {
+    let room = match entity_room(world, server_entity) {
+        None => return,
+        Some(room) => room,
+    };
+    let mut system_state: SystemState<NetServer> = SystemState::new(world);
+    let mut server = system_state.get_mut(world);
+    for client_id in server.room_members(&room) {
+        let msg = if Some(client_id) == owner {
+            OwnershipMsgClient::OwnershipGranted(server_entity)
+        } else {
+            OwnershipMsgClient::OwnerChanged(server_entity, owner.map(Owner))
+        };
+        server.send(client_id, msg);
+    }
+}
+
+pub(crate) fn client_disconnected(
+    mut lost: EventReader<ConnectionLostEvent>,
+    mut server: NetServer,
+    mut commands: Commands,
+    owners: Query<(Entity, &Owner, &InRoom), With<OnServer>>,
+) {
+    for client in lost.iter() {
+        for (entity, owner, room) in owners.iter() {
+            if owner.0 != client.id {
+                continue;
+            }
+            // Nobody can move it anymore, so leave it up for grabs.
+            commands.entity(entity).remove::<Owner>().remove::<Sequence>();
+            server.broadcast_room(&room.0, None, OwnershipMsgClient::OwnerChanged(ServerEntity(entity), None));
+        }
+    }
+}
+
+/// Gives a resumed client back what it owned, unless somebody took it in the meantime.
+fn client_resumed(
+    mut resumed: EventReader<ClientResumed>,
+    mut server: NetServer,
+    mut commands: Commands,
+    models: Query<(Option<&Owner>, &InRoom), With<OnServer>>,
+) {
+    for resumed in resumed.iter() {
+        let client_id = resumed.client_id;
+        for entity in &resumed.owned {
+            let server_entity = ServerEntity(*entity);
+            match models.get(*entity) {
+                Err(_) => {}
+                Ok((Some(owner), _)) => {
+                    server.send(client_id, OwnershipMsgClient::OwnerChanged(server_entity, Some(*owner)));
+                }
+                Ok((None, room)) => {
+                    commands.entity(*entity).insert(Owner(client_id));
+                    // Marks it dirty there, so whatever changed while offline gets sent.
+                    server.send(client_id, OwnershipMsgClient::OwnershipGranted(server_entity));
+                    server.broadcast_room(
+                        &room.0,
+                        Some(client_id),
+                        OwnershipMsgClient::OwnerChanged(server_entity, Some(Owner(client_id))),
+                    );
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,138 @@
+use crate::networking::asset_server::ServerAssets;
+use crate::networking::model_server::ServerModel;
+use crate::networking::room_server::Rooms;
+use crate::networking::{AssetId, InRoom, ModelData, ModelKey, OnServer};
+use bevy::log::error;
+use bevy_app::AppExit;
+use bevy_ecs::prelude::{Commands, Entity, EventReader, Local, Query, Res, ResMut, Resource, With};
+use bevy_time::Time;
+use leknet::ServerEntity;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::time::Duration;
+
+/// Where and how often the server saves its models. Nothing is saved or loaded without a path.
+#[derive(Resource, Clone, Debug)]
+pub struct PersistenceConfig {
+    pub path: Option<PathBuf>,
+    pub interval: Duration,
+}
+
+impl Default for PersistenceConfig {
+    fn default() -> Self {
+        Self {
+            path: None,
+            interval: Duration::from_secs(30),
+        }
+    }
+}
+
+/// Send this on the server to save right away instead of waiting for the interval.
+pub struct SaveWorld;
+
+#[derive(Serialize, Deserialize)]
+struct SavedModel {
+    data: ModelData,
+    /// So its client can take it back after a restart.
+    key: Option<ModelKey>,
+    room: String,
+    /// Index of the parent in the saved models, entities are different every run.
+    parent: Option<usize>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct SavedWorld {
+    models: Vec<SavedModel>,
+    assets: Vec<(AssetId, Vec<u8>)>,
+}
+
+pub(crate) fn load_world(
+    config: Res<PersistenceConfig>,
+    mut assets: ResMut<ServerAssets>,
+    mut rooms: ResMut<Rooms>,
+    mut commands: Commands,
+) {
+    let path = match &config.path {
+        None => return,
+        Some(path) => path,
+    };
+    let bytes = match std::fs::read(path) {
+        Err(_) => return,
+        Ok(bytes) => bytes,
+    };
+    let saved: SavedWorld = match bincode::deserialize(&bytes) {
+        Err(error) => {
+            error!("couldn't load {}: {error}", path.display());
+            return;
+        }
+        Ok(saved) => saved,
+    };
+    for (asset, bytes) in saved.assets {
+        assets.insert(asset, bytes);
+    }
+    let entities: Vec<Entity> = saved.models.iter().map(|_| commands.spawn(OnServer).id()).collect();
+    for (entity, model) in entities.iter().zip(saved.models) {
+        let parent = model.parent.and_then(|parent| entities.get(parent)).map(|parent| ServerEntity(*parent));
+        rooms.create(&model.room);
+        let mut entity_commands = commands.entity(*entity);
+        entity_commands.insert((ServerModel(model.data.with_parent(parent)), InRoom(model.room)));
+        if let Some(key) = model.key {
+            entity_commands.insert(key);
+        }
+    }
+}
+
+pub(crate) fn save_world(
+    config: Res<PersistenceConfig>,
+    models: Query<(Entity, &ServerModel, Option<&ModelKey>, &InRoom), With<OnServer>>,
+    assets: Res<ServerAssets>,
+    time: Res<Time>,
+    mut requested: EventReader<SaveWorld>,
+    mut exit: EventReader<AppExit>,
+    mut last_saved: Local<Duration>,
+) {
+    let path = match &config.path {
+        None => return,
+        Some(path) => path,
+    };
+    let requested = requested.iter().count() > 0;
+    // Otherwise whatever changed since the last save is lost.
+    let exiting = exit.iter().count() > 0;
+    if !requested && !exiting && time.elapsed() - *last_saved < config.interval {
+        return;
+    }
+    *last_saved = time.elapsed();
+    let indices: HashMap<Entity, usize> = models
+        .iter()
+        .enumerate()
+        .map(|(i, (entity, _, _, _))| (entity, i))
+        .collect();
+    let mut saved = SavedWorld {
+        models: vec![],
+        assets: vec![],
+    };
+    for (_, model, key, room) in models.iter() {
+        let parent = model.0.parent.and_then(|parent| indices.get(&parent.0).copied());
+        saved.models.push(SavedModel {
+            data: model.0.clone().with_parent(None),
+            key: key.copied(),
+            room: room.0.clone(),
+            parent,
+        });
+        if let Some(asset) = model.0.asset {
+            if let Some(bytes) = assets.get(asset) {
+                saved.assets.push((asset, bytes.to_vec()));
+            }
+        }
+    }
+    saved.assets.sort_by_key(|(asset, _)| asset.0);
+    saved.assets.dedup_by_key(|(asset, _)| *asset);
+    // Written next to the file first so a crash while saving can't lose the last save.
+    let temporary = path.with_extension("tmp");
+    let result = std::fs::write(&temporary, bincode::serialize(&saved).unwrap())
+        .and_then(|_| std::fs::rename(&temporary, path));
+    if let Err(error) = result {
+        error!("couldn't save {}: {error}", path.display());
+    }
+}
@@ -1,27 +1,33 @@
 use bevy_app::App;
-use bevy_ecs::prelude::{Added, Changed, Commands, Entity, NonSend, Query, Res, ResMut, With, Without, World, Component};
+use bevy_ecs::prelude::{Changed, Commands, Entity, Local, NonSend, Query, Ref, Res, ResMut, With, Without, World, Component};
 use bevy_ecs::system::SystemState;
-use bevy_quinnet::client::Client;
 use bevy_quinnet::shared::channel::ChannelType;
 use bevy_quinnet::shared::channel::ChannelType::{OrderedReliable, Unreliable};
-use bevy_quinnet::shared::ClientId;
 use bevy_transform::prelude::Transform;
 use bevy_transform::{TransformBundle, TransformPlugin};
-use leknet::{ClientEntity, ClientMessage, EntityMap, LekClient, Networked, ServerEntity, TypeName};
-use crate::networking::{IgnorePlayerAdd, IgnorePlayerChanged, ModelData, ModelData2, Player};
+use leknet::{ClientEntity, ClientMessage, EntityMap, Networked, ServerEntity, TypeName};
+use crate::networking::{HasAuthority, IgnorePlayerAdd, IgnorePlayerChanged, ModelData, ModelData2, Player, PlayerId};
 use serde::{Serialize, Deserialize};
-use stereokit::{Sk, SkDraw, StereoKitMultiThread};
+use bevy_time::Time;
+use stereokit::{Handed, Sk, SkDraw, StereoKitMultiThread};
+use crate::networking::hands::{HandPose, HandsConfig, HandsSequence, PlayerHands};
+use crate::networking::interpolation::{push_snapshot, Sequence, SequenceCounter};
 use crate::networking::player_server::PlayerMsgServer;
+use crate::networking::transport::{receive_from_server, NetClient};
+use crate::networking::replication::{
+    accumulate_priority, due, ReplicationConfig, ReplicationRate, ReplicationState, SendBudget,
+};
+use bevy_ecs::schedule::IntoSystemConfig;
 
 #[derive(Clone, Debug, Serialize, Deserialize, Component)]
 pub struct LocalPlayer;
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub enum PlayerMsgClient {
-    PlayerAdded(ServerEntity, Transform),
-    PlayerChanged(ServerEntity, Transform),
+    PlayerAdded(ServerEntity, PlayerId, Transform),
+    PlayerChanged(ServerEntity, Sequence, Transform),
+    HandsChanged(ServerEntity, Sequence, PlayerHands),
     EntityMap(ServerEntity, ClientEntity),
-    GetAllPlayers(ClientId),
 }
 
 impl TypeName for PlayerMsgClient {
@@ -33,84 +39,131 @@ impl TypeName for PlayerMsgClient {
 impl ClientMessage for PlayerMsgClient {
     fn client(self, world: &mut World) {
         match self {
-            PlayerMsgClient::PlayerAdded(server_entity, player_position) => {
-                player_added_msg(world, server_entity, player_position);
+            PlayerMsgClient::PlayerAdded(server_entity, player_id, player_position) => {
+                player_added_msg(world, server_entity, player_id, player_position);
             }
-            PlayerMsgClient::PlayerChanged(server_entity, player_position) => {
-                player_changed_msg(world, server_entity, player_position);
+            PlayerMsgClient::PlayerChanged(server_entity, sequence, player_position) => {
+                player_changed_msg(world, server_entity, sequence, player_position);
+            }
+            PlayerMsgClient::HandsChanged(server_entity, sequence, hands) => {
+                hands_changed_msg(world, server_entity, sequence, hands);
             }
             PlayerMsgClient::EntityMap(server_entity, client_entity) => {
                 let mut system_state: SystemState<ResMut<EntityMap>> = SystemState::new(world);
                 let mut entity_map: ResMut<EntityMap> = system_state.get_mut(world);
                 entity_map.0.insert(client_entity, server_entity);
             }
-            PlayerMsgClient::GetAllPlayers(client_id) => {
-                get_all_players_msg(world, client_id);
-            }
         }
     }
 
     fn _client(world: &mut World, msg_bytes: &[u8]) {
-        bincode::deserialize::<Self>(msg_bytes).unwrap().client(world)
+        if let Some(msg) = receive_from_server::<Self>(world, msg_bytes) {
+            msg.client(world)
+        }
     }
 
     fn channel_type(&self) -> ChannelType {
         match self {
-            PlayerMsgClient::PlayerAdded(_, _) => OrderedReliable,
-            PlayerMsgClient::PlayerChanged(_, _) => Unreliable,
+            PlayerMsgClient::PlayerAdded(_, _, _) => OrderedReliable,
+            PlayerMsgClient::PlayerChanged(_, _, _) => Unreliable,
+            PlayerMsgClient::HandsChanged(_, _, _) => Unreliable,
             PlayerMsgClient::EntityMap(_, _) => OrderedReliable,
-            PlayerMsgClient::GetAllPlayers(_) => OrderedReliable,
         }
     }
 
     fn plugin(app: &mut App) {
         app.add_system(player_added);
-        app.add_system(player_changed);
+        app.add_system(player_dirty.before(accumulate_priority));
+        app.add_system(player_changed.after(accumulate_priority));
         app.add_startup_system(spawn_player);
         app.add_system(sync_player);
+        app.init_resource::<HandsConfig>();
+        app.add_system(capture_hands.after(sync_player));
     }
 }
 
-fn spawn_player(sk: NonSend<SkDraw>, mut commands: Commands) {
-    let transform = Transform::from_translation(sk.input_head().position).with_rotation(sk.input_head().orientation);
-    commands.spawn((Player, Networked, LocalPlayer)).insert(TransformBundle::from(transform));
+fn spawn_player(sk: Option<NonSend<SkDraw>>, mut commands: Commands) {
+    let transform = sk
+        .map(|sk| Transform::from_translation(sk.input_head().position).with_rotation(sk.input_head().orientation))
+        .unwrap_or_default();
+    commands
+        .spawn((Player, Networked, LocalPlayer, HasAuthority, ReplicationState::dirty()))
+        .insert(TransformBundle::from(transform));
 }
-fn sync_player(mut query: Query<(&LocalPlayer, &Player, &Networked, &mut Transform)>, sk: Res<Sk>) {
+fn sync_player(mut query: Query<(&LocalPlayer, &Player, &Networked, &mut Transform)>, sk: Option<Res<Sk>>) {
+    let sk = match sk {
+        None => return,
+        Some(sk) => sk,
+    };
     for (_, _, _, mut transform) in query.iter_mut() {
         transform.translation = sk.input_head().position;
         transform.rotation = sk.input_head().orientation;
     }
 }
 
-fn get_all_players_msg(world: &mut World, client_id: ClientId) {
-    let mut system_state: SystemState<(
-        Query<
-            (Entity, &Player, &Transform),
-            (With<Networked>),
-        >,
-        ResMut<Client>,
-        Res<EntityMap>,
-    )> = SystemState::new(world);
-    let (query, mut client, entity_map) = system_state.get_mut(world);
-    let mut client: ResMut<Client> = client;
-    let entity_map: Res<EntityMap> = entity_map;
-    let mut players = vec![];
-    for (entity, _, transform) in query.iter() {
-        let server_entity = match entity_map.get_by_left(&ClientEntity(entity)) {
-            None => continue,
-            Some(server_entity) => server_entity.clone(),
+fn capture_hands(
+    mut query: Query<(Entity, &Transform, Option<&mut PlayerHands>), With<LocalPlayer>>,
+    sk: Option<Res<Sk>>,
+    config: Res<HandsConfig>,
+    time: Res<Time>,
+    mut last_sent: Local<f64>,
+    mut client: NetClient,
+    entity_map: Res<EntityMap>,
+    mut sequence_counter: ResMut<SequenceCounter>,
+    mut commands: Commands,
+) {
+    let sk = match sk {
+        None => return,
+        Some(sk) => sk,
+    };
+    for (entity, transform, hands) in query.iter_mut() {
+        let capture = |handed| {
+            HandPose::capture(
+                transform,
+                &sk.input_hand(handed),
+                sk.input_hand_source(handed),
+                &sk.input_controller(handed),
+            )
+        };
+        let captured = PlayerHands {
+            left: capture(Handed::Left),
+            right: capture(Handed::Right),
         };
-        players.push((
-            server_entity,
-            *transform,
-        ))
+        match hands {
+            None => {
+                commands.entity(entity).insert(captured.clone());
+            }
+            Some(mut hands) => *hands = captured.clone(),
+        }
+        let now = time.elapsed_seconds_f64();
+        if !client.is_connected() || now - *last_sent < 1.0 / config.rate as f64 {
+            continue;
+        }
+        if let Some(server_entity) = entity_map.get_by_left(&ClientEntity(entity)) {
+            client.send(PlayerMsgServer::HandsChanged(*server_entity, sequence_counter.next(), captured));
+            *last_sent = now;
+        }
+    }
+}
+
+fn hands_changed_msg(world: &mut World, server_entity: ServerEntity, sequence: Sequence, hands: PlayerHands) {
+    let client_entity = match world.resource::<EntityMap>().get_by_right(&server_entity) {
+        None => return,
+        Some(client_entity) => client_entity.0,
+    };
+    let mut entity = match world.get_entity_mut(client_entity) {
+        None => return,
+        Some(entity) => entity,
+    };
+    if let Some(last) = entity.get::<HandsSequence>() {
+        if !sequence.is_newer_than(last.0) {
+            return;
+        }
     }
-    client
-        .connection_mut()
-        .send_lek_msg(PlayerMsgServer::AllPlayerData(client_id, players))
-        .unwrap();
+    entity.insert((hands, HandsSequence(sequence)));
 }
-fn player_changed_msg(world: &mut World, server_entity: ServerEntity, transform: Transform) {
+
+fn player_changed_msg(world: &mut World, server_entity: ServerEntity, sequence: Sequence, transform: Transform) {
     let mut client_entity = None;
     {
         let mut system_state: SystemState<ResMut<EntityMap>> = SystemState::new(world);
@@ -118,12 +171,14 @@ fn player_changed_msg(world: &mut World, server_entity: ServerEntity, transform:
         client_entity = entity_map.get_by_right(&server_entity).map(|a| a.clone());
     }
     if let Some(client_entity) = client_entity {
-        let mut world_entity = world.entity_mut(client_entity.0);
-        *world_entity.get_mut().unwrap() = transform;
-        //world_entity.insert(IgnorePlayerChanged);
+        push_snapshot(world, client_entity.0, sequence, transform);
     }
 }
-fn player_added_msg(world: &mut World, server_entity: ServerEntity, transform: Transform) {
+fn player_added_msg(world: &mut World, server_entity: ServerEntity, player_id: PlayerId, transform: Transform) {
+    // Told again when it comes back into this client's room, or after a resumed session.
+    if world.resource::<EntityMap>().0.contains_right(&server_entity) {
+        return;
+    }
     let mut system_state: SystemState<(ResMut<EntityMap>, Commands)> =
         SystemState::new(world);
     let (entity_map, commands) = system_state.get_mut(world);
@@ -131,7 +186,7 @@ fn player_added_msg(world: &mut World, server_entity: ServerEntity, transform: T
     let mut commands: Commands = commands;
     let client_entity = ClientEntity(
         commands
-            .spawn((Player, Networked))
+            .spawn((Player, player_id, Networked))
             .insert(TransformBundle::from(transform))
             .insert(IgnorePlayerAdd)
             .id(),
@@ -140,47 +195,67 @@ fn player_added_msg(world: &mut World, server_entity: ServerEntity, transform: T
     system_state.apply(world);
 }
 
+/// Like models, a player spawned before the server accepted this client is announced once it does.
 fn player_added(
-    query: Query<
-        (Entity, &Transform, &Player),
-        (Added<Networked>, Without<IgnorePlayerAdd>),
-    >,
-    mut client: ResMut<Client>,
+    query: Query<(Entity, Ref<Networked>, &Transform), (With<Player>, Without<IgnorePlayerAdd>)>,
+    mut client: NetClient,
+    mut was_connected: Local<bool>,
 ) {
-    if let Some(connection) = client.get_connection_mut() {
-        for (entity, transform, _) in query.iter() {
-            connection
-                .send_lek_msg(PlayerMsgServer::PlayerAdded(
-                    ClientEntity(entity),
-                    *transform,
-                ))
-                .unwrap()
+    let just_connected = client.is_connected() && !*was_connected;
+    *was_connected = client.is_connected();
+    if client.is_connected() {
+        for (entity, networked, transform) in query.iter() {
+            if !just_connected && !networked.is_added() {
+                continue;
+            }
+            client.send(PlayerMsgServer::PlayerAdded(
+                ClientEntity(entity),
+                *transform,
+            ));
         }
     }
 }
 
-fn player_changed(
-    query: Query<
-        (Entity, &Transform, &Player),
+fn player_dirty(
+    mut query: Query<
+        &mut ReplicationState,
         (
             Changed<Transform>,
+            With<Player>,
             Without<IgnorePlayerChanged>,
+            With<HasAuthority>,
             With<Networked>,
         ),
     >,
-    mut client: ResMut<Client>,
+) {
+    for mut state in query.iter_mut() {
+        state.mark_dirty();
+    }
+}
+
+pub(crate) fn player_changed(
+    mut query: Query<
+        (Entity, &Transform, &mut ReplicationState, Option<&ReplicationRate>),
+        (With<Player>, With<HasAuthority>, With<Networked>),
+    >,
+    mut client: NetClient,
     entity_map: Res<EntityMap>,
+    mut sequence_counter: ResMut<SequenceCounter>,
+    config: Res<ReplicationConfig>,
+    mut budget: ResMut<SendBudget>,
 ) {
-    if let Some(connection) = client.get_connection_mut() {
-        for (entity, transform, _) in query.iter() {
+    if client.is_connected() {
+        let due = due(&config, query.iter().map(|(entity, _, state, rate)| (entity, state, rate)));
+        for entity in due {
+            let (_, transform, mut state, _) = query.get_mut(entity).unwrap();
             if let Some(server_entity) = entity_map.get_by_left(&ClientEntity(entity)) {
-                connection
-                    .send_lek_msg(PlayerMsgServer::PlayerChanged(
-                        *server_entity,
-                        *transform,
-                    ))
-                    .unwrap()
+                let msg = PlayerMsgServer::PlayerChanged(*server_entity, sequence_counter.next(), *transform);
+                if !budget.try_spend(bincode::serialized_size(&msg).unwrap()) {
+                    break;
+                }
+                client.send(msg);
+                state.sent();
             }
         }
     }
-}
\ No newline at end of file
+}
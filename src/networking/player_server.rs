@@ -1,20 +1,29 @@
 use bevy_app::App;
-use bevy_ecs::prelude::{Commands, EventReader, ResMut, World};
+use bevy_ecs::prelude::{Commands, Component, Entity, EventReader, Query, ResMut, With, World};
 use bevy_ecs::system::SystemState;
-use bevy_quinnet::server::{ConnectionEvent, Server};
 use bevy_quinnet::shared::channel::ChannelType;
 use bevy_quinnet::shared::channel::ChannelType::{OrderedReliable, Unreliable};
 use bevy_quinnet::shared::ClientId;
 use bevy_transform::components::Transform;
-use leknet::{ClientEntity, LekServer, ServerEntity, ServerMessage, TypeName};
+use leknet::{ClientEntity, ServerEntity, ServerMessage, TypeName};
 use serde::{Serialize, Deserialize};
+use crate::networking::hands::{HandsSequence, PlayerHands};
+use crate::networking::interpolation::{accept_sequence, accept_sequence_of, Sequence, SequenceCounter};
+use crate::networking::ownership_server::is_owner;
 use crate::networking::player_client::PlayerMsgClient;
+use crate::networking::room_server::{entity_room, ClientJoinedRoom};
+use crate::networking::transport::{receive_from_client, NetServer};
+use crate::networking::{InRoom, OnServer, Owner, PlayerId};
+
+/// Where the server last heard a player's head was.
+#[derive(Component, Clone, Debug)]
+pub(crate) struct ServerPlayer(pub Transform);
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub enum PlayerMsgServer {
     PlayerAdded(ClientEntity, Transform),
-    PlayerChanged(ServerEntity, Transform),
-    AllPlayerData(ClientId, Vec<(ServerEntity, Transform)>),
+    PlayerChanged(ServerEntity, Sequence, Transform),
+    HandsChanged(ServerEntity, Sequence, PlayerHands),
 }
 impl TypeName for PlayerMsgServer {
     fn get_type_name() -> String {
@@ -27,34 +36,26 @@ impl ServerMessage for PlayerMsgServer {
             PlayerMsgServer::PlayerAdded(client_entity, player_data) => {
                 player_added_msg(world, client_id, client_entity, player_data)
             }
-            PlayerMsgServer::PlayerChanged(server_entity, player_data) => {
-                player_changed_msg(world, client_id, server_entity, player_data)
+            PlayerMsgServer::PlayerChanged(server_entity, sequence, player_data) => {
+                player_changed_msg(world, client_id, server_entity, sequence, player_data)
             }
-            PlayerMsgServer::AllPlayerData(client_id, all_player_data) => {
-                let mut endpoint: SystemState<ResMut<Server>> = SystemState::new(world);
-                let mut endpoint = endpoint.get_mut(world);
-                let endpoint = endpoint.endpoint_mut();
-                for (entity, player_data) in all_player_data {
-                    endpoint
-                        .send_lek_msg(
-                            client_id.clone(),
-                            PlayerMsgClient::PlayerAdded(entity, player_data),
-                        )
-                        .unwrap();
-                }
+            PlayerMsgServer::HandsChanged(server_entity, sequence, hands) => {
+                hands_changed_msg(world, client_id, server_entity, sequence, hands)
             }
         }
     }
 
     fn _server(world: &mut World, msg_bytes: &[u8], client_id: ClientId) {
-        bincode::deserialize::<Self>(msg_bytes).unwrap().server(world, client_id);
+        if let Some(msg) = receive_from_client::<Self>(world, msg_bytes, client_id) {
+            msg.server(world, client_id);
+        }
     }
 
     fn channel_type(&self) -> ChannelType {
         match self {
             PlayerMsgServer::PlayerAdded(_, _) => OrderedReliable,
-            PlayerMsgServer::PlayerChanged(_, _) => Unreliable,
-            PlayerMsgServer::AllPlayerData(_, _) => OrderedReliable,
+            PlayerMsgServer::PlayerChanged(_, _, _) => Unreliable,
+            PlayerMsgServer::HandsChanged(_, _, _) => Unreliable,
         }
     }
 
@@ -63,71 +64,104 @@ impl ServerMessage for PlayerMsgServer {
     }
 }
 
-fn player_changed_msg(world: &mut World, client_id: ClientId, server_entity: ServerEntity, player_data: Transform) {
-    let mut system_state: SystemState<ResMut<Server>> = SystemState::new(world);
-    let mut server = system_state.get_mut(world);
-    let endpoint = server.endpoint_mut();
-    for client_id2 in endpoint.clients() {
-        if client_id2 == client_id {
-            continue;
-        }
-        endpoint
-            .send_lek_msg(
-                client_id2.clone(),
-                PlayerMsgClient::PlayerChanged(
-                    server_entity,
-                    player_data.clone(),
-                ),
-            )
-            .unwrap();
+fn player_changed_msg(world: &mut World, client_id: ClientId, server_entity: ServerEntity, sequence: Sequence, player_data: Transform) {
+    if !is_owner(world, client_id, server_entity) {
+        return;
+    }
+    if !accept_sequence(world, client_id, server_entity, sequence) {
+        return;
+    }
+    let room = match entity_room(world, server_entity) {
+        None => return,
+        Some(room) => room,
+    };
+    let mut system_state: SystemState<(NetServer, ResMut<SequenceCounter>)> = SystemState::new(world);
+    let (mut server, mut sequence_counter) = system_state.get_mut(world);
+    let sequence = sequence_counter.next();
+    server.broadcast_room(
+        &room,
+        Some(client_id),
+        PlayerMsgClient::PlayerChanged(server_entity, sequence, player_data),
+    );
+    if let Some(mut player) = world.get_mut::<ServerPlayer>(server_entity.0) {
+        player.0 = player_data;
+    }
+}
+
+fn hands_changed_msg(world: &mut World, client_id: ClientId, server_entity: ServerEntity, sequence: Sequence, hands: PlayerHands) {
+    if !is_owner(world, client_id, server_entity) || !hands.is_valid() {
+        return;
     }
+    if !accept_sequence_of::<HandsSequence>(world, client_id, server_entity, sequence) {
+        return;
+    }
+    let room = match entity_room(world, server_entity) {
+        None => return,
+        Some(room) => room,
+    };
+    let mut system_state: SystemState<(NetServer, ResMut<SequenceCounter>)> = SystemState::new(world);
+    let (mut server, mut sequence_counter) = system_state.get_mut(world);
+    let sequence = sequence_counter.next();
+    server.broadcast_room(
+        &room,
+        Some(client_id),
+        PlayerMsgClient::HandsChanged(server_entity, sequence, hands),
+    );
 }
 
 fn player_added_msg(world: &mut World, client_id: ClientId, client_entity: ClientEntity, player_data: Transform) {
-    let mut system_state: SystemState<(ResMut<Server>, Commands)> =
+    let mut system_state: SystemState<(NetServer, Commands)> =
         SystemState::new(world);
     let (mut server, mut commands) = system_state.get_mut(world);
-    let mut server: ResMut<Server> = server;
     let mut commands: Commands = commands;
-    let server_entity = ServerEntity(commands.spawn_empty().id());
-    let endpoint = server.get_endpoint_mut().expect("no server endpoint");
-    endpoint
-        .send_lek_msg(
-            client_id,
-            PlayerMsgClient::EntityMap(server_entity, client_entity),
-        )
-        .unwrap();
-    for client_id2 in endpoint.clients() {
-        if client_id2 == client_id {
-            continue;
-        }
-        endpoint
-            .send_lek_msg(
-                client_id2.clone(),
-                PlayerMsgClient::PlayerAdded(
-                    server_entity,
-                    player_data.clone(),
-                ),
-            )
-            .unwrap()
-    }
+    let room = match server.room_of(client_id) {
+        None => return,
+        Some(room) => room,
+    };
+    let server_entity = ServerEntity(
+        commands
+            .spawn((
+                OnServer,
+                Owner(client_id),
+                PlayerId(client_id),
+                ServerPlayer(player_data),
+                InRoom(room.clone()),
+            ))
+            .id(),
+    );
+    server.send(
+        client_id,
+        PlayerMsgClient::EntityMap(server_entity, client_entity),
+    );
+    server.broadcast_room(
+        &room,
+        Some(client_id),
+        PlayerMsgClient::PlayerAdded(server_entity, PlayerId(client_id), player_data),
+    );
     system_state.apply(world);
 }
 
-fn new_client_connected(mut connected: EventReader<ConnectionEvent>, mut server: ResMut<Server>) {
-    let endpoint = server.endpoint_mut();
-    for client in connected.iter() {
-        let client_id: ClientId = client.id.clone();
-        for client_id2 in endpoint.clients() {
-            if client_id2 == client_id {
+/// A client entering a room is told about the players already there, and brings its own along.
+fn new_client_connected(
+    mut joined: EventReader<ClientJoinedRoom>,
+    mut players: Query<(Entity, &PlayerId, &ServerPlayer, &mut InRoom), With<OnServer>>,
+    mut server: NetServer,
+) {
+    for joined in joined.iter() {
+        let client_id: ClientId = joined.client_id;
+        for (entity, player_id, player, mut room) in players.iter_mut() {
+            if player_id.0 != client_id {
+                if room.0 == joined.room {
+                    server.send(client_id, PlayerMsgClient::PlayerAdded(ServerEntity(entity), *player_id, player.0));
+                }
                 continue;
             }
-            endpoint
-                .send_lek_msg(
-                    client_id2,
-                    PlayerMsgClient::GetAllPlayers(client_id.clone()),
-                )
-                .unwrap();
+            room.0 = joined.room.clone();
+            server.broadcast_room(
+                &joined.room,
+                Some(client_id),
+                PlayerMsgClient::PlayerAdded(ServerEntity(entity), *player_id, player.0),
+            );
         }
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,250 @@
+use crate::networking::config::{connect_in_memory, open_connection, EndpointFailed, NetworkConfig};
+use crate::networking::handshake_client::{HandshakeState, SessionToken};
+use crate::networking::memory::MemoryClient;
+use crate::networking::room_client::CurrentRoom;
+use crate::networking::room_server::Rooms;
+use crate::networking::{HasAuthority, IgnoreModelAdd, IgnorePlayerAdd, OnServer, Owner};
+use bevy_ecs::prelude::{Entity, EventReader, EventWriter, Local, Or, Query, Res, ResMut, Resource, With, World};
+use bevy_hierarchy::DespawnRecursiveExt;
+use bevy_quinnet::client::{Client, ConnectionEvent, ConnectionLostEvent};
+use bevy_quinnet::server::ConnectionLostEvent as ServerConnectionLostEvent;
+use bevy_quinnet::shared::ClientId;
+use bevy_time::Time;
+use leknet::{ClientEntity, EntityMap, Networked};
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::hash::{BuildHasher, Hash, Hasher};
+use std::time::{Duration, Instant};
+
+/// How a client tries to get a lost connection back. The wait doubles after every failed attempt.
+#[derive(Resource, Clone, Debug)]
+pub struct ReconnectConfig {
+    pub enabled: bool,
+    pub initial_delay: Duration,
+    pub max_delay: Duration,
+}
+
+impl Default for ReconnectConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            initial_delay: Duration::from_millis(500),
+            max_delay: Duration::from_secs(10),
+        }
+    }
+}
+
+/// How long the server keeps the session of a client that lost its connection, for it to resume.
+#[derive(Resource, Clone, Debug)]
+pub struct SessionConfig {
+    pub resume_window: Duration,
+}
+
+impl Default for SessionConfig {
+    fn default() -> Self {
+        Self {
+            resume_window: Duration::from_secs(60),
+        }
+    }
+}
+
+/// Sent on a client once the server accepted it again after a lost connection. When `resumed`, the
+/// server still had its session and this client's models kept their server entities.
+pub struct Reconnected {
+    pub resumed: bool,
+}
+
+struct Backoff {
+    delay: Duration,
+    next_attempt: Option<Duration>,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Self {
+            delay: Duration::ZERO,
+            next_attempt: None,
+        }
+    }
+}
+
+pub(crate) fn reconnect(
+    mut lost: EventReader<ConnectionLostEvent>,
+    config: Res<ReconnectConfig>,
+    network: Res<NetworkConfig>,
+    state: Res<HandshakeState>,
+    mut client: Option<ResMut<Client>>,
+    mut memory: Option<ResMut<MemoryClient>>,
+    mut connected: EventWriter<ConnectionEvent>,
+    mut failed: EventWriter<EndpointFailed>,
+    time: Res<Time>,
+    mut backoff: Local<Backoff>,
+) {
+    // A server that turned this build away will do it again.
+    if matches!(*state, HandshakeState::Rejected(_)) || !config.enabled {
+        lost.clear();
+        backoff.next_attempt = None;
+        return;
+    }
+    if *state == HandshakeState::Accepted {
+        backoff.delay = config.initial_delay;
+    }
+    for lost in lost.iter() {
+        if let Some(client) = &mut client {
+            client.close_connection(lost.id).ok();
+        }
+        if backoff.next_attempt.is_none() {
+            backoff.delay = backoff.delay.max(config.initial_delay);
+            backoff.next_attempt = Some(time.elapsed() + backoff.delay);
+        }
+    }
+    match backoff.next_attempt {
+        Some(at) if at <= time.elapsed() => {}
+        _ => return,
+    }
+    // Should this attempt fail too, its lost connection schedules the next one.
+    backoff.next_attempt = None;
+    backoff.delay = (backoff.delay * 2).min(config.max_delay);
+    if let Some(memory) = &mut memory {
+        connect_in_memory(memory, &mut connected);
+    } else if let Some(client) = &mut client {
+        if let Err(error) = open_connection(&network, client) {
+            // No connection to lose, so nothing else schedules the next attempt.
+            backoff.next_attempt = Some(time.elapsed() + backoff.delay);
+            failed.send(error);
+        }
+    }
+}
+
+/// Called when the server accepted this client again. Copies of everybody else's entities are
+/// dropped, the server sends them again as they are now. A resumed session keeps this client's
+/// own models, otherwise they're forgotten by the server and get announced again like new ones.
+pub(crate) fn resync(world: &mut World, resumed: bool) {
+    let mut query = world.query_filtered::<
+        (Entity, Option<&HasAuthority>),
+        (With<Networked>, Or<(With<IgnoreModelAdd>, With<IgnorePlayerAdd>)>),
+    >();
+    let remote: Vec<Entity> = query
+        .iter(world)
+        .filter(|(_, authority)| !resumed || authority.is_none())
+        .map(|(entity, _)| entity)
+        .collect();
+    {
+        let mut entity_map = world.resource_mut::<EntityMap>();
+        if resumed {
+            for entity in &remote {
+                entity_map.0.remove_by_left(&ClientEntity(*entity));
+            }
+        } else {
+            entity_map.0.clear();
+        }
+    }
+    for entity in remote {
+        if let Some(entity) = world.get_entity_mut(entity) {
+            entity.despawn_recursive();
+        }
+    }
+    // The server puts this client in a room again, that's not a move from the old one.
+    world.resource_mut::<CurrentRoom>().0 = None;
+    world.send_event(Reconnected { resumed });
+}
+
+struct Session {
+    client_id: ClientId,
+    /// The [`Time::elapsed`] its connection was lost at.
+    left_at: Option<Duration>,
+    room: Option<String>,
+    owned: Vec<Entity>,
+}
+
+/// Sent on the server when a client took its session back after a lost connection.
+pub(crate) struct ClientResumed {
+    pub client_id: ClientId,
+    pub previous: ClientId,
+    pub room: Option<String>,
+    /// What the client owned when it left, whoever owns it now.
+    pub owned: Vec<Entity>,
+}
+
+/// Every client's session, kept for a while after its connection is lost.
+#[derive(Resource, Default)]
+pub(crate) struct Sessions {
+    sessions: HashMap<SessionToken, Session>,
+    hasher: RandomState,
+}
+
+impl Sessions {
+    pub(crate) fn start(&mut self, client_id: ClientId) -> SessionToken {
+        // Hard to guess, since it's all a client needs to take over another one's models.
+        let mut hasher = self.hasher.build_hasher();
+        client_id.hash(&mut hasher);
+        Instant::now().hash(&mut hasher);
+        let token = SessionToken(hasher.finish());
+        self.sessions.insert(
+            token,
+            Session {
+                client_id,
+                left_at: None,
+                room: None,
+                owned: vec![],
+            },
+        );
+        token
+    }
+
+    /// Hands the session of `token` to `client_id`, if its previous client is gone.
+    pub(crate) fn resume(&mut self, token: SessionToken, client_id: ClientId) -> Option<ClientResumed> {
+        let session = self.sessions.get_mut(&token)?;
+        session.left_at?;
+        let resumed = ClientResumed {
+            client_id,
+            previous: session.client_id,
+            room: session.room.take(),
+            owned: std::mem::take(&mut session.owned),
+        };
+        session.client_id = client_id;
+        session.left_at = None;
+        Some(resumed)
+    }
+
+    pub(crate) fn leave(&mut self, client_id: ClientId, room: Option<String>, owned: Vec<Entity>, now: Duration) {
+        if let Some(session) = self
+            .sessions
+            .values_mut()
+            .find(|session| session.client_id == client_id && session.left_at.is_none())
+        {
+            session.left_at = Some(now);
+            session.room = room;
+            session.owned = owned;
+        }
+    }
+
+    pub(crate) fn expire(&mut self, resume_window: Duration, now: Duration) {
+        self.sessions.retain(|_, session| match session.left_at {
+            None => true,
+            Some(left_at) => now.saturating_sub(left_at) < resume_window,
+        });
+    }
+}
+
+/// Remembers where a client was and what it owned, before the rest of the server forgets.
+pub(crate) fn leave_sessions(
+    mut lost: EventReader<ServerConnectionLostEvent>,
+    mut sessions: ResMut<Sessions>,
+    rooms: Res<Rooms>,
+    owners: Query<(Entity, &Owner), With<OnServer>>,
+    time: Res<Time>,
+) {
+    for client in lost.iter() {
+        let owned = owners
+            .iter()
+            .filter(|(_, owner)| owner.0 == client.id)
+            .map(|(entity, _)| entity)
+            .collect();
+        sessions.leave(client.id, rooms.room_of(client.id).map(str::to_string), owned, time.elapsed());
+    }
+}
+
+pub(crate) fn expire_sessions(config: Res<SessionConfig>, mut sessions: ResMut<Sessions>, time: Res<Time>) {
+    sessions.expire(config.resume_window, time.elapsed());
+}
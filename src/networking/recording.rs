@@ -0,0 +1,319 @@
+use crate::networking::asset_server::ServerAssets;
+use crate::networking::handshake_client::{MessageRegistry, PROTOCOL_VERSION};
+use crate::networking::handshake_server::AcceptedClients;
+use crate::networking::interest::KnownModels;
+use crate::networking::interpolation::SequenceCounter;
+use crate::networking::persistence::load_world;
+use crate::networking::room_client::CurrentRoom;
+use crate::networking::room_server::Rooms;
+use crate::networking::roster_client::Roster;
+use crate::networking::roster_server::ServerRoster;
+use crate::networking::transport::MessageHandlers;
+use crate::networking::{IgnoreModelAdd, IgnorePlayerAdd};
+use bevy::log::{error, warn};
+use bevy_ecs::prelude::{Entity, IntoSystem, Mut, Or, Res, ResMut, Resource, With, World};
+use bevy_ecs::system::{Commands, System};
+use bevy_hierarchy::DespawnRecursiveExt;
+use bevy_quinnet::shared::ClientId;
+use bevy_time::Time;
+use leknet::{EntityMap, Networked, TypeName};
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+/// Where the server records the messages it exchanges with its clients. Nothing is recorded without a path.
+#[derive(Resource, Clone, Debug, Default)]
+pub struct RecordingConfig {
+    pub path: Option<PathBuf>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Direction {
+    ToServer,
+    ToClient,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct RecordedMessage {
+    /// Seconds since the recording started.
+    pub time: f64,
+    pub direction: Direction,
+    /// The client that sent or received the message.
+    pub client_id: ClientId,
+    pub type_name: String,
+    pub bytes: Vec<u8>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct RecordingHeader {
+    protocol_version: u32,
+    messages: MessageRegistry,
+}
+
+/// A recorded session, read back from a file.
+#[derive(Clone, Debug)]
+pub struct Recording {
+    pub protocol_version: u32,
+    /// The message types of the build that made the recording.
+    pub messages: MessageRegistry,
+    pub recorded: Vec<RecordedMessage>,
+}
+
+impl Recording {
+    /// A recording cut short, by a crash say, loads up to its last whole message.
+    pub fn load(path: impl AsRef<Path>) -> bincode::Result<Self> {
+        let mut reader = BufReader::new(File::open(path)?);
+        let header: RecordingHeader = bincode::deserialize_from(&mut reader)?;
+        let mut recorded = vec![];
+        loop {
+            match bincode::deserialize_from(&mut reader) {
+                Ok(message) => recorded.push(message),
+                Err(error) => {
+                    if matches!(&*error, bincode::ErrorKind::Io(io) if io.kind() == std::io::ErrorKind::UnexpectedEof) {
+                        break;
+                    }
+                    return Err(error);
+                }
+            }
+        }
+        Ok(Self {
+            protocol_version: header.protocol_version,
+            messages: header.messages,
+            recorded,
+        })
+    }
+
+    /// How long the recording runs, in seconds.
+    pub fn duration(&self) -> f64 {
+        self.recorded.last().map(|message| message.time).unwrap_or(0.0)
+    }
+}
+
+/// Writes messages to the file of [`RecordingConfig`] as they go by.
+#[derive(Resource)]
+pub(crate) struct Recorder {
+    start: Instant,
+    writer: Option<BufWriter<File>>,
+}
+
+impl Recorder {
+    pub(crate) fn record<M: TypeName>(&mut self, direction: Direction, client_id: ClientId, bytes: Vec<u8>) {
+        let writer = match &mut self.writer {
+            None => return,
+            Some(writer) => writer,
+        };
+        let message = RecordedMessage {
+            time: self.start.elapsed().as_secs_f64(),
+            direction,
+            client_id,
+            type_name: M::get_type_name(),
+            bytes,
+        };
+        // One failed write would leave the rest of the file unreadable, so recording stops there.
+        if let Err(error) = bincode::serialize_into(writer, &message) {
+            error!("stopped recording: {error}");
+            self.writer = None;
+        }
+    }
+}
+
+pub(crate) fn record_message<M: TypeName>(world: &mut World, direction: Direction, client_id: ClientId, bytes: &[u8]) {
+    if let Some(mut recorder) = world.get_resource_mut::<Recorder>() {
+        recorder.record::<M>(direction, client_id, bytes.to_vec());
+    }
+}
+
+pub(crate) fn start_recording(config: Res<RecordingConfig>, registry: Res<MessageRegistry>, mut commands: Commands) {
+    let path = match &config.path {
+        None => return,
+        Some(path) => path,
+    };
+    let mut writer = match File::create(path) {
+        Err(error) => {
+            error!("couldn't record to {}: {error}", path.display());
+            return;
+        }
+        Ok(file) => BufWriter::new(file),
+    };
+    let header = RecordingHeader {
+        protocol_version: PROTOCOL_VERSION,
+        messages: registry.clone(),
+    };
+    if let Err(error) = bincode::serialize_into(&mut writer, &header) {
+        error!("couldn't record to {}: {error}", path.display());
+        return;
+    }
+    commands.insert_resource(Recorder {
+        start: Instant::now(),
+        writer: Some(writer),
+    });
+}
+
+/// Servers are usually stopped by killing them, so nothing waits in the buffer for long.
+pub(crate) fn flush_recording(recorder: Option<ResMut<Recorder>>) {
+    if let Some(mut recorder) = recorder {
+        if let Some(writer) = &mut recorder.writer {
+            writer.flush().ok();
+        }
+    }
+}
+
+/// Which side of the recorded session a replay plays.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ReplayTarget {
+    /// Everything the clients sent, as if they were connected.
+    Server,
+    /// Everything the server sent to this client.
+    Client(ClientId),
+}
+
+/// Insert this into a server or client app to feed it a recording, at the pace it was recorded.
+/// Whatever the app sends in return goes nowhere. Messages name server entities by id, so a server
+/// being replayed into should start out like the recorded one did, from the same saved world say.
+#[derive(Resource)]
+pub struct Replay {
+    recording: Recording,
+    target: ReplayTarget,
+    position: usize,
+    time: f64,
+    checked: bool,
+    rewound: bool,
+    pub speed: f64,
+    pub paused: bool,
+}
+
+impl Replay {
+    pub fn new(recording: Recording, target: ReplayTarget) -> Self {
+        Self {
+            recording,
+            target,
+            position: 0,
+            time: 0.0,
+            checked: false,
+            rewound: false,
+            speed: 1.0,
+            paused: false,
+        }
+    }
+
+    pub fn recording(&self) -> &Recording {
+        &self.recording
+    }
+
+    /// How far into the recording the replay is, in seconds.
+    pub fn time(&self) -> f64 {
+        self.time
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.position >= self.recording.recorded.len()
+    }
+
+    /// Moves to `time`, everything before it is fed in on the next update. Going back rebuilds the
+    /// app from the start of the recording: a server drops all its entities and loads its saved
+    /// world again, a client drops everything it got from the server.
+    pub fn seek(&mut self, time: f64) {
+        if time < self.time {
+            self.position = 0;
+            self.rewound = true;
+        }
+        self.time = time;
+    }
+
+    /// Moves to the next message for the target, it's fed in on the next update even when paused.
+    pub fn step(&mut self) {
+        if let Some(message) = self.recording.recorded[self.position..]
+            .iter()
+            .find(|message| self.plays(message))
+        {
+            self.time = self.time.max(message.time);
+        }
+    }
+
+    fn plays(&self, message: &RecordedMessage) -> bool {
+        match self.target {
+            ReplayTarget::Server => message.direction == Direction::ToServer,
+            ReplayTarget::Client(client_id) => {
+                message.direction == Direction::ToClient && message.client_id == client_id
+            }
+        }
+    }
+}
+
+pub(crate) fn replay(world: &mut World) {
+    if !world.contains_resource::<Replay>() {
+        return;
+    }
+    let delta = world.resource::<Time>().delta_seconds_f64();
+    world.resource_scope(|world, mut replay: Mut<Replay>| {
+        if !replay.checked {
+            replay.checked = true;
+            if let Some(reason) = world.resource::<MessageRegistry>().incompatibility(&replay.recording.messages) {
+                warn!("the recording is from a different build, {reason}");
+            }
+        }
+        if replay.rewound {
+            replay.rewound = false;
+            rewind(world, replay.target);
+        }
+        if !replay.paused {
+            replay.time += delta * replay.speed;
+        }
+        while let Some(message) = replay.recording.recorded.get(replay.position).cloned() {
+            if message.time > replay.time {
+                break;
+            }
+            replay.position += 1;
+            if !replay.plays(&message) {
+                continue;
+            }
+            let handlers = world.resource::<MessageHandlers>();
+            match message.direction {
+                Direction::ToServer => {
+                    if let Some(handler) = handlers.server.get(&message.type_name).copied() {
+                        handler(world, &message.bytes, message.client_id);
+                    }
+                }
+                Direction::ToClient => {
+                    if let Some(handler) = handlers.client.get(&message.type_name).copied() {
+                        handler(world, &message.bytes);
+                    }
+                }
+            }
+        }
+    });
+}
+
+/// Puts the app back the way it was before the replay fed it anything.
+fn rewind(world: &mut World, target: ReplayTarget) {
+    match target {
+        ReplayTarget::Server => {
+            // Also starts entity ids over, so models get the ids the recording names again.
+            world.clear_entities();
+            world.insert_resource(AcceptedClients::default());
+            world.insert_resource(ServerRoster::default());
+            world.insert_resource(Rooms::default());
+            world.insert_resource(KnownModels::default());
+            world.insert_resource(ServerAssets::default());
+            world.insert_resource(SequenceCounter::default());
+            let mut load_world = IntoSystem::into_system(load_world);
+            load_world.initialize(world);
+            load_world.run((), world);
+            load_world.apply_buffers(world);
+        }
+        ReplayTarget::Client(_) => {
+            let mut query = world.query_filtered::<Entity, (With<Networked>, Or<(With<IgnoreModelAdd>, With<IgnorePlayerAdd>)>)>();
+            let remote: Vec<Entity> = query.iter(world).collect();
+            for entity in remote {
+                if let Some(entity) = world.get_entity_mut(entity) {
+                    entity.despawn_recursive();
+                }
+            }
+            world.resource_mut::<EntityMap>().0.clear();
+            world.resource_mut::<Roster>().0.clear();
+            world.resource_mut::<CurrentRoom>().0 = None;
+        }
+    }
+}
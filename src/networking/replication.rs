@@ -0,0 +1,126 @@
+use crate::networking::player_client::LocalPlayer;
+use crate::networking::HasAuthority;
+use bevy_ecs::prelude::{Component, Entity, Query, Res, ResMut, Resource, With};
+use bevy_time::Time;
+use bevy_transform::prelude::{GlobalTransform, Transform};
+
+/// How often and how much this client sends updates for the entities it owns.
+#[derive(Resource, Clone, Debug)]
+pub struct ReplicationConfig {
+    /// Updates per second for entities without a [`ReplicationRate`].
+    pub rate: f32,
+    /// Bytes per second this client may send for entity changes, at most a second of it is saved up.
+    pub bytes_per_second: f32,
+    /// How fast priority drops with distance from the local player's head, per meter.
+    pub distance_falloff: f32,
+}
+
+impl Default for ReplicationConfig {
+    fn default() -> Self {
+        Self {
+            rate: 30.0,
+            bytes_per_second: 64.0 * 1024.0,
+            distance_falloff: 0.5,
+        }
+    }
+}
+
+/// Overrides [`ReplicationConfig::rate`] for one entity.
+#[derive(Component, Clone, Copy, Debug)]
+pub struct ReplicationRate(pub f32);
+
+/// Scales how fast an entity gains priority, defaults to 1.
+#[derive(Component, Clone, Copy, Debug)]
+pub struct ReplicationPriority(pub f32);
+
+/// Send bookkeeping for an entity this client owns.
+#[derive(Component, Clone, Debug, Default)]
+pub struct ReplicationState {
+    dirty: bool,
+    priority: f32,
+    since_sent: f32,
+}
+
+impl ReplicationState {
+    /// A state that sends on the next chance, for entities that were just created or taken over.
+    pub fn dirty() -> Self {
+        Self {
+            dirty: true,
+            since_sent: f32::MAX,
+            ..Default::default()
+        }
+    }
+
+    pub fn mark_dirty(&mut self) {
+        self.dirty = true;
+    }
+
+    fn ready(&self, rate: f32) -> bool {
+        self.dirty && self.since_sent >= 1.0 / rate.max(f32::EPSILON)
+    }
+
+    pub fn sent(&mut self) {
+        self.dirty = false;
+        self.priority = 0.0;
+        self.since_sent = 0.0;
+    }
+}
+
+/// Bytes this client can still send this frame.
+#[derive(Resource, Default, Debug)]
+pub struct SendBudget {
+    bytes: f32,
+}
+
+impl SendBudget {
+    /// Takes `bytes` out of the budget, returns false if there isn't enough left.
+    pub fn try_spend(&mut self, bytes: u64) -> bool {
+        if self.bytes < bytes as f32 {
+            return false;
+        }
+        self.bytes -= bytes as f32;
+        true
+    }
+}
+
+pub(crate) fn refill_budget(time: Res<Time>, config: Res<ReplicationConfig>, mut budget: ResMut<SendBudget>) {
+    budget.bytes = (budget.bytes + config.bytes_per_second * time.delta_seconds()).min(config.bytes_per_second);
+}
+
+/// Entities that waited longer, are closer to the local head or weigh more get sent first.
+pub(crate) fn accumulate_priority(
+    time: Res<Time>,
+    config: Res<ReplicationConfig>,
+    head: Query<&Transform, With<LocalPlayer>>,
+    mut query: Query<
+        (&mut ReplicationState, &GlobalTransform, Option<&ReplicationPriority>),
+        With<HasAuthority>,
+    >,
+) {
+    let delta = time.delta_seconds();
+    let head = head.iter().next().map(|head| head.translation);
+    for (mut state, transform, weight) in query.iter_mut() {
+        state.since_sent += delta;
+        if !state.dirty {
+            continue;
+        }
+        let distance = head
+            .map(|head| head.distance(transform.translation()))
+            .unwrap_or(0.0);
+        let weight = weight.map(|weight| weight.0).unwrap_or(1.0);
+        state.priority += delta * weight / (1.0 + distance * config.distance_falloff);
+    }
+}
+
+/// The entities that are due for an update, highest priority first.
+pub(crate) fn due<'a>(
+    config: &ReplicationConfig,
+    candidates: impl Iterator<Item = (Entity, &'a ReplicationState, Option<&'a ReplicationRate>)>,
+) -> Vec<Entity> {
+    let mut due: Vec<(Entity, f32)> = candidates
+        .filter(|(_, state, rate)| state.ready(rate.map(|rate| rate.0).unwrap_or(config.rate)))
+        .map(|(entity, state, _)| (entity, state.priority))
+        .collect();
+    due.sort_by(|(_, a), (_, b)| b.total_cmp(a));
+    due.into_iter().map(|(entity, _)| entity).collect()
+}
@@ -0,0 +1,131 @@
+use crate::networking::player_client::LocalPlayer;
+use crate::networking::roster_client::Roster;
+use crate::networking::room_server::RoomMsgServer;
+use crate::networking::transport::{receive_from_server, NetClient};
+use crate::networking::HasAuthority;
+use bevy_app::App;
+use bevy_ecs::prelude::{Entity, EventReader, Resource, With, Without, World};
+use bevy_hierarchy::{BuildWorldChildren, DespawnRecursiveExt, Parent};
+use bevy_quinnet::shared::channel::ChannelType;
+use leknet::{ClientEntity, ClientMessage, EntityMap, Networked, TypeName};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RoomInfo {
+    pub name: String,
+    pub members: usize,
+}
+
+/// The room this client is in, there once the server put it in one.
+#[derive(Resource, Clone, Debug, Default, PartialEq, Eq)]
+pub struct CurrentRoom(pub Option<String>);
+
+/// The rooms on the server as of the last [`ListRooms`] or [`CreateRoom`].
+#[derive(Resource, Clone, Debug, Default)]
+pub struct RoomList(pub Vec<RoomInfo>);
+
+/// Send these to ask the server for its rooms, make a new one or move to another.
+pub struct ListRooms;
+pub struct CreateRoom(pub String);
+pub struct JoinRoom(pub String);
+
+/// Sent when this client entered a room, everything from the previous room is gone by then.
+pub struct RoomJoined(pub String);
+/// Sent when the server couldn't do what was asked.
+pub struct RoomError(pub String);
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum RoomMsgClient {
+    RoomList(Vec<RoomInfo>),
+    JoinedRoom(String),
+    RoomError(String),
+}
+
+impl TypeName for RoomMsgClient {
+    fn get_type_name() -> String {
+        "stereokit_bevy::networking::RoomMsgClient".to_string()
+    }
+}
+
+impl ClientMessage for RoomMsgClient {
+    fn client(self, world: &mut World) {
+        match self {
+            RoomMsgClient::RoomList(rooms) => world.insert_resource(RoomList(rooms)),
+            RoomMsgClient::JoinedRoom(room) => joined_room_msg(world, room),
+            RoomMsgClient::RoomError(error) => world.send_event(RoomError(error)),
+        }
+    }
+
+    fn _client(world: &mut World, msg_bytes: &[u8]) {
+        if let Some(msg) = receive_from_server::<Self>(world, msg_bytes) {
+            msg.client(world)
+        }
+    }
+
+    fn channel_type(&self) -> ChannelType {
+        match self {
+            RoomMsgClient::RoomList(_) => ChannelType::OrderedReliable,
+            RoomMsgClient::JoinedRoom(_) => ChannelType::OrderedReliable,
+            RoomMsgClient::RoomError(_) => ChannelType::OrderedReliable,
+        }
+    }
+
+    fn plugin(app: &mut App) {
+        app.init_resource::<CurrentRoom>();
+        app.init_resource::<RoomList>();
+        app.add_event::<ListRooms>();
+        app.add_event::<CreateRoom>();
+        app.add_event::<JoinRoom>();
+        app.add_event::<RoomJoined>();
+        app.add_event::<RoomError>();
+        app.add_system(send_room_requests);
+    }
+}
+
+/// Leaving a room drops everything replicated from it. The local player and the models this client
+/// owns come along, the server moves those too.
+fn joined_room_msg(world: &mut World, room: String) {
+    let previous = world.resource::<CurrentRoom>().0.clone();
+    if previous.is_some() {
+        let mut query = world.query_filtered::<(Entity, Option<&HasAuthority>), (With<Networked>, Without<LocalPlayer>)>();
+        let (owned, entities): (Vec<_>, Vec<_>) = query.iter(world).partition(|(_, authority)| authority.is_some());
+        let owned: HashSet<Entity> = owned.into_iter().map(|(entity, _)| entity).collect();
+        // Or they'd go down with a parent that stays behind.
+        for entity in &owned {
+            let parent = world.get::<Parent>(*entity).map(Parent::get);
+            if matches!(parent, Some(parent) if !owned.contains(&parent)) {
+                world.entity_mut(*entity).remove_parent();
+            }
+        }
+        let mut entity_map = world.resource_mut::<EntityMap>();
+        for (entity, _) in &entities {
+            entity_map.0.remove_by_left(&ClientEntity(*entity));
+        }
+        for (entity, _) in entities {
+            if let Some(entity) = world.get_entity_mut(entity) {
+                entity.despawn_recursive();
+            }
+        }
+        world.resource_mut::<Roster>().0.clear();
+    }
+    world.resource_mut::<CurrentRoom>().0 = Some(room.clone());
+    world.send_event(RoomJoined(room));
+}
+
+fn send_room_requests(
+    mut list: EventReader<ListRooms>,
+    mut create: EventReader<CreateRoom>,
+    mut join: EventReader<JoinRoom>,
+    mut client: NetClient,
+) {
+    for _ in list.iter() {
+        client.send(RoomMsgServer::ListRooms);
+    }
+    for CreateRoom(room) in create.iter() {
+        client.send(RoomMsgServer::CreateRoom(room.clone()));
+    }
+    for JoinRoom(room) in join.iter() {
+        client.send(RoomMsgServer::JoinRoom(room.clone()));
+    }
+}
@@ -0,0 +1,333 @@
+use crate::networking::handshake_server::ClientJoined;
+use crate::networking::interest::Interest;
+use crate::networking::model_client::ModelMsgClient;
+use crate::networking::model_server::ServerModel;
+use crate::networking::reconnect::ClientResumed;
+use crate::networking::room_client::{RoomInfo, RoomMsgClient};
+use crate::networking::transport::{receive_from_client, HostQueue, NetServer, HOST_CLIENT_ID};
+use crate::networking::{InRoom, OnServer, Owner};
+use bevy_app::App;
+use bevy_ecs::prelude::{Entity, EventReader, EventWriter, Query, Res, ResMut, Resource, With, World};
+use bevy_ecs::schedule::IntoSystemConfig;
+use bevy_ecs::system::SystemState;
+use bevy_quinnet::server::ConnectionLostEvent;
+use bevy_quinnet::shared::channel::ChannelType;
+use bevy_quinnet::shared::ClientId;
+use leknet::{ServerEntity, ServerMessage, TypeName};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+/// The room every client is put in when it joins. It always exists.
+pub const DEFAULT_ROOM: &str = "lobby";
+/// Longest room name clients can create, in characters.
+pub const MAX_ROOM_NAME_LEN: usize = 32;
+/// Clients can't create rooms beyond this many.
+pub const MAX_ROOMS: usize = 64;
+/// Rooms one client can have created at a time.
+pub const MAX_ROOMS_PER_CLIENT: usize = 4;
+
+/// Who is in which room. Replication and broadcasts stay within a room.
+#[derive(Resource, Debug)]
+pub struct Rooms {
+    rooms: BTreeMap<String, HashSet<ClientId>>,
+    clients: HashMap<ClientId, String>,
+    /// Who created each room, until it's removed.
+    creators: HashMap<String, ClientId>,
+    /// Rooms the last member just left, removed unless models are left in them.
+    emptied: Vec<String>,
+}
+
+impl Default for Rooms {
+    fn default() -> Self {
+        Self {
+            rooms: BTreeMap::from([(DEFAULT_ROOM.to_string(), HashSet::new())]),
+            clients: HashMap::new(),
+            creators: HashMap::new(),
+            emptied: vec![],
+        }
+    }
+}
+
+impl Rooms {
+    pub fn room_of(&self, client_id: ClientId) -> Option<&str> {
+        self.clients.get(&client_id).map(String::as_str)
+    }
+
+    pub fn contains(&self, room: &str, client_id: ClientId) -> bool {
+        self.rooms
+            .get(room)
+            .map(|members| members.contains(&client_id))
+            .unwrap_or(false)
+    }
+
+    pub fn list(&self) -> Vec<RoomInfo> {
+        self.rooms
+            .iter()
+            .map(|(name, members)| RoomInfo {
+                name: name.clone(),
+                members: members.len(),
+            })
+            .collect()
+    }
+
+    pub(crate) fn create(&mut self, room: &str) -> bool {
+        if self.rooms.contains_key(room) {
+            return false;
+        }
+        self.rooms.insert(room.to_string(), HashSet::new());
+        true
+    }
+
+    /// Moves the client into `room`, returning the room it left.
+    fn join(&mut self, client_id: ClientId, room: &str) -> Option<String> {
+        let previous = self.leave(client_id);
+        self.rooms.entry(room.to_string()).or_default().insert(client_id);
+        self.clients.insert(client_id, room.to_string());
+        previous
+    }
+
+    fn leave(&mut self, client_id: ClientId) -> Option<String> {
+        let room = self.clients.remove(&client_id)?;
+        if let Some(members) = self.rooms.get_mut(&room) {
+            members.remove(&client_id);
+            if members.is_empty() && room != DEFAULT_ROOM {
+                self.emptied.push(room.clone());
+            }
+        }
+        Some(room)
+    }
+}
+
+/// Why a client can't create a room called `room`, if it can't.
+fn room_name_error(rooms: &Rooms, client_id: ClientId, room: &str) -> Option<String> {
+    if room.trim().is_empty() || room.trim() != room || room.chars().any(char::is_control) {
+        Some(format!("{room:?} isn't a room name"))
+    } else if room.chars().count() > MAX_ROOM_NAME_LEN {
+        Some(format!("room names are at most {MAX_ROOM_NAME_LEN} characters"))
+    } else if rooms.rooms.contains_key(room) {
+        Some(format!("room {room} already exists"))
+    } else if rooms.creators.values().filter(|creator| **creator == client_id).count() >= MAX_ROOMS_PER_CLIENT {
+        Some(format!("you can have at most {MAX_ROOMS_PER_CLIENT} rooms"))
+    } else if rooms.rooms.len() >= MAX_ROOMS {
+        Some("there are too many rooms".to_string())
+    } else {
+        None
+    }
+}
+
+/// Sent on the server when a client enters a room, `previous` is the room it came from.
+pub struct ClientJoinedRoom {
+    pub client_id: ClientId,
+    pub room: String,
+    pub previous: Option<String>,
+}
+
+/// The room a server entity belongs to, if it still exists.
+pub(crate) fn entity_room(world: &World, server_entity: ServerEntity) -> Option<String> {
+    world
+        .get_entity(server_entity.0)
+        .and_then(|entity| entity.get::<InRoom>())
+        .map(|room| room.0.clone())
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum RoomMsgServer {
+    ListRooms,
+    CreateRoom(String),
+    JoinRoom(String),
+}
+
+impl TypeName for RoomMsgServer {
+    fn get_type_name() -> String {
+        "stereokit_bevy::networking::RoomMsgServer".to_string()
+    }
+}
+
+impl ServerMessage for RoomMsgServer {
+    fn server(self, world: &mut World, client_id: ClientId) {
+        match self {
+            RoomMsgServer::ListRooms => {
+                let mut system_state: SystemState<(NetServer, Res<Rooms>)> = SystemState::new(world);
+                let (mut server, rooms) = system_state.get_mut(world);
+                server.send(client_id, RoomMsgClient::RoomList(rooms.list()));
+            }
+            RoomMsgServer::CreateRoom(room) => {
+                let mut system_state: SystemState<(NetServer, ResMut<Rooms>)> = SystemState::new(world);
+                let (mut server, mut rooms) = system_state.get_mut(world);
+                if let Some(error) = room_name_error(&rooms, client_id, &room) {
+                    server.send(client_id, RoomMsgClient::RoomError(error));
+                    return;
+                }
+                rooms.create(&room);
+                rooms.creators.insert(room, client_id);
+                server.send(client_id, RoomMsgClient::RoomList(rooms.list()));
+            }
+            RoomMsgServer::JoinRoom(room) => join_room_msg(world, client_id, room),
+        }
+    }
+
+    fn _server(world: &mut World, msg_bytes: &[u8], client_id: ClientId) {
+        if let Some(msg) = receive_from_client::<Self>(world, msg_bytes, client_id) {
+            msg.server(world, client_id);
+        }
+    }
+
+    fn channel_type(&self) -> ChannelType {
+        match self {
+            RoomMsgServer::ListRooms => ChannelType::OrderedReliable,
+            RoomMsgServer::CreateRoom(_) => ChannelType::OrderedReliable,
+            RoomMsgServer::JoinRoom(_) => ChannelType::OrderedReliable,
+        }
+    }
+
+    fn plugin(app: &mut App) {
+        app.init_resource::<Rooms>();
+        app.add_event::<ClientJoinedRoom>();
+        app.add_startup_system(join_host);
+        app.add_system(join_default_room);
+        app.add_system(client_left.after(join_default_room));
+        app.add_system(remove_empty_rooms.after(client_left));
+    }
+}
+
+fn join_room_msg(world: &mut World, client_id: ClientId, room: String) {
+    let mut system_state: SystemState<(NetServer, ResMut<Rooms>)> = SystemState::new(world);
+    let (mut server, mut rooms) = system_state.get_mut(world);
+    if !rooms.rooms.contains_key(&room) {
+        server.send(client_id, RoomMsgClient::RoomError(format!("there's no room {room}")));
+        return;
+    }
+    if rooms.room_of(client_id) == Some(room.as_str()) {
+        return;
+    }
+    let previous = rooms.join(client_id, &room);
+    server.send(client_id, RoomMsgClient::JoinedRoom(room.clone()));
+    move_owned_models(world, client_id, &room, previous.as_deref());
+    world.send_event(ClientJoinedRoom {
+        client_id,
+        room,
+        previous,
+    });
+}
+
+/// Whatever a client owns comes along to its new room, rather than staying behind where it can't
+/// be moved. Parents and children on either side of the move are let go of.
+fn move_owned_models(world: &mut World, client_id: ClientId, room: &str, previous: Option<&str>) {
+    let mut query = world.query_filtered::<(Entity, &Owner), (With<ServerModel>, With<OnServer>)>();
+    let owned: HashSet<Entity> = query
+        .iter(world)
+        .filter(|(_, owner)| owner.0 == client_id)
+        .map(|(entity, _)| entity)
+        .collect();
+    let mut query = world.query_filtered::<(Entity, &ServerModel), With<OnServer>>();
+    let left_behind: Vec<Entity> = query
+        .iter(world)
+        .filter(|(entity, model)| {
+            !owned.contains(entity) && matches!(model.0.parent, Some(parent) if owned.contains(&parent.0))
+        })
+        .map(|(entity, _)| entity)
+        .collect();
+    for entity in &owned {
+        let mut entity = world.entity_mut(*entity);
+        entity.insert(InRoom(room.to_string()));
+        let mut model = entity.get_mut::<ServerModel>().unwrap();
+        if matches!(model.0.parent, Some(parent) if !owned.contains(&parent.0)) {
+            model.0.parent = None;
+        }
+    }
+    for entity in &left_behind {
+        world.get_mut::<ServerModel>(*entity).unwrap().0.parent = None;
+    }
+    let previous = match previous {
+        Some(previous) if !left_behind.is_empty() => previous,
+        _ => return,
+    };
+    // Before the moved parents are removed there, which would take the children along.
+    let mut system_state: SystemState<(NetServer, Interest)> = SystemState::new(world);
+    let (mut server, interest) = system_state.get_mut(world);
+    for member in server.room_members(previous) {
+        for entity in &left_behind {
+            if interest.knows(member, *entity) {
+                server.send(member, ModelMsgClient::ParentChanged(ServerEntity(*entity), None));
+            }
+        }
+    }
+}
+
+/// New clients start in the default room, resumed ones go back to the room they were in.
+fn join_default_room(
+    mut joined: EventReader<ClientJoined>,
+    mut resumed: EventReader<ClientResumed>,
+    mut rooms: ResMut<Rooms>,
+    mut joined_room: EventWriter<ClientJoinedRoom>,
+    mut server: NetServer,
+) {
+    let resumed: HashMap<ClientId, String> = resumed
+        .iter()
+        .filter_map(|resumed| Some((resumed.client_id, resumed.room.clone()?)))
+        .collect();
+    for joined in joined.iter() {
+        let room = resumed
+            .get(&joined.0)
+            .filter(|room| rooms.rooms.contains_key(*room))
+            .cloned()
+            .unwrap_or_else(|| DEFAULT_ROOM.to_string());
+        rooms.join(joined.0, &room);
+        server.send(joined.0, RoomMsgClient::JoinedRoom(room.clone()));
+        joined_room.send(ClientJoinedRoom {
+            client_id: joined.0,
+            room,
+            previous: None,
+        });
+    }
+}
+
+/// A host's own client never goes through the handshake, so it's put in the default room here.
+fn join_host(
+    host: Option<Res<HostQueue>>,
+    mut rooms: ResMut<Rooms>,
+    mut joined_room: EventWriter<ClientJoinedRoom>,
+    mut server: NetServer,
+) {
+    if host.is_none() {
+        return;
+    }
+    rooms.join(HOST_CLIENT_ID, DEFAULT_ROOM);
+    server.send(HOST_CLIENT_ID, RoomMsgClient::JoinedRoom(DEFAULT_ROOM.to_string()));
+    joined_room.send(ClientJoinedRoom {
+        client_id: HOST_CLIENT_ID,
+        room: DEFAULT_ROOM.to_string(),
+        previous: None,
+    });
+}
+
+pub(crate) fn client_left(mut lost: EventReader<ConnectionLostEvent>, mut rooms: ResMut<Rooms>) {
+    let rooms = &mut *rooms;
+    for client in lost.iter() {
+        rooms.leave(client.id);
+        // Or rooms it made and never went into would stay forever.
+        let emptied = &mut rooms.emptied;
+        rooms.creators.retain(|room, creator| {
+            let created = *creator == client.id;
+            if created {
+                emptied.push(room.clone());
+            }
+            !created
+        });
+    }
+}
+
+/// Drops rooms nobody is in anymore, unless there are models in them.
+fn remove_empty_rooms(mut rooms: ResMut<Rooms>, models: Query<&InRoom, (With<ServerModel>, With<OnServer>)>) {
+    if rooms.emptied.is_empty() {
+        return;
+    }
+    let rooms = &mut *rooms;
+    for room in rooms.emptied.drain(..) {
+        let empty = matches!(rooms.rooms.get(&room), Some(members) if members.is_empty());
+        if empty && !models.iter().any(|in_room| in_room.0 == room) {
+            rooms.rooms.remove(&room);
+            rooms.creators.remove(&room);
+        }
+    }
+}
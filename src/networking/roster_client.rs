@@ -0,0 +1,167 @@
+use crate::networking::player_client::LocalPlayer;
+use crate::networking::roster_server::RosterMsgServer;
+use crate::networking::transport::{receive_from_server, NetClient};
+use crate::networking::{Player, PlayerId, PlayerProfile};
+use bevy_app::App;
+use bevy_ecs::prelude::{Commands, Entity, EventReader, Local, NonSend, Query, Res, ResMut, Resource, With, Without, World};
+use bevy_ecs::system::SystemState;
+use bevy_hierarchy::DespawnRecursiveExt;
+use bevy_quinnet::client::ConnectionLostEvent;
+use bevy_quinnet::shared::channel::ChannelType;
+use bevy_quinnet::shared::ClientId;
+use bevy_transform::prelude::{GlobalTransform, Transform};
+use glam::{Mat4, Vec3};
+use leknet::{ClientMessage, TypeName};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use stereokit::{SkDraw, StereoKitDraw, StereoKitMultiThread, TextAlign, TextStyle};
+
+/// Everyone in the session, this client included, as the server last told us.
+#[derive(Resource, Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Roster(pub BTreeMap<ClientId, PlayerProfile>);
+
+/// The id the server knows this client by, there once the server has welcomed it.
+#[derive(Resource, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LocalClientId(pub ClientId);
+
+/// Sent when someone joins the session, also for everyone already there when this client joins.
+pub struct PlayerJoined(pub ClientId, pub PlayerProfile);
+/// Sent when someone leaves the session.
+pub struct PlayerLeft(pub ClientId, pub PlayerProfile);
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum RosterMsgClient {
+    Welcome(ClientId, Roster),
+    Joined(ClientId, PlayerProfile),
+    ProfileChanged(ClientId, PlayerProfile),
+    Left(ClientId),
+}
+
+impl TypeName for RosterMsgClient {
+    fn get_type_name() -> String {
+        "stereokit_bevy::networking::RosterMsgClient".to_string()
+    }
+}
+
+impl ClientMessage for RosterMsgClient {
+    fn client(self, world: &mut World) {
+        match self {
+            RosterMsgClient::Welcome(client_id, roster) => {
+                world.insert_resource(LocalClientId(client_id));
+                for (client_id, profile) in roster.0 {
+                    joined_msg(world, client_id, profile);
+                }
+            }
+            RosterMsgClient::Joined(client_id, profile) => joined_msg(world, client_id, profile),
+            RosterMsgClient::ProfileChanged(client_id, profile) => {
+                world.resource_mut::<Roster>().0.insert(client_id, profile);
+            }
+            RosterMsgClient::Left(client_id) => left_msg(world, client_id),
+        }
+    }
+
+    fn _client(world: &mut World, msg_bytes: &[u8]) {
+        if let Some(msg) = receive_from_server::<Self>(world, msg_bytes) {
+            msg.client(world)
+        }
+    }
+
+    fn channel_type(&self) -> ChannelType {
+        match self {
+            RosterMsgClient::Welcome(_, _) => ChannelType::OrderedReliable,
+            RosterMsgClient::Joined(_, _) => ChannelType::OrderedReliable,
+            RosterMsgClient::ProfileChanged(_, _) => ChannelType::OrderedReliable,
+            RosterMsgClient::Left(_) => ChannelType::OrderedReliable,
+        }
+    }
+
+    fn plugin(app: &mut App) {
+        app.init_resource::<PlayerProfile>();
+        app.init_resource::<Roster>();
+        app.add_event::<PlayerJoined>();
+        app.add_event::<PlayerLeft>();
+        app.add_system(send_profile);
+        app.add_system(connection_lost);
+        app.add_system(draw_player_names);
+    }
+}
+
+fn joined_msg(world: &mut World, client_id: ClientId, profile: PlayerProfile) {
+    let is_new = world
+        .resource_mut::<Roster>()
+        .0
+        .insert(client_id, profile.clone())
+        .is_none();
+    if is_new {
+        world.send_event(PlayerJoined(client_id, profile));
+    }
+}
+
+fn left_msg(world: &mut World, client_id: ClientId) {
+    let mut system_state: SystemState<(
+        Commands,
+        ResMut<Roster>,
+        Query<(Entity, &PlayerId), (With<Player>, Without<LocalPlayer>)>,
+    )> = SystemState::new(world);
+    let (mut commands, mut roster, query) = system_state.get_mut(world);
+    let profile = roster.0.remove(&client_id);
+    for (entity, player_id) in query.iter() {
+        if player_id.0 == client_id {
+            commands.entity(entity).despawn_recursive();
+        }
+    }
+    system_state.apply(world);
+    if let Some(profile) = profile {
+        world.send_event(PlayerLeft(client_id, profile));
+    }
+}
+
+/// Sends the profile once the server accepts this client, and again whenever it changes.
+fn send_profile(profile: Res<PlayerProfile>, mut sent: Local<bool>, mut client: NetClient) {
+    if !client.is_connected() {
+        *sent = false;
+        return;
+    }
+    if !*sent || profile.is_changed() {
+        client.send(RosterMsgServer::SetProfile(profile.clone()));
+        *sent = true;
+    }
+}
+
+fn connection_lost(mut lost: EventReader<ConnectionLostEvent>, mut roster: ResMut<Roster>, mut commands: Commands) {
+    if lost.iter().count() > 0 {
+        roster.0.clear();
+        commands.remove_resource::<LocalClientId>();
+    }
+}
+
+fn draw_player_names(
+    sk: Option<NonSend<SkDraw>>,
+    roster: Res<Roster>,
+    query: Query<(&PlayerId, &GlobalTransform), (With<Player>, Without<LocalPlayer>)>,
+) {
+    let sk = match sk {
+        None => return,
+        Some(sk) => sk,
+    };
+    let head = sk.input_head().position;
+    for (player_id, transform) in query.iter() {
+        let profile = match roster.0.get(&player_id.0) {
+            None => continue,
+            Some(profile) => profile,
+        };
+        let position = transform.translation() + Vec3::Y * 0.25;
+        let facing = Transform::from_translation(position).looking_at(head, Vec3::Y);
+        sk.text_add_at(
+            &profile.name,
+            Mat4::from_rotation_translation(facing.rotation, position),
+            TextStyle::default(),
+            TextAlign::BottomCenter,
+            TextAlign::Center,
+            0.0,
+            0.0,
+            0.0,
+            profile.color,
+        );
+    }
+}
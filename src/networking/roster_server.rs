@@ -0,0 +1,140 @@
+use crate::networking::room_server::{self, ClientJoinedRoom};
+use crate::networking::roster_client::{Roster, RosterMsgClient};
+use crate::networking::transport::{receive_from_client, NetServer};
+use crate::networking::{OnServer, PlayerId, PlayerProfile};
+use bevy_app::App;
+use bevy_ecs::prelude::{Commands, Entity, EventReader, Query, Res, ResMut, Resource, With, World};
+use bevy_ecs::schedule::IntoSystemConfig;
+use bevy_ecs::system::SystemState;
+use bevy_quinnet::server::ConnectionLostEvent;
+use bevy_quinnet::shared::channel::ChannelType;
+use bevy_quinnet::shared::ClientId;
+use leknet::{ServerMessage, TypeName};
+use serde::{Deserialize, Serialize};
+
+/// Longer player names are cut off.
+pub const MAX_PLAYER_NAME_LEN: usize = 32;
+/// Longer avatar names are cut off.
+pub const MAX_AVATAR_LEN: usize = 64;
+
+/// The server's roster, kept apart from the client's mirror so a host has one of each.
+#[derive(Resource, Default, Debug)]
+pub(crate) struct ServerRoster(pub Roster);
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum RosterMsgServer {
+    SetProfile(PlayerProfile),
+}
+
+impl TypeName for RosterMsgServer {
+    fn get_type_name() -> String {
+        "stereokit_bevy::networking::RosterMsgServer".to_string()
+    }
+}
+
+impl ServerMessage for RosterMsgServer {
+    fn server(self, world: &mut World, client_id: ClientId) {
+        match self {
+            RosterMsgServer::SetProfile(profile) => set_profile_msg(world, client_id, profile),
+        }
+    }
+
+    fn _server(world: &mut World, msg_bytes: &[u8], client_id: ClientId) {
+        if let Some(msg) = receive_from_client::<Self>(world, msg_bytes, client_id) {
+            msg.server(world, client_id);
+        }
+    }
+
+    fn channel_type(&self) -> ChannelType {
+        match self {
+            RosterMsgServer::SetProfile(_) => ChannelType::OrderedReliable,
+        }
+    }
+
+    fn plugin(app: &mut App) {
+        app.init_resource::<ServerRoster>();
+        app.add_system(client_moved);
+        // Runs while the client still counts as in its room.
+        app.add_system(client_left.before(room_server::client_left));
+    }
+}
+
+/// The profiles of the players in `room`.
+fn room_roster(roster: &ServerRoster, server: &NetServer, room: &str) -> Roster {
+    let members = server.room_members(room);
+    Roster(
+        roster.0 .0
+            .iter()
+            .filter(|(client_id, _)| members.contains(client_id))
+            .map(|(client_id, profile)| (*client_id, profile.clone()))
+            .collect(),
+    )
+}
+
+/// Cuts `text` off after `max` characters.
+fn truncate(text: &mut String, max: usize) {
+    if let Some((end, _)) = text.char_indices().nth(max) {
+        text.truncate(end);
+    }
+}
+
+fn set_profile_msg(world: &mut World, client_id: ClientId, mut profile: PlayerProfile) {
+    // Everyone in the room gets a copy, and again on every move.
+    truncate(&mut profile.name, MAX_PLAYER_NAME_LEN);
+    truncate(&mut profile.avatar, MAX_AVATAR_LEN);
+    let mut system_state: SystemState<(NetServer, ResMut<ServerRoster>)> = SystemState::new(world);
+    let (mut server, mut roster) = system_state.get_mut(world);
+    let room = match server.room_of(client_id) {
+        None => return,
+        Some(room) => room,
+    };
+    let is_new = roster.0 .0.insert(client_id, profile.clone()).is_none();
+    if is_new {
+        let welcome = room_roster(&roster, &server, &room);
+        server.send(client_id, RosterMsgClient::Welcome(client_id, welcome));
+        server.broadcast_room(&room, Some(client_id), RosterMsgClient::Joined(client_id, profile));
+    } else {
+        server.broadcast_room(&room, None, RosterMsgClient::ProfileChanged(client_id, profile));
+    }
+}
+
+/// A client moving rooms leaves the old room's roster and gets the new one's.
+fn client_moved(mut joined: EventReader<ClientJoinedRoom>, roster: Res<ServerRoster>, mut server: NetServer) {
+    for joined in joined.iter() {
+        let previous = match &joined.previous {
+            None => continue,
+            Some(previous) => previous,
+        };
+        let profile = match roster.0 .0.get(&joined.client_id) {
+            None => continue,
+            Some(profile) => profile.clone(),
+        };
+        server.broadcast_room(previous, Some(joined.client_id), RosterMsgClient::Left(joined.client_id));
+        let welcome = room_roster(&roster, &server, &joined.room);
+        server.send(joined.client_id, RosterMsgClient::Welcome(joined.client_id, welcome));
+        server.broadcast_room(
+            &joined.room,
+            Some(joined.client_id),
+            RosterMsgClient::Joined(joined.client_id, profile),
+        );
+    }
+}
+
+fn client_left(
+    mut lost: EventReader<ConnectionLostEvent>,
+    mut roster: ResMut<ServerRoster>,
+    players: Query<(Entity, &PlayerId), With<OnServer>>,
+    mut commands: Commands,
+    mut server: NetServer,
+) {
+    for client in lost.iter() {
+        for (entity, player_id) in players.iter() {
+            if player_id.0 == client.id {
+                commands.entity(entity).despawn();
+            }
+        }
+        if roster.0 .0.remove(&client.id).is_some() {
+            server.broadcast_from(client.id, RosterMsgClient::Left(client.id));
+        }
+    }
+}
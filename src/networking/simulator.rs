@@ -0,0 +1,168 @@
+use bevy_app::{App, Plugin};
+use bevy_ecs::prelude::{Resource, World};
+use bevy_quinnet::shared::channel::ChannelType;
+use bevy_time::Time;
+use std::time::Duration;
+
+/// What a bad network does to messages on one kind of channel.
+#[derive(Clone, Debug, Default)]
+pub struct LinkConditions {
+    pub latency: Duration,
+    /// Up to this much extra latency, picked at random for every message.
+    pub jitter: Duration,
+    /// Chance from 0 to 1 that a message is dropped. Reliable channels resend it instead, so it
+    /// arrives about a round trip late.
+    pub loss: f32,
+    /// Chance from 0 to 1 that a message is sent twice.
+    pub duplicate: f32,
+    /// Chance from 0 to 1 that a message is held back long enough to arrive after later ones.
+    /// Ordered channels keep their order no matter what.
+    pub reorder: f32,
+}
+
+/// Makes outgoing messages late, lost, duplicated or out of order, to reproduce Wi-Fi problems
+/// locally. Add it with [`NetworkSimulatorPlugin`] to a client, a server or both.
+#[derive(Resource, Clone, Debug)]
+pub struct NetworkConditions {
+    pub ordered_reliable: LinkConditions,
+    pub unordered_reliable: LinkConditions,
+    pub unreliable: LinkConditions,
+    /// The same seed drops and delays the same messages every run.
+    pub seed: u64,
+}
+
+impl Default for NetworkConditions {
+    fn default() -> Self {
+        Self {
+            ordered_reliable: Default::default(),
+            unordered_reliable: Default::default(),
+            unreliable: Default::default(),
+            seed: 0x5eed,
+        }
+    }
+}
+
+impl NetworkConditions {
+    /// Roughly a busy home Wi-Fi network.
+    pub fn wifi() -> Self {
+        let reliable = LinkConditions {
+            latency: Duration::from_millis(30),
+            jitter: Duration::from_millis(40),
+            ..Default::default()
+        };
+        Self {
+            ordered_reliable: reliable.clone(),
+            unordered_reliable: reliable,
+            unreliable: LinkConditions {
+                latency: Duration::from_millis(30),
+                jitter: Duration::from_millis(40),
+                loss: 0.05,
+                duplicate: 0.01,
+                reorder: 0.05,
+            },
+            ..Default::default()
+        }
+    }
+
+    pub fn for_channel(&self, channel_type: ChannelType) -> &LinkConditions {
+        match channel_type {
+            ChannelType::OrderedReliable => &self.ordered_reliable,
+            ChannelType::UnorderedReliable => &self.unordered_reliable,
+            ChannelType::Unreliable => &self.unreliable,
+        }
+    }
+}
+
+type Delivery = Box<dyn FnOnce(&mut World) + Send + Sync>;
+
+/// Messages held back by the simulator until their time comes.
+#[derive(Resource)]
+pub struct SimulatedLink {
+    rng: u64,
+    /// The app's time as of the last update, so delays follow [`Time`] like everything else.
+    now: Duration,
+    last_ordered: Option<Duration>,
+    queue: Vec<(Duration, Delivery)>,
+}
+
+impl SimulatedLink {
+    pub(crate) fn new(seed: u64) -> Self {
+        Self {
+            // Xorshift gets stuck on zero.
+            rng: seed.max(1),
+            now: Duration::ZERO,
+            last_ordered: None,
+            queue: vec![],
+        }
+    }
+
+    fn random(&mut self) -> f32 {
+        self.rng ^= self.rng << 13;
+        self.rng ^= self.rng >> 7;
+        self.rng ^= self.rng << 17;
+        (self.rng >> 40) as f32 / (1u64 << 24) as f32
+    }
+
+    /// When each copy of a message should go out, empty if it's lost.
+    pub(crate) fn schedule(&mut self, conditions: &NetworkConditions, channel_type: ChannelType) -> Vec<Duration> {
+        let link = conditions.for_channel(channel_type).clone();
+        let mut resent_after = Duration::ZERO;
+        if self.random() < link.loss {
+            match channel_type {
+                ChannelType::Unreliable => return vec![],
+                // Quinnet resends it once the loss is noticed, about a round trip later.
+                ChannelType::OrderedReliable | ChannelType::UnorderedReliable => {
+                    resent_after = link.latency * 2 + link.jitter;
+                }
+            }
+        }
+        let copies = if self.random() < link.duplicate { 2 } else { 1 };
+        (0..copies)
+            .map(|_| {
+                let mut delay = resent_after + link.latency + link.jitter.mul_f32(self.random());
+                if self.random() < link.reorder {
+                    delay += link.latency + link.jitter;
+                }
+                let mut at = self.now + delay;
+                if let ChannelType::OrderedReliable = channel_type {
+                    at = at.max(self.last_ordered.unwrap_or(at));
+                    self.last_ordered = Some(at);
+                }
+                at
+            })
+            .collect()
+    }
+
+    /// `send` runs once for every copy that gets through, when it's due.
+    pub(crate) fn queue<F>(&mut self, conditions: &NetworkConditions, channel_type: ChannelType, send: F)
+    where
+        F: Fn(&mut World) + Clone + Send + Sync + 'static,
+    {
+        for at in self.schedule(conditions, channel_type) {
+            self.queue.push((at, Box::new(send.clone())));
+        }
+    }
+}
+
+pub struct NetworkSimulatorPlugin(pub NetworkConditions);
+
+impl Plugin for NetworkSimulatorPlugin {
+    fn build(&self, app: &mut App) {
+        app.insert_resource(SimulatedLink::new(self.0.seed));
+        app.insert_resource(self.0.clone());
+        app.add_system(flush_simulated_link);
+    }
+}
+
+fn flush_simulated_link(world: &mut World) {
+    let now = world.resource::<Time>().elapsed();
+    let mut link = world.resource_mut::<SimulatedLink>();
+    link.now = now;
+    // Sorted so messages due in the same frame still go out in the order they were scheduled for.
+    link.queue.sort_by_key(|(at, _)| *at);
+    let due = link.queue.iter().take_while(|(at, _)| *at <= now).count();
+    let due: Vec<_> = link.queue.drain(..due).collect();
+    for (_, send) in due {
+        send(world);
+    }
+}
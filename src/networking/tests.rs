@@ -1,23 +1,69 @@
+use crate::networking::admin::{connected_players, ClearWorld, KickClient};
+use crate::networking::asset_client::{AssetCache, AssetStreamConfig, PartialAsset};
+use crate::networking::asset_server::{AssetMsgServer, ServerAssets};
+use crate::networking::avatar::{Avatar, AvatarLibrary};
+use crate::networking::clock_client::NetworkClock;
+use crate::networking::clock_server::{measure_rtt, ServerClock};
+use crate::networking::compression::{DirtyMask, LastSent, ModelDelta, QuantizedQuat, SETTLE_TIME};
+use crate::networking::config::NetworkConfig;
+use crate::networking::diagnostics::{NetworkDiagnostics, Peer};
+use crate::networking::discovery::{DiscoveredServers, DiscoveryConfig, MAX_NAME_LEN};
+use crate::networking::handshake_client::{HandshakeState, MessageRegistry, PROTOCOL_VERSION};
+use crate::networking::handshake_server::AcceptedClients;
+use crate::networking::hands::{CompressedPose, HandPose, HandsSequence, PlayerHands, JOINTS};
+use crate::networking::harness::LoopbackHarness;
+use crate::networking::interest::{AreaOfInterest, InterestConfig};
+use crate::networking::interpolation::{Sequence, SequenceCounter, SnapshotBuffer};
+use crate::networking::model_server::{ModelMsgServer, ServerModel};
+use crate::networking::player_server::PlayerMsgServer;
+use crate::networking::network_event_client::{NetworkEvent, NetworkEventAppExt};
+use crate::networking::ownership_server::OwnershipMsgServer;
+use crate::networking::persistence::PersistenceConfig;
+use crate::networking::player_client::LocalPlayer;
+use crate::networking::reconnect::{Reconnected, SessionConfig, Sessions};
+use crate::networking::recording::{Direction, Recording, RecordingConfig, Replay, ReplayTarget};
+use crate::networking::replication::{
+    accumulate_priority, due, refill_budget, ReplicationConfig, ReplicationPriority, ReplicationRate, ReplicationState,
+    SendBudget,
+};
+use crate::networking::room_client::{CreateRoom, CurrentRoom, JoinRoom, RoomList};
+use crate::networking::room_server::{RoomMsgServer, Rooms, MAX_ROOMS, MAX_ROOMS_PER_CLIENT, MAX_ROOM_NAME_LEN};
+use crate::networking::roster_client::{LocalClientId, Roster};
+use crate::networking::roster_server::{MAX_AVATAR_LEN, MAX_PLAYER_NAME_LEN};
+use crate::networking::simulator::{LinkConditions, NetworkConditions, NetworkSimulatorPlugin, SimulatedLink};
+use crate::networking::transport::{run_host_messages, HostQueue};
+use crate::networking::voice::{decode_frame, encode_frame, FRAME_SAMPLES, SAMPLE_RATE};
+use crate::networking::{AssetId, HasAuthority, IgnoreModelAdd, InRoom, ModelData2, Owner, Player, PlayerId, PlayerProfile};
 use crate::{ModelBundle, ModelInfo};
-use bevy_ecs::prelude::{Commands, Component, NonSend, Query, Res};
-use bevy_transform::prelude::Transform;
-use glam::Vec3;
-use leknet::Networked;
-use stereokit::{Handed, Material, Sk, SkDraw, StereoKitMultiThread};
+use bevy_ecs::prelude::{
+    Commands, Component, Entity, EventReader, NonSend, Query, Res, ResMut, Resource, Schedule, With, Without,
+    World,
+};
+use bevy_hierarchy::{BuildWorldChildren, Parent};
+use bevy_quinnet::shared::channel::ChannelType;
+use bevy_transform::prelude::{GlobalTransform, Transform};
+use bevy_time::Time;
+use bevy_transform::TransformBundle;
+use glam::{Quat, Vec3};
+use leknet::{Networked, ServerEntity, ServerMessage, TypeName};
+use serde::{Deserialize, Serialize};
+use std::net::{IpAddr, Ipv4Addr, UdpSocket};
+use std::time::{Duration, Instant};
+use stereokit::{Color128, Handed, Material, RenderLayer, Sk, SkDraw, StereoKitMultiThread};
 
 #[test]
+#[ignore = "runs until killed, start it by hand next to client_test"]
 fn server_test() {
     let mut app = bevy_app::App::new();
     app.add_plugins(crate::networking::StereoKitBevyServerPlugins);
-    app.add_startup_system(leknet::start_server);
     app.run();
 }
 
 #[test]
+#[ignore = "runs until killed and needs a display"]
 fn client_test() {
     let mut app = bevy_app::App::new();
     app.add_plugins(crate::networking::StereoKitBevyClientPlugins);
-    app.add_startup_system(leknet::connect_to_server);
     app.add_startup_system(add_example_model);
     app.add_system(sync_example_model);
     app.run();
@@ -50,3 +96,1223 @@ fn sync_example_model(sk: Res<Sk>, mut query: Query<(&RightHand, &mut Transform)
         transform.rotation = temp.rotation;
     }
 }
+
+#[test]
+fn snapshot_buffer_drops_old_and_interpolates() {
+    let mut buffer = SnapshotBuffer::default();
+    assert!(buffer.push(Sequence(1), 0.0, Transform::from_xyz(0.0, 0.0, 0.0), 8));
+    assert!(buffer.push(Sequence(3), 1.0, Transform::from_xyz(2.0, 0.0, 0.0), 8));
+    assert!(!buffer.push(Sequence(2), 1.1, Transform::from_xyz(9.0, 0.0, 0.0), 8));
+    assert_eq!(buffer.sample(0.5).unwrap().translation, Vec3::new(1.0, 0.0, 0.0));
+    assert_eq!(buffer.sample(2.0).unwrap().translation, Vec3::new(2.0, 0.0, 0.0));
+    assert!(Sequence(0).is_newer_than(Sequence(u32::MAX)));
+}
+
+#[test]
+fn model_delta_is_smaller_than_full_update() {
+    /// What `ModelChanged` sent before deltas.
+    #[derive(Serialize)]
+    struct FullUpdate {
+        transform: Transform,
+        color128: Color128,
+        render_layer: RenderLayer,
+    }
+
+    let color = Color128::new(0.1, 0.4, 0.0, 0.6);
+    let layer = RenderLayer::LAYER1;
+    let mut transform = Transform::from_xyz(0.5, 1.2, -0.3).with_rotation(Quat::from_rotation_y(0.7));
+    let mut last_sent = LastSent::new(&transform, color, layer);
+    transform.translation.x += 0.05;
+    let mask = last_sent.peek(&transform, color, layer);
+    last_sent.update(&transform, color, layer, Duration::ZERO, false);
+    assert_eq!(mask, DirtyMask(DirtyMask::TRANSLATION));
+
+    let full = bincode::serialize(&FullUpdate {
+        transform,
+        color128: color,
+        render_layer: layer,
+    })
+    .unwrap();
+    let moved = bincode::serialize(&ModelDelta::new(mask, &transform, color, layer)).unwrap();
+    let everything = bincode::serialize(&ModelDelta::new(DirtyMask::ALL, &transform, color, layer)).unwrap();
+    assert!(moved.len() * 4 < full.len());
+    assert!(everything.len() < full.len());
+
+    let mut received = Transform::default();
+    ModelDelta::new(DirtyMask::ALL, &transform, color, layer).apply_transform(&mut received);
+    assert!(received.translation.distance(transform.translation) < 0.001);
+    assert!(QuantizedQuat::new(transform.rotation).get().angle_between(transform.rotation) < 0.01);
+}
+
+#[test]
+fn far_translations_keep_full_precision() {
+    let color = Color128::new(1.0, 1.0, 1.0, 1.0);
+    let far = Transform::from_xyz(120.5, -3.0, 4000.25);
+    let mut received = Transform::default();
+    ModelDelta::new(DirtyMask::ALL, &far, color, RenderLayer::LAYER1).apply_transform(&mut received);
+    assert_eq!(received.translation, far.translation);
+
+    let mut last_sent = LastSent::new(&far, color, RenderLayer::LAYER1);
+    let farther = Transform::from_xyz(121.5, -3.0, 4000.25);
+    assert_eq!(last_sent.peek(&farther, color, RenderLayer::LAYER1), DirtyMask(DirtyMask::TRANSLATION));
+    last_sent.update(&farther, color, RenderLayer::LAYER1, Duration::ZERO, false);
+    assert_eq!(last_sent.peek(&farther, color, RenderLayer::LAYER1), DirtyMask(0));
+}
+
+#[test]
+fn last_sent_settles_once_changes_stop() {
+    let color = Color128::new(1.0, 1.0, 1.0, 1.0);
+    let layer = RenderLayer::LAYER1;
+    let transform = Transform::from_xyz(0.5, 1.2, -0.3);
+    let mut last_sent = LastSent::new(&transform, color, layer);
+    // Added reliably, nothing to settle.
+    assert!(!last_sent.needs_settling(SETTLE_TIME * 4));
+
+    let sent_at = Duration::from_secs(1);
+    last_sent.update(&transform, color, layer, sent_at, false);
+    assert!(!last_sent.needs_settling(sent_at + SETTLE_TIME / 2));
+    assert!(last_sent.needs_settling(sent_at + SETTLE_TIME));
+    last_sent.update(&transform, color, layer, sent_at + SETTLE_TIME, true);
+    assert!(!last_sent.needs_settling(sent_at + SETTLE_TIME * 4));
+}
+
+#[test]
+fn simulated_link_schedules_like_a_bad_network() {
+    let latency = Duration::from_millis(50);
+    let link = |loss, duplicate| LinkConditions {
+        latency,
+        loss,
+        duplicate,
+        ..Default::default()
+    };
+    let conditions = |link: LinkConditions| NetworkConditions {
+        ordered_reliable: link.clone(),
+        unordered_reliable: link.clone(),
+        unreliable: link,
+        ..Default::default()
+    };
+    let mut simulated = SimulatedLink::new(1);
+
+    let clean = conditions(link(0.0, 0.0));
+    assert_eq!(simulated.schedule(&clean, ChannelType::Unreliable), vec![latency]);
+
+    // Lost unreliable messages are gone, reliable ones come a round trip later.
+    let lossy = conditions(link(1.0, 0.0));
+    assert!(simulated.schedule(&lossy, ChannelType::Unreliable).is_empty());
+    for channel_type in [ChannelType::OrderedReliable, ChannelType::UnorderedReliable] {
+        assert_eq!(simulated.schedule(&lossy, channel_type), vec![latency * 3]);
+    }
+
+    let duplicating = conditions(link(0.0, 1.0));
+    assert_eq!(simulated.schedule(&duplicating, ChannelType::Unreliable), vec![latency; 2]);
+
+    let mut shuffling = conditions(link(0.5, 0.0));
+    shuffling.ordered_reliable.jitter = latency;
+    shuffling.ordered_reliable.reorder = 0.5;
+    let ordered: Vec<_> = (0..100)
+        .flat_map(|_| simulated.schedule(&shuffling, ChannelType::OrderedReliable))
+        .collect();
+    assert_eq!(ordered.len(), 100);
+    assert!(ordered.windows(2).all(|pair| pair[0] <= pair[1]));
+}
+
+/// A world that went through one update of `delta` with `config`, for running replication systems.
+fn replication_world(config: ReplicationConfig, delta: Duration) -> World {
+    let mut world = World::new();
+    let mut time = Time::default();
+    let start = Instant::now();
+    time.update_with_instant(start);
+    time.update_with_instant(start + delta);
+    world.insert_resource(time);
+    world.insert_resource(config);
+    world.init_resource::<SendBudget>();
+    world
+}
+
+#[test]
+fn send_budget_refills_up_to_a_second() {
+    let config = ReplicationConfig {
+        bytes_per_second: 1000.0,
+        ..Default::default()
+    };
+    let mut world = replication_world(config.clone(), Duration::from_millis(100));
+    let mut schedule = Schedule::new();
+    schedule.add_system(refill_budget);
+    schedule.run(&mut world);
+    let mut budget = world.resource_mut::<SendBudget>();
+    assert!(budget.try_spend(60));
+    assert!(!budget.try_spend(60));
+    assert!(budget.try_spend(40));
+
+    let mut world = replication_world(config, Duration::from_secs(10));
+    schedule.run(&mut world);
+    let mut budget = world.resource_mut::<SendBudget>();
+    assert!(!budget.try_spend(1001));
+    assert!(budget.try_spend(1000));
+}
+
+#[test]
+fn due_entities_are_rate_limited_and_ordered_by_priority() {
+    let mut world = replication_world(ReplicationConfig::default(), Duration::from_millis(100));
+    world.spawn((LocalPlayer, Transform::IDENTITY));
+    let mut spawn = |x: f32| {
+        let transform = GlobalTransform::from_translation(Vec3::new(x, 0.0, 0.0));
+        world.spawn((HasAuthority, ReplicationState::dirty(), transform)).id()
+    };
+    let (near, far, weighted, limited) = (spawn(1.0), spawn(10.0), spawn(20.0), spawn(1.0));
+    world.entity_mut(weighted).insert(ReplicationPriority(100.0));
+    // Sent just now and changed again, it has to wait a second at this rate.
+    let mut state = ReplicationState::dirty();
+    state.sent();
+    state.mark_dirty();
+    world.entity_mut(limited).insert((state, ReplicationRate(1.0)));
+
+    let mut schedule = Schedule::new();
+    schedule.add_system(accumulate_priority);
+    schedule.run(&mut world);
+    let config = world.resource::<ReplicationConfig>().clone();
+    let mut query = world.query::<(Entity, &ReplicationState, Option<&ReplicationRate>)>();
+    assert_eq!(due(&config, query.iter(&world)), vec![weighted, near, far]);
+}
+
+#[test]
+fn loopback_model_replicates() {
+    let mut harness = LoopbackHarness::new(2);
+    harness.steps(20);
+    harness.spawn_cube(0, Vec3::new(1.0, 0.0, 0.0));
+    assert!(harness.step_until(200, |harness| harness.client_sees_model_at(
+        1,
+        Vec3::new(1.0, 0.0, 0.0),
+        0.001
+    )));
+
+    let world = &mut harness.client(0).world;
+    let mut query = world.query_filtered::<&mut Transform, With<Networked>>();
+    for mut transform in query.iter_mut(world) {
+        if transform.translation.x == 1.0 {
+            transform.translation.y = 2.0;
+        }
+    }
+    assert!(harness.step_until(200, |harness| harness.client_sees_model_at(
+        1,
+        Vec3::new(1.0, 2.0, 0.0),
+        0.001
+    )));
+    assert_eq!(harness.client_model_count(1), 1);
+}
+
+#[test]
+fn partial_asset_reassembles_chunks_in_any_order() {
+    let bytes: Vec<u8> = (0..100u32).map(|i| (i * 7) as u8).collect();
+    let asset = AssetId::new(&bytes);
+    let mut partial = PartialAsset::new(bytes.len(), 1000).unwrap();
+    for start in [60, 0, 0, 90] {
+        let end = (start + 30).min(bytes.len());
+        assert!(partial.insert(bytes.len(), start, &bytes[start..end]));
+    }
+    assert_eq!(partial.received(), 70);
+    // Overlaps count once.
+    assert!(partial.insert(bytes.len(), 10, &bytes[10..50]));
+    assert_eq!(partial.received(), 90);
+    assert!(!partial.is_complete());
+    assert!(partial.insert(bytes.len(), 30, &bytes[30..60]));
+    assert!(partial.is_complete());
+    assert_eq!(partial.finish(asset), Some(bytes));
+}
+
+#[test]
+fn partial_asset_refuses_bad_chunks() {
+    assert!(PartialAsset::new(1001, 1000).is_none());
+    let bytes = vec![1; 100];
+    let mut partial = PartialAsset::new(bytes.len(), 1000).unwrap();
+    assert!(!partial.insert(100, usize::MAX, &bytes[..1]));
+    assert!(!partial.insert(100, 90, &bytes[..20]));
+    assert!(!partial.insert(200, 0, &bytes[..20]));
+    assert_eq!(partial.received(), 0);
+    assert!(partial.insert(100, 0, &bytes));
+    assert!(partial.is_complete());
+    assert_eq!(partial.finish(AssetId::new(&[2; 100])), None);
+}
+
+#[test]
+fn loopback_model_bytes_stream_in_chunks() {
+    let config = AssetStreamConfig {
+        chunk_size: 1000,
+        ..Default::default()
+    };
+    let mut harness = LoopbackHarness::with_apps(
+        2,
+        |server| {
+            server.insert_resource(config.clone());
+        },
+        |_, client| {
+            client.insert_resource(config.clone());
+        },
+    );
+    harness.steps(20);
+    let bytes: Vec<u8> = (0..10_000u32).map(|i| (i * 7 % 251) as u8).collect();
+    let asset = AssetId::new(&bytes);
+    harness.client(0).world.spawn((
+        ModelInfo::Mem {
+            name: "streamed".to_string(),
+            mem: bytes.clone(),
+        },
+        TransformBundle::default(),
+        Color128::new(1.0, 1.0, 1.0, 1.0),
+        RenderLayer::LAYER1,
+        Networked,
+    ));
+    // The placeholder on the other client gets the bytes once they're all there.
+    assert!(harness.step_until(200, |harness| {
+        let world = &mut harness.client(1).world;
+        let mut query = world.query::<&ModelInfo>();
+        let swapped = query
+            .iter(world)
+            .any(|model_info| matches!(model_info, ModelInfo::Mem { mem, .. } if *mem == bytes));
+        swapped
+    }));
+    assert_eq!(harness.server.world.resource::<ServerAssets>().get(asset), Some(&bytes[..]));
+    assert_eq!(harness.client(1).world.resource::<AssetCache>().0[&asset], bytes);
+}
+
+#[test]
+fn loopback_server_takes_only_uploads_it_asked_for() {
+    let mut harness = LoopbackHarness::new(1);
+    harness.steps(20);
+    let client_id = harness.client_id(0).unwrap();
+    let world = &mut harness.server.world;
+    let bytes = vec![7u8; 100];
+    let asset = AssetId::new(&bytes);
+    let chunk = AssetMsgServer::Chunk {
+        asset,
+        total: bytes.len(),
+        offset: 0,
+        bytes: bytes.clone(),
+    };
+    chunk.clone().server(world, client_id);
+    assert_eq!(world.resource::<ServerAssets>().get(asset), None);
+
+    let max_uploads = world.resource::<AssetStreamConfig>().max_uploads_per_client;
+    for i in 0..10u8 {
+        world.resource_mut::<ServerAssets>().announce(client_id, AssetId::new(&[i]));
+    }
+    world.resource_mut::<ServerAssets>().announce(client_id, asset);
+    harness.steps(1);
+    assert_eq!(harness.server.world.resource::<ServerAssets>().uploads_from(client_id), max_uploads);
+
+    harness.disconnect(0);
+    harness.steps(5);
+    assert_eq!(harness.server.world.resource::<ServerAssets>().uploads_from(client_id), 0);
+}
+
+#[test]
+fn loopback_model_replicates_over_bad_network() {
+    let mut harness = LoopbackHarness::with_apps(
+        2,
+        |server| {
+            server.add_plugin(NetworkSimulatorPlugin(NetworkConditions::wifi()));
+        },
+        |_, client| {
+            client.add_plugin(NetworkSimulatorPlugin(NetworkConditions::wifi()));
+        },
+    );
+    harness.steps(20);
+    harness.spawn_cube(0, Vec3::new(0.0, 1.0, 0.0));
+    assert!(harness.step_until(400, |harness| harness.client_sees_model_at(
+        1,
+        Vec3::new(0.0, 1.0, 0.0),
+        0.001
+    )));
+}
+
+/// Makes the harness server a host, minus StereoKit.
+fn add_headless_host(server: &mut bevy_app::App) {
+    super::add_client(server);
+    server.init_resource::<HostQueue>();
+    server.add_system(run_host_messages);
+}
+
+#[test]
+fn loopback_host_shares_models_both_ways() {
+    let mut harness = LoopbackHarness::with_apps(1, add_headless_host, |_, _| {});
+    harness.steps(20);
+    harness.server.world.spawn((
+        ModelInfo::Cube(Vec3::splat(0.1)),
+        TransformBundle::from(Transform::from_translation(Vec3::new(1.0, 0.0, 0.0))),
+        Color128::new(1.0, 1.0, 1.0, 1.0),
+        RenderLayer::LAYER1,
+        Networked,
+    ));
+    assert!(harness.step_until(200, |harness| harness.client_sees_model_at(
+        0,
+        Vec3::new(1.0, 0.0, 0.0),
+        0.001
+    )));
+
+    harness.spawn_cube(0, Vec3::new(2.0, 0.0, 0.0));
+    assert!(harness.step_until(200, |harness| {
+        let world = &mut harness.server.world;
+        let mut query = world.query_filtered::<&Transform, (With<ModelInfo>, With<IgnoreModelAdd>)>();
+        query
+            .iter(world)
+            .any(|transform| transform.translation.distance(Vec3::new(2.0, 0.0, 0.0)) < 0.001)
+    }));
+}
+
+#[test]
+fn loopback_rejects_incompatible_client() {
+    let mut harness = LoopbackHarness::with_apps(
+        2,
+        |_| {},
+        |i, client| {
+            if i == 1 {
+                let mut registry = client.world.resource::<MessageRegistry>().clone();
+                registry.0[0].1 ^= 1;
+                client.insert_resource(registry);
+            }
+        },
+    );
+    assert!(harness.step_until(200, |harness| {
+        matches!(
+            harness.client(1).world.resource::<HandshakeState>(),
+            HandshakeState::Rejected(_)
+        ) && *harness.client(0).world.resource::<HandshakeState>() == HandshakeState::Accepted
+    }));
+}
+
+mod schema_a {
+    #[derive(serde::Deserialize)]
+    pub struct Message {
+        pub position: [f32; 3],
+    }
+}
+
+mod schema_b {
+    #[derive(serde::Deserialize)]
+    pub struct Message {
+        pub position: [f32; 3],
+    }
+}
+
+mod schema_c {
+    #[derive(serde::Deserialize)]
+    pub struct Message {
+        pub position: [f64; 3],
+    }
+}
+
+macro_rules! named_message {
+    ($($message:ty),*) => {$(
+        impl TypeName for $message {
+            fn get_type_name() -> String {
+                "Message".to_string()
+            }
+        }
+    )*};
+}
+
+named_message!(schema_a::Message, schema_b::Message, schema_c::Message);
+
+fn schema_hash<M: TypeName + for<'de> Deserialize<'de>>() -> u64 {
+    let mut registry = MessageRegistry::default();
+    registry.register::<M>();
+    registry.0[0].1
+}
+
+#[test]
+fn schema_hash_follows_layout() {
+    // Where the type lives doesn't matter, only what goes over the wire.
+    assert_eq!(schema_hash::<schema_a::Message>(), schema_hash::<schema_b::Message>());
+    assert_ne!(schema_hash::<schema_a::Message>(), schema_hash::<schema_c::Message>());
+}
+
+#[test]
+fn loopback_server_drops_unaccepted_and_undecodable_messages() {
+    let mut harness = LoopbackHarness::new(1);
+    assert!(harness.step_until(200, |harness| {
+        *harness.client(0).world.resource::<HandshakeState>() == HandshakeState::Accepted
+    }));
+    let world = &mut harness.server.world;
+    let client_id = *world.resource::<AcceptedClients>().0.iter().next().unwrap();
+
+    let stranger = client_id.wrapping_add(1);
+    let bytes = bincode::serialize(&ModelMsgServer::ParentChanged(ServerEntity(Entity::from_raw(0)), None)).unwrap();
+    ModelMsgServer::_server(world, &bytes, stranger);
+    let mut diagnostics = world.resource_mut::<NetworkDiagnostics>();
+    assert_eq!(diagnostics.connection(Peer::Client(stranger)).dropped, 1);
+
+    ModelMsgServer::_server(world, &[0xff; 4], client_id);
+    assert!(!world.resource::<AcceptedClients>().0.contains(&client_id));
+}
+
+#[test]
+fn loopback_roster_names_players() {
+    let mut harness = LoopbackHarness::with_apps(
+        2,
+        |_| {},
+        |i, client| {
+            client.insert_resource(PlayerProfile {
+                name: format!("player {i}"),
+                ..Default::default()
+            });
+        },
+    );
+    assert!(harness.step_until(200, |harness| {
+        let world = &mut harness.client(1).world;
+        let roster = world.resource::<Roster>().clone();
+        let mut query = world.query_filtered::<&PlayerId, With<Player>>();
+        roster.0.len() == 2
+            && query.iter(world).any(|player_id| {
+                roster.0.get(&player_id.0).map(|profile| profile.name.as_str()) == Some("player 0")
+            })
+    }));
+}
+
+#[test]
+fn loopback_server_cuts_long_profiles() {
+    let mut harness = LoopbackHarness::with_apps(
+        2,
+        |_| {},
+        |_, client| {
+            client.insert_resource(PlayerProfile {
+                name: "é".repeat(1000),
+                avatar: "robot".repeat(1000),
+                ..Default::default()
+            });
+        },
+    );
+    harness.steps(20);
+    let client_id = harness.client_id(0).unwrap();
+    assert!(harness.step_until(200, |harness| {
+        harness.client(1).world.resource::<Roster>().0.get(&client_id).map(|profile| {
+            (profile.name.chars().count(), profile.avatar.chars().count())
+        }) == Some((MAX_PLAYER_NAME_LEN, MAX_AVATAR_LEN))
+    }));
+}
+
+#[test]
+fn loopback_avatars_follow_profile_changes() {
+    let robot = Avatar {
+        head: ModelInfo::Cube(Vec3::splat(0.3)),
+        ..Default::default()
+    };
+    let mut library = AvatarLibrary::default();
+    library.0.insert("robot".to_string(), robot);
+    let mut harness = LoopbackHarness::with_apps(2, |_| {}, |_, client| {
+        client.insert_resource(library.clone());
+    });
+    let has_head = |harness: &mut LoopbackHarness, head: Vec3| {
+        let world = &mut harness.client(1).world;
+        let mut query = world.query_filtered::<&Avatar, (With<Player>, Without<LocalPlayer>)>();
+        query
+            .iter(world)
+            .any(|avatar| matches!(avatar.head, ModelInfo::Cube(size) if size == head))
+    };
+    assert!(harness.step_until(200, |harness| has_head(harness, Vec3::new(0.18, 0.22, 0.2))));
+    harness.client(0).world.resource_mut::<PlayerProfile>().avatar = "robot".to_string();
+    assert!(harness.step_until(200, |harness| has_head(harness, Vec3::splat(0.3))));
+}
+
+#[test]
+fn loopback_joiner_sees_each_player_once() {
+    let mut harness = LoopbackHarness::new(3);
+    let remote_players = |harness: &mut LoopbackHarness, i: usize| {
+        let world = &mut harness.client(i).world;
+        let mut query = world.query_filtered::<(), (With<Player>, Without<LocalPlayer>)>();
+        query.iter(world).count()
+    };
+    assert!(harness.step_until(200, |harness| (0..3).all(|i| remote_players(harness, i) == 2)));
+    harness.steps(50);
+    assert!((0..3).all(|i| remote_players(&mut harness, i) == 2));
+}
+
+fn tracked_hands(joints: usize) -> PlayerHands {
+    let pose = CompressedPose::new(Vec3::ZERO, Quat::IDENTITY);
+    PlayerHands {
+        left: HandPose::Tracked {
+            wrist: pose,
+            joints: vec![pose; joints],
+        },
+        right: HandPose::Untracked,
+    }
+}
+
+#[test]
+fn loopback_server_drops_stale_and_oversized_hands() {
+    let mut harness = LoopbackHarness::new(1);
+    let player = |harness: &mut LoopbackHarness| {
+        let client_id = harness.client_id(0)?;
+        let world = &mut harness.server.world;
+        let mut query = world.query::<(Entity, &PlayerId)>();
+        let player = query.iter(world).find(|(_, player_id)| player_id.0 == client_id);
+        player.map(|(entity, _)| ServerEntity(entity))
+    };
+    assert!(harness.step_until(200, |harness| player(harness).is_some()));
+    let player = player(&mut harness).unwrap();
+    let client_id = harness.client_id(0).unwrap();
+    let world = &mut harness.server.world;
+    let last_sequence = |world: &World| world.get::<HandsSequence>(player.0).map(|sequence| sequence.0);
+    let dropped = |world: &mut World| {
+        let mut diagnostics = world.resource_mut::<NetworkDiagnostics>();
+        diagnostics.connection(Peer::Client(client_id)).dropped
+    };
+
+    let sequence = Sequence(1_000_000);
+    PlayerMsgServer::HandsChanged(player, sequence, tracked_hands(JOINTS)).server(world, client_id);
+    assert_eq!(last_sequence(world), Some(sequence));
+    let dropped_before = dropped(world);
+    PlayerMsgServer::HandsChanged(player, Sequence(999_999), tracked_hands(JOINTS)).server(world, client_id);
+    assert_eq!(last_sequence(world), Some(sequence));
+    assert_eq!(dropped(world), dropped_before + 1);
+
+    assert!(!tracked_hands(JOINTS + 1).is_valid());
+    PlayerMsgServer::HandsChanged(player, Sequence(1_000_001), tracked_hands(10_000)).server(world, client_id);
+    assert_eq!(last_sequence(world), Some(sequence));
+}
+
+#[test]
+fn voice_frame_round_trips() {
+    let frame: Vec<f32> = (0..FRAME_SAMPLES)
+        .map(|i| (i as f32 / SAMPLE_RATE as f32 * 440.0 * std::f32::consts::TAU).sin() * 0.5)
+        .collect();
+    let encoded = encode_frame(&frame);
+    assert_eq!(encoded.len(), FRAME_SAMPLES / 3);
+    let decoded = decode_frame(&encoded);
+    assert_eq!(decoded.len(), FRAME_SAMPLES);
+    let error = frame
+        .iter()
+        .zip(&decoded)
+        .map(|(a, b)| (a - b).abs())
+        .sum::<f32>()
+        / FRAME_SAMPLES as f32;
+    assert!(error < 0.05);
+}
+
+#[test]
+fn loopback_network_event_reaches_other_client() {
+    #[derive(Clone, Debug, Serialize, Deserialize)]
+    struct RingBell(u32);
+
+    #[derive(Resource, Default)]
+    struct Rung(Vec<(Option<u64>, u32)>);
+
+    fn hear_bell(mut events: EventReader<NetworkEvent<RingBell>>, mut rung: ResMut<Rung>) {
+        for event in events.iter() {
+            rung.0.push((event.sender, event.0));
+        }
+    }
+
+    let mut harness = LoopbackHarness::with_apps(
+        2,
+        |server| {
+            server.add_network_event::<RingBell>();
+        },
+        |_, client| {
+            client.add_network_event::<RingBell>();
+            client.init_resource::<Rung>();
+            client.add_system(hear_bell);
+        },
+    );
+    assert!(harness.step_until(200, |harness| harness
+        .clients
+        .iter()
+        .all(|client| client.world.resource::<Roster>().0.len() == 2)));
+    let sender = harness.client(0).world.resource::<LocalClientId>().0;
+    harness.client(0).world.send_event(RingBell(3));
+    assert!(harness.step_until(200, |harness| harness.client(1).world.resource::<Rung>().0 == [(Some(sender), 3)]));
+    assert!(harness.client(0).world.resource::<Rung>().0.is_empty());
+}
+
+#[test]
+fn loopback_child_replicates_under_parent() {
+    let mut harness = LoopbackHarness::new(2);
+    harness.steps(20);
+    let parent = harness.spawn_cube(0, Vec3::new(1.0, 0.0, 0.0));
+    let child = harness.spawn_cube(0, Vec3::new(0.0, 1.0, 0.0));
+    harness.client(0).world.entity_mut(child).set_parent(parent);
+    assert!(harness.step_until(200, |harness| {
+        let world = &mut harness.client(1).world;
+        let mut query = world.query_filtered::<&GlobalTransform, (With<ModelInfo>, With<Parent>)>();
+        query
+            .iter(world)
+            .any(|transform| transform.translation().distance(Vec3::new(1.0, 1.0, 0.0)) < 0.001)
+    }));
+}
+
+#[test]
+fn loopback_server_refuses_bad_parents() {
+    let mut harness = LoopbackHarness::new(2);
+    harness.steps(20);
+    for x in [1.0, 2.0, 3.0] {
+        harness.spawn_cube(0, Vec3::new(x, 0.0, 0.0));
+    }
+    harness.spawn_cube(1, Vec3::new(4.0, 0.0, 0.0));
+    assert!(harness.step_until(200, |harness| server_model_count(harness) == 4));
+    let client_id = harness.client_id(0).unwrap();
+    let world = &mut harness.server.world;
+    let mut query = world.query::<(Entity, &ServerModel)>();
+    let mut model_at = |world: &World, x: f32| {
+        let mut query = query.iter(world);
+        ServerEntity(query.find(|(_, model)| model.0.transform.translation.x == x).unwrap().0)
+    };
+    let (a, b, c, other) = (model_at(world, 1.0), model_at(world, 2.0), model_at(world, 3.0), model_at(world, 4.0));
+    world.entity_mut(c.0).insert(InRoom("attic".to_string()));
+    let parent_of = |world: &World, entity: ServerEntity| world.get::<ServerModel>(entity.0).unwrap().0.parent;
+
+    ModelMsgServer::ParentChanged(a, Some(a)).server(world, client_id);
+    assert_eq!(parent_of(world, a), None);
+    ModelMsgServer::ParentChanged(b, Some(a)).server(world, client_id);
+    assert_eq!(parent_of(world, b), Some(a));
+    // That would make a loop.
+    ModelMsgServer::ParentChanged(a, Some(b)).server(world, client_id);
+    assert_eq!(parent_of(world, a), None);
+    // Another room, somebody else's and one that doesn't exist.
+    for parent in [c, other, ServerEntity(Entity::from_raw(1000))] {
+        ModelMsgServer::ParentChanged(a, Some(parent)).server(world, client_id);
+        assert_eq!(parent_of(world, a), None);
+    }
+}
+
+#[test]
+fn loopback_server_ignores_non_owners() {
+    let mut harness = LoopbackHarness::new(2);
+    harness.steps(20);
+    harness.spawn_cube(0, Vec3::new(1.0, 0.0, 0.0));
+    assert!(harness.step_until(200, |harness| harness.client_sees_model_at(
+        1,
+        Vec3::new(1.0, 0.0, 0.0),
+        0.001
+    )));
+    let (owner, other) = (harness.client_id(0).unwrap(), harness.client_id(1).unwrap());
+    let world = &mut harness.server.world;
+    let mut query = world.query_filtered::<Entity, With<ServerModel>>();
+    let model = ServerEntity(query.single(world));
+    let moved = Transform::from_translation(Vec3::new(5.0, 0.0, 0.0));
+    let changed = ModelData2 {
+        sequence: SequenceCounter::default().next(),
+        delta: ModelDelta::new(DirtyMask::ALL, &moved, Color128::new(1.0, 1.0, 1.0, 1.0), RenderLayer::LAYER1),
+        settled: true,
+    };
+    ModelMsgServer::ModelChanged(model, changed).server(world, other);
+    ModelMsgServer::ParentChanged(model, None).server(world, other);
+    OwnershipMsgServer::ReleaseOwnership(model).server(world, other);
+    OwnershipMsgServer::RequestOwnership(model).server(world, other);
+    assert_eq!(world.get::<ServerModel>(model.0).unwrap().0.transform.translation, Vec3::new(1.0, 0.0, 0.0));
+    assert_eq!(world.get::<Owner>(model.0), Some(&Owner(owner)));
+
+    harness.steps(20);
+    assert!(harness.client_sees_model_at(1, Vec3::new(1.0, 0.0, 0.0), 0.001));
+    let world = &mut harness.client(1).world;
+    let mut query = world.query_filtered::<(), (With<ModelInfo>, With<HasAuthority>)>();
+    assert_eq!(query.iter(world).count(), 0);
+}
+
+#[test]
+fn loopback_server_grants_only_models_in_the_same_room() {
+    let mut harness = LoopbackHarness::new(2);
+    harness.steps(20);
+    harness.spawn_cube(0, Vec3::new(1.0, 0.0, 0.0));
+    assert!(harness.step_until(200, |harness| server_model_count(harness) == 1));
+    let (owner, other) = (harness.client_id(0).unwrap(), harness.client_id(1).unwrap());
+    let world = &mut harness.server.world;
+    let mut query = world.query_filtered::<Entity, With<ServerModel>>();
+    let model = ServerEntity(query.single(world));
+    world.entity_mut(model.0).remove::<Owner>().insert(InRoom("attic".to_string()));
+    let mut query = world.query_filtered::<(Entity, &Owner), With<Player>>();
+    let player = ServerEntity(query.iter(world).find(|(_, player_owner)| player_owner.0 == owner).unwrap().0);
+
+    OwnershipMsgServer::RequestOwnership(model).server(world, other);
+    assert_eq!(world.get::<Owner>(model.0), None);
+    OwnershipMsgServer::RequestOwnership(player).server(world, other);
+    assert_eq!(world.get::<Owner>(player.0), Some(&Owner(owner)));
+    world.entity_mut(model.0).insert(InRoom("lobby".to_string()));
+    OwnershipMsgServer::RequestOwnership(model).server(world, other);
+    assert_eq!(world.get::<Owner>(model.0), Some(&Owner(other)));
+}
+
+#[test]
+fn loopback_server_reloads_saved_models() {
+    let path = std::env::temp_dir().join(format!("stereokit_bevy_world_{}.bin", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let config = PersistenceConfig {
+        path: Some(path.clone()),
+        interval: Duration::ZERO,
+    };
+
+    let server_config = config.clone();
+    let mut harness = LoopbackHarness::with_apps(
+        1,
+        |server| {
+            server.insert_resource(server_config);
+        },
+        |_, _| {},
+    );
+    harness.steps(20);
+    harness.spawn_cube(0, Vec3::new(0.0, 0.0, 3.0));
+    harness.steps(20);
+    drop(harness);
+    assert!(path.exists());
+
+    let mut harness = LoopbackHarness::with_apps(
+        1,
+        |server| {
+            server.insert_resource(config);
+        },
+        |_, _| {},
+    );
+    assert!(harness.step_until(200, |harness| harness.client_sees_model_at(
+        0,
+        Vec3::new(0.0, 0.0, 3.0),
+        0.001
+    )));
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn loopback_rooms_scope_models() {
+    let mut harness = LoopbackHarness::new(2);
+    harness.steps(20);
+    harness.client(1).world.send_event(CreateRoom("workshop".to_string()));
+    assert!(harness.step_until(200, |harness| {
+        let rooms = harness.client(1).world.resource::<RoomList>();
+        rooms.0.iter().any(|room| room.name == "workshop")
+    }));
+    harness.client(1).world.send_event(JoinRoom("workshop".to_string()));
+    assert!(harness.step_until(200, |harness| {
+        harness.client(1).world.resource::<CurrentRoom>().0.as_deref() == Some("workshop")
+    }));
+
+    harness.spawn_cube(0, Vec3::new(2.0, 0.0, 0.0));
+    harness.steps(50);
+    assert!(!harness.client_sees_model_at(1, Vec3::new(2.0, 0.0, 0.0), 0.001));
+
+    harness.client(1).world.send_event(JoinRoom("lobby".to_string()));
+    assert!(harness.step_until(200, |harness| harness.client_sees_model_at(
+        1,
+        Vec3::new(2.0, 0.0, 0.0),
+        0.001
+    )));
+}
+
+#[test]
+fn loopback_server_checks_room_names() {
+    let mut harness = LoopbackHarness::new(1);
+    harness.steps(20);
+    let client_id = harness.client_id(0).unwrap();
+    let world = &mut harness.server.world;
+    let room_count = |world: &World| world.resource::<Rooms>().list().len();
+    for name in ["", " attic", "at\ntic", "a".repeat(MAX_ROOM_NAME_LEN + 1).as_str(), "lobby"] {
+        RoomMsgServer::CreateRoom(name.to_string()).server(world, client_id);
+        assert_eq!(room_count(world), 1);
+    }
+    for i in 0..MAX_ROOMS_PER_CLIENT + 2 {
+        RoomMsgServer::CreateRoom(format!("room {i}")).server(world, client_id);
+    }
+    assert_eq!(room_count(world), 1 + MAX_ROOMS_PER_CLIENT);
+    // Many clients together still stop at the server's limit.
+    for i in 0..MAX_ROOMS * 2 {
+        let other = client_id + 1 + (i / MAX_ROOMS_PER_CLIENT) as u64;
+        RoomMsgServer::CreateRoom(format!("other room {i}")).server(world, other);
+    }
+    assert_eq!(room_count(world), MAX_ROOMS);
+}
+
+#[test]
+fn loopback_rooms_never_joined_go_with_their_creator() {
+    let mut harness = LoopbackHarness::new(2);
+    harness.steps(20);
+    for room in ["attic", "cellar"] {
+        harness.client(0).world.send_event(CreateRoom(room.to_string()));
+    }
+    harness.client(1).world.send_event(CreateRoom("workshop".to_string()));
+    assert!(harness.step_until(200, |harness| {
+        harness.server.world.resource::<Rooms>().list().len() == 4
+    }));
+    harness.disconnect(0);
+    assert!(harness.step_until(200, |harness| {
+        harness.server.world.resource::<Rooms>().list().len() == 2
+    }));
+    harness.steps(20);
+    assert_eq!(harness.server.world.resource::<Rooms>().list().len(), 2);
+}
+
+#[test]
+fn loopback_empty_rooms_are_removed() {
+    let mut harness = LoopbackHarness::new(1);
+    harness.steps(20);
+    harness.client(0).world.send_event(CreateRoom("workshop".to_string()));
+    harness.steps(20);
+    harness.client(0).world.send_event(JoinRoom("workshop".to_string()));
+    assert!(harness.step_until(200, |harness| {
+        harness.client(0).world.resource::<CurrentRoom>().0.as_deref() == Some("workshop")
+    }));
+    harness.client(0).world.send_event(JoinRoom("lobby".to_string()));
+    assert!(harness.step_until(200, |harness| {
+        harness.server.world.resource::<Rooms>().list().len() == 1
+    }));
+}
+
+#[test]
+fn loopback_owned_models_follow_their_client() {
+    let mut harness = LoopbackHarness::new(2);
+    harness.steps(20);
+    harness.spawn_cube(0, Vec3::new(2.0, 0.0, 0.0));
+    assert!(harness.step_until(200, |harness| harness.client_sees_model_at(
+        1,
+        Vec3::new(2.0, 0.0, 0.0),
+        0.001
+    )));
+    harness.client(0).world.send_event(CreateRoom("workshop".to_string()));
+    harness.steps(20);
+    harness.client(0).world.send_event(JoinRoom("workshop".to_string()));
+    assert!(harness.step_until(200, |harness| harness.client_model_count(1) == 0));
+    assert!(harness.client_sees_model_at(0, Vec3::new(2.0, 0.0, 0.0), 0.001));
+
+    harness.client(1).world.send_event(JoinRoom("workshop".to_string()));
+    assert!(harness.step_until(200, |harness| harness.client_sees_model_at(
+        1,
+        Vec3::new(2.0, 0.0, 0.0),
+        0.001
+    )));
+}
+
+#[test]
+fn loopback_models_follow_area_of_interest() {
+    let mut harness = LoopbackHarness::with_apps(
+        2,
+        |server| {
+            server.insert_resource(InterestConfig {
+                area: AreaOfInterest::Radius(2.0),
+                interval: Duration::ZERO,
+            });
+        },
+        |_, _| {},
+    );
+    harness.steps(20);
+    harness.spawn_cube(0, Vec3::new(5.0, 0.0, 0.0));
+    harness.steps(50);
+    assert_eq!(harness.client_model_count(1), 0);
+
+    let move_model = |harness: &mut LoopbackHarness, x: f32| {
+        let world = &mut harness.client(0).world;
+        let mut query = world.query_filtered::<&mut Transform, With<ModelInfo>>();
+        for mut transform in query.iter_mut(world) {
+            transform.translation.x = x;
+        }
+    };
+    move_model(&mut harness, 1.0);
+    assert!(harness.step_until(200, |harness| harness.client_sees_model_at(
+        1,
+        Vec3::new(1.0, 0.0, 0.0),
+        0.001
+    )));
+    move_model(&mut harness, 5.0);
+    assert!(harness.step_until(200, |harness| harness.client_model_count(1) == 0));
+}
+
+#[test]
+fn sessions_resume_only_after_leaving() {
+    let mut world = World::new();
+    let model = world.spawn_empty().id();
+    let mut sessions = Sessions::default();
+    let token = sessions.start(1);
+    assert_ne!(token, sessions.start(2));
+    // Still connected, nobody else can take it.
+    assert!(sessions.resume(token, 3).is_none());
+
+    sessions.leave(1, Some("workshop".to_string()), vec![model], Duration::from_secs(1));
+    let resumed = sessions.resume(token, 3).unwrap();
+    assert_eq!(resumed.previous, 1);
+    assert_eq!(resumed.room.as_deref(), Some("workshop"));
+    assert_eq!(resumed.owned, vec![model]);
+    assert!(sessions.resume(token, 4).is_none());
+
+    let window = Duration::from_secs(60);
+    sessions.leave(3, None, vec![], Duration::from_secs(10));
+    sessions.expire(window, Duration::from_secs(10) + window - Duration::from_millis(1));
+    assert_eq!(sessions.resume(token, 4).unwrap().previous, 3);
+
+    sessions.leave(4, None, vec![], Duration::from_secs(100));
+    sessions.expire(window, Duration::from_secs(100) + window);
+    assert!(sessions.resume(token, 5).is_none());
+}
+
+/// Whether each reconnect so far resumed the session.
+#[derive(Resource, Default)]
+struct Reconnects(Vec<bool>);
+
+fn record_reconnects(mut reconnected: EventReader<Reconnected>, mut reconnects: ResMut<Reconnects>) {
+    reconnects.0.extend(reconnected.iter().map(|reconnected| reconnected.resumed));
+}
+
+fn server_model_count(harness: &mut LoopbackHarness) -> usize {
+    let world = &mut harness.server.world;
+    let mut query = world.query_filtered::<(), With<ServerModel>>();
+    query.iter(world).count()
+}
+
+/// Drops the first client's connection while both see its cube, then moves the cube once it's back.
+fn reconnect_with_cube(server_setup: impl FnOnce(&mut bevy_app::App)) -> LoopbackHarness {
+    let mut harness = LoopbackHarness::with_apps(2, server_setup, |_, client| {
+        client.init_resource::<Reconnects>();
+        client.add_system(record_reconnects);
+    });
+    harness.steps(20);
+    let cube = harness.spawn_cube(0, Vec3::new(1.0, 0.0, 0.0));
+    assert!(harness.step_until(200, |harness| harness.client_sees_model_at(
+        1,
+        Vec3::new(1.0, 0.0, 0.0),
+        0.001
+    )));
+
+    harness.disconnect(0);
+    assert!(harness.step_until(200, |harness| !harness.client(0).world.resource::<Reconnects>().0.is_empty()));
+    harness.steps(20);
+    harness.client(0).world.get_mut::<Transform>(cube).unwrap().translation.y = 2.0;
+    assert!(harness.step_until(200, |harness| harness.client_sees_model_at(
+        1,
+        Vec3::new(1.0, 2.0, 0.0),
+        0.001
+    )));
+    harness
+}
+
+#[test]
+fn loopback_client_resumes_after_dropped_connection() {
+    let mut harness = reconnect_with_cube(|_| {});
+    assert_eq!(harness.client(0).world.resource::<Reconnects>().0, vec![true]);
+    assert_eq!(server_model_count(&mut harness), 1);
+    assert_eq!(harness.client_model_count(0), 1);
+    assert_eq!(harness.client_model_count(1), 1);
+}
+
+#[test]
+fn loopback_client_takes_its_models_back_after_session_expired() {
+    let mut harness = reconnect_with_cube(|server| {
+        // Over before the first reconnect attempt, half a second after the connection is lost.
+        server.insert_resource(SessionConfig {
+            resume_window: Duration::from_millis(200),
+        });
+    });
+    assert_eq!(harness.client(0).world.resource::<Reconnects>().0, vec![false]);
+    // Announced again, and matched to the model the server still had.
+    assert_eq!(server_model_count(&mut harness), 1);
+    assert_eq!(harness.client_model_count(0), 1);
+    assert_eq!(harness.client_model_count(1), 1);
+}
+
+#[test]
+fn loopback_upload_cut_off_by_reconnect_finishes() {
+    let config = AssetStreamConfig {
+        chunk_size: 100,
+        chunks_per_frame: 1,
+        ..Default::default()
+    };
+    let mut harness = LoopbackHarness::with_apps(
+        2,
+        |server| {
+            server.insert_resource(config.clone());
+        },
+        |_, client| {
+            client.insert_resource(config.clone());
+        },
+    );
+    harness.steps(20);
+    let bytes: Vec<u8> = (0..10_000u32).map(|i| (i * 13 % 251) as u8).collect();
+    let asset = AssetId::new(&bytes);
+    harness.client(0).world.spawn((
+        ModelInfo::Mem {
+            name: "cut off".to_string(),
+            mem: bytes.clone(),
+        },
+        TransformBundle::default(),
+        Color128::new(1.0, 1.0, 1.0, 1.0),
+        RenderLayer::LAYER1,
+        Networked,
+    ));
+    harness.steps(10);
+    assert_eq!(harness.server.world.resource::<ServerAssets>().get(asset), None);
+    harness.disconnect(0);
+    assert!(harness.step_until(400, |harness| {
+        harness.client(1).world.resource::<AssetCache>().0.get(&asset) == Some(&bytes)
+    }));
+}
+
+#[test]
+fn server_rtt_ignores_bogus_echoes() {
+    assert_eq!(measure_rtt(10.0, 0.5, 10.75), Some(Duration::from_secs_f64(0.25)));
+    assert_eq!(measure_rtt(10.0, 1.0, 10.75), None);
+    assert_eq!(measure_rtt(11.0, 0.0, 10.75), None);
+    assert_eq!(measure_rtt(-1e300, 0.0, 10.75), None);
+    assert_eq!(measure_rtt(f64::NAN, 0.0, 10.75), None);
+    assert_eq!(measure_rtt(10.0, f64::NEG_INFINITY, 10.75), None);
+}
+
+#[test]
+fn loopback_client_clock_matches_server() {
+    let mut harness = LoopbackHarness::new(1);
+    assert!(harness.step_until(200, |harness| harness.client(0).world.resource::<NetworkClock>().is_synced()));
+    harness.steps(20);
+    let clock = harness.client(0).world.resource::<NetworkClock>().clone();
+    let server_time = harness.server.world.resource::<ServerClock>().now();
+    // The server started first, so its clock is ahead of the client's.
+    assert!(clock.offset() > 0.0);
+    assert!((clock.server_time() - server_time).abs() < 0.05);
+}
+
+#[test]
+fn loopback_diagnostics_count_model_traffic() {
+    let mut harness = LoopbackHarness::new(2);
+    harness.steps(20);
+    harness.spawn_cube(0, Vec3::new(1.0, 0.0, 0.0));
+    assert!(harness.step_until(200, |harness| harness.client_sees_model_at(
+        1,
+        Vec3::new(1.0, 0.0, 0.0),
+        0.001
+    )));
+
+    let sent_bytes = |diagnostics: &NetworkDiagnostics, peer: Peer| {
+        diagnostics.connections[&peer]
+            .sent
+            .iter()
+            .filter(|((name, _), _)| *name == ModelMsgServer::get_type_name())
+            .map(|(_, traffic)| traffic.bytes)
+            .sum::<u64>()
+    };
+    let client = harness.client(0).world.resource::<NetworkDiagnostics>().clone();
+    assert!(sent_bytes(&client, Peer::Server) > 0);
+    let server = harness.server.world.resource::<NetworkDiagnostics>();
+    assert!(server
+        .connections
+        .iter()
+        .any(|(peer, connection)| matches!(peer, Peer::Client(_)) && !connection.received.is_empty()));
+}
+
+#[test]
+fn loopback_recorded_session_replays_into_server() {
+    let path = std::env::temp_dir().join(format!("stereokit_bevy_recording_{}.bin", std::process::id()));
+    let config = RecordingConfig {
+        path: Some(path.clone()),
+    };
+    let mut harness = LoopbackHarness::with_apps(
+        1,
+        |server| {
+            server.insert_resource(config);
+        },
+        |_, _| {},
+    );
+    harness.steps(20);
+    harness.spawn_cube(0, Vec3::new(0.0, 2.0, 0.0));
+    harness.steps(20);
+    drop(harness);
+
+    let recording = Recording::load(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert!(recording.recorded.iter().any(|message| {
+        message.direction == Direction::ToServer && message.type_name == ModelMsgServer::get_type_name()
+    }));
+    assert!(recording.recorded.iter().any(|message| message.direction == Direction::ToClient));
+
+    let mut harness = LoopbackHarness::with_apps(
+        0,
+        |server| {
+            let mut replay = Replay::new(recording, ReplayTarget::Server);
+            replay.speed = 4.0;
+            server.insert_resource(replay);
+        },
+        |_, _| {},
+    );
+    let replayed_model = |harness: &mut LoopbackHarness| {
+        let world = &mut harness.server.world;
+        let mut query = world.query::<&ServerModel>();
+        query
+            .iter(world)
+            .any(|model| model.0.transform.translation.distance(Vec3::new(0.0, 2.0, 0.0)) < 0.001)
+    };
+    assert!(harness.step_until(400, replayed_model));
+
+    // Back to before the model was added, then forward again.
+    harness.server.world.resource_mut::<Replay>().seek(0.0);
+    harness.server.world.resource_mut::<Replay>().paused = true;
+    harness.steps(1);
+    assert_eq!(server_model_count(&mut harness), 0);
+    let end = harness.server.world.resource::<Replay>().recording().duration();
+    harness.server.world.resource_mut::<Replay>().seek(end);
+    harness.steps(1);
+    assert!(replayed_model(&mut harness));
+}
+
+#[test]
+fn loopback_client_discovers_server() {
+    let port = UdpSocket::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+    // Too long to list whole.
+    let name = "test server ".repeat(10);
+    let config = DiscoveryConfig {
+        enabled: true,
+        name: name.clone(),
+        port,
+        query_address: IpAddr::V4(Ipv4Addr::LOCALHOST),
+        interval: Duration::from_millis(20),
+        ..Default::default()
+    };
+    let server_config = config.clone();
+    let mut harness = LoopbackHarness::with_apps(
+        1,
+        |server| {
+            server.insert_resource(server_config);
+        },
+        |_, client| {
+            client.insert_resource(config.clone());
+        },
+    );
+    assert!(harness.step_until(200, |harness| {
+        let servers = harness.client(0).world.resource::<DiscoveredServers>();
+        servers.0.iter().any(|server| server.players == 1)
+    }));
+    let port = harness.server.world.resource::<NetworkConfig>().port;
+    let servers = harness.client(0).world.resource::<DiscoveredServers>();
+    assert_eq!(servers.0.len(), 1);
+    let server = &servers.0[0];
+    assert_eq!(server.name, name[..MAX_NAME_LEN]);
+    assert_eq!(server.address.port(), port);
+    assert_eq!(server.rooms, 1);
+    assert_eq!(server.protocol_version, PROTOCOL_VERSION);
+    assert!(server.is_compatible());
+}
+
+#[test]
+fn loopback_admin_limits_clears_and_kicks() {
+    let mut harness = LoopbackHarness::with_apps(
+        2,
+        |server| {
+            server.world.resource_mut::<NetworkConfig>().max_players = Some(1);
+        },
+        |_, _| {},
+    );
+    let state = |harness: &mut LoopbackHarness, i: usize| harness.client(i).world.resource::<HandshakeState>().clone();
+    assert!(harness.step_until(200, |harness| {
+        let states = [state(harness, 0), state(harness, 1)];
+        states.contains(&HandshakeState::Accepted)
+            && states.contains(&HandshakeState::Rejected("the server is full".to_string()))
+    }));
+    let accepted = if state(&mut harness, 0) == HandshakeState::Accepted { 0 } else { 1 };
+    let players = connected_players(&harness.server.world);
+    assert_eq!(players.len(), 1);
+
+    harness.spawn_cube(accepted, Vec3::new(0.0, 1.0, 0.0));
+    let server_models = |harness: &mut LoopbackHarness| {
+        let world = &mut harness.server.world;
+        world.query::<&ServerModel>().iter(world).count()
+    };
+    assert!(harness.step_until(200, |harness| server_models(harness) == 1));
+    harness.server.world.send_event(ClearWorld);
+    assert!(harness.step_until(200, |harness| server_models(harness) == 0));
+
+    harness.server.world.send_event(KickClient {
+        client_id: players[0].client_id,
+        reason: "kicked".to_string(),
+    });
+    assert!(harness.step_until(200, |harness| {
+        state(harness, accepted) == HandshakeState::Rejected("kicked".to_string())
+    }));
+}
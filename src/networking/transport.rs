@@ -0,0 +1,350 @@
+use crate::networking::diagnostics::{record_dropped, record_received, NetworkDiagnostics, Peer};
+use crate::networking::handshake_client::HandshakeState;
+use crate::networking::handshake_server::AcceptedClients;
+use crate::networking::memory::{MemoryClient, MemoryServer};
+use crate::networking::recording::{Direction, Recorder};
+use crate::networking::room_server::Rooms;
+use crate::networking::simulator::{NetworkConditions, SimulatedLink};
+use bevy_ecs::prelude::{Res, ResMut, Resource, World};
+use bevy::log::warn;
+use bevy_ecs::system::{SystemParam, SystemState};
+use bevy_quinnet::client::Client;
+use bevy_quinnet::server::Server;
+use bevy_quinnet::shared::ClientId;
+use leknet::{ClientMessage, LekClient, LekServer, ServerMessage, TypeName};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::collections::HashMap;
+
+/// The client id the server uses for the local client of a host.
+pub const HOST_CLIENT_ID: ClientId = ClientId::MAX;
+
+type LocalMessage = Box<dyn FnOnce(&mut World) + Send + Sync>;
+
+/// Messages between the server and the local client of a host, they never touch the network.
+#[derive(Resource, Default)]
+pub struct HostQueue {
+    messages: Vec<LocalMessage>,
+}
+
+impl HostQueue {
+    fn push(&mut self, message: impl FnOnce(&mut World) + Send + Sync + 'static) {
+        self.messages.push(Box::new(message));
+    }
+}
+
+/// Decodes messages by type name, the same way leknet does when they come in. For messages that
+/// don't come through leknet, from a replay or a [`MemoryNetwork`](crate::networking::memory::MemoryNetwork).
+#[derive(Resource, Default)]
+pub(crate) struct MessageHandlers {
+    pub(crate) client: HashMap<String, fn(&mut World, &[u8])>,
+    pub(crate) server: HashMap<String, fn(&mut World, &[u8], ClientId)>,
+}
+
+impl MessageHandlers {
+    pub(crate) fn register<C: ClientMessage + TypeName, S: ServerMessage + TypeName>(&mut self) {
+        self.client.insert(C::get_type_name(), C::_client);
+        self.server.insert(S::get_type_name(), S::_server);
+    }
+}
+
+/// Sends to the server, or straight into the server half of this app when hosting.
+#[derive(SystemParam)]
+pub struct NetClient<'w> {
+    client: Option<ResMut<'w, Client>>,
+    memory: Option<Res<'w, MemoryClient>>,
+    diagnostics: Option<ResMut<'w, NetworkDiagnostics>>,
+    handshake: Option<Res<'w, HandshakeState>>,
+    host: Option<ResMut<'w, HostQueue>>,
+    recorder: Option<ResMut<'w, Recorder>>,
+    link: Option<ResMut<'w, SimulatedLink>>,
+    conditions: Option<Res<'w, NetworkConditions>>,
+}
+
+impl<'w> NetClient<'w> {
+    /// Connected and accepted by the server, only then should anything but the handshake be sent.
+    pub fn is_connected(&self) -> bool {
+        if self.host.is_some() {
+            return true;
+        }
+        let accepted = self
+            .handshake
+            .as_ref()
+            .map(|handshake| **handshake == HandshakeState::Accepted)
+            .unwrap_or(true);
+        if let Some(memory) = &self.memory {
+            return accepted && memory.id().is_some();
+        }
+        accepted
+            && self
+                .client
+                .as_ref()
+                .map(|client| client.get_connection().is_some())
+                .unwrap_or(false)
+    }
+
+    /// Does nothing while there's no connection.
+    pub fn send<M: ServerMessage + TypeName + Serialize + Clone + Send + Sync + 'static>(&mut self, msg: M) {
+        if let Some(host) = &mut self.host {
+            // The host's own client never goes through a connection, so its messages are recorded here.
+            if let Some(recorder) = &mut self.recorder {
+                let bytes = bincode::serialize(&msg).unwrap();
+                recorder.record::<M>(Direction::ToServer, HOST_CLIENT_ID, bytes);
+            }
+            host.push(move |world| msg.server(world, HOST_CLIENT_ID));
+            return;
+        }
+        if let Some(diagnostics) = &mut self.diagnostics {
+            let bytes = bincode::serialized_size(&msg).unwrap_or(0) as usize;
+            diagnostics.record_sent::<M>(Peer::Server, msg.channel_type(), bytes);
+        }
+        if let (Some(link), Some(conditions)) = (&mut self.link, &self.conditions) {
+            let channel_type = msg.channel_type();
+            link.queue(conditions, channel_type, move |world: &mut World| {
+                if let Some(memory) = world.get_resource::<MemoryClient>() {
+                    memory.send(&msg);
+                } else if let Some(connection) = world
+                    .get_resource_mut::<Client>()
+                    .and_then(|client| client.into_inner().get_connection_mut())
+                {
+                    connection.send_lek_msg(msg.clone()).ok();
+                }
+            });
+            return;
+        }
+        if let Some(memory) = &self.memory {
+            memory.send(&msg);
+            return;
+        }
+        if let Some(connection) = self
+            .client
+            .as_mut()
+            .and_then(|client| client.get_connection_mut())
+        {
+            if connection.send_lek_msg(msg).is_err() {
+                if let Some(diagnostics) = &mut self.diagnostics {
+                    diagnostics.connection(Peer::Server).dropped += 1;
+                }
+            }
+        }
+    }
+}
+
+/// Sends to clients, including the local client when hosting.
+#[derive(SystemParam)]
+pub struct NetServer<'w> {
+    server: Option<ResMut<'w, Server>>,
+    memory: Option<Res<'w, MemoryServer>>,
+    diagnostics: Option<ResMut<'w, NetworkDiagnostics>>,
+    accepted: Option<Res<'w, AcceptedClients>>,
+    rooms: Option<Res<'w, Rooms>>,
+    host: Option<ResMut<'w, HostQueue>>,
+    recorder: Option<ResMut<'w, Recorder>>,
+    link: Option<ResMut<'w, SimulatedLink>>,
+    conditions: Option<Res<'w, NetworkConditions>>,
+}
+
+impl<'w> NetServer<'w> {
+    /// The clients that passed the handshake.
+    pub fn clients(&self) -> Vec<ClientId> {
+        let mut clients = match (&self.server, &self.memory) {
+            (_, Some(memory)) => memory.0.clients(),
+            (Some(server), None) => server
+                .get_endpoint()
+                .map(|endpoint| endpoint.clients())
+                .unwrap_or_default(),
+            (None, None) => vec![],
+        };
+        if let Some(accepted) = &self.accepted {
+            clients.retain(|client_id| accepted.0.contains(client_id));
+        }
+        if self.host.is_some() {
+            clients.push(HOST_CLIENT_ID);
+        }
+        clients
+    }
+
+    /// The clients in `room`.
+    pub fn room_members(&self, room: &str) -> Vec<ClientId> {
+        let mut clients = self.clients();
+        if let Some(rooms) = &self.rooms {
+            clients.retain(|client_id| rooms.contains(room, *client_id));
+        }
+        clients
+    }
+
+    pub fn room_of(&self, client_id: ClientId) -> Option<String> {
+        self.rooms
+            .as_ref()
+            .and_then(|rooms| rooms.room_of(client_id))
+            .map(str::to_string)
+    }
+
+    pub fn send<M: ClientMessage + TypeName + Serialize + Clone + Send + Sync + 'static>(
+        &mut self,
+        client_id: ClientId,
+        msg: M,
+    ) {
+        if let Some(recorder) = &mut self.recorder {
+            let bytes = bincode::serialize(&msg).unwrap();
+            recorder.record::<M>(Direction::ToClient, client_id, bytes);
+        }
+        if client_id == HOST_CLIENT_ID {
+            if let Some(host) = &mut self.host {
+                host.push(move |world| msg.client(world));
+            }
+            return;
+        }
+        if let Some(diagnostics) = &mut self.diagnostics {
+            let bytes = bincode::serialized_size(&msg).unwrap_or(0) as usize;
+            diagnostics.record_sent::<M>(Peer::Client(client_id), msg.channel_type(), bytes);
+        }
+        if let (Some(link), Some(conditions)) = (&mut self.link, &self.conditions) {
+            let channel_type = msg.channel_type();
+            link.queue(conditions, channel_type, move |world: &mut World| {
+                // The client may have left while the message was held back.
+                if let Some(memory) = world.get_resource::<MemoryServer>() {
+                    memory.0.send_to_client(client_id, &msg);
+                } else if let Some(endpoint) = world
+                    .get_resource_mut::<Server>()
+                    .and_then(|server| server.into_inner().get_endpoint_mut())
+                {
+                    endpoint.send_lek_msg(client_id, msg.clone()).ok();
+                }
+            });
+            return;
+        }
+        if let Some(memory) = &self.memory {
+            memory.0.send_to_client(client_id, &msg);
+            return;
+        }
+        let sent = self
+            .server
+            .as_mut()
+            .and_then(|server| server.get_endpoint_mut())
+            .map(|endpoint| endpoint.send_lek_msg(client_id, msg).is_ok());
+        if sent == Some(false) {
+            if let Some(diagnostics) = &mut self.diagnostics {
+                diagnostics.connection(Peer::Client(client_id)).dropped += 1;
+            }
+        }
+    }
+
+    /// Sends `msg` to every client except `except`.
+    pub fn broadcast<M: ClientMessage + TypeName + Serialize + Clone + Send + Sync + 'static>(
+        &mut self,
+        except: Option<ClientId>,
+        msg: M,
+    ) {
+        for client_id in self.clients() {
+            if Some(client_id) == except {
+                continue;
+            }
+            self.send(client_id, msg.clone());
+        }
+    }
+
+    /// Sends `msg` to everyone in `room` except `except`.
+    pub fn broadcast_room<M: ClientMessage + TypeName + Serialize + Clone + Send + Sync + 'static>(
+        &mut self,
+        room: &str,
+        except: Option<ClientId>,
+        msg: M,
+    ) {
+        for client_id in self.room_members(room) {
+            if Some(client_id) == except {
+                continue;
+            }
+            self.send(client_id, msg.clone());
+        }
+    }
+
+    /// Sends `msg` to everyone in the same room as `client_id`, except that client.
+    pub fn broadcast_from<M: ClientMessage + TypeName + Serialize + Clone + Send + Sync + 'static>(
+        &mut self,
+        client_id: ClientId,
+        msg: M,
+    ) {
+        if let Some(room) = self.room_of(client_id) {
+            self.broadcast_room(&room, Some(client_id), msg);
+        }
+    }
+
+    pub fn disconnect(&mut self, client_id: ClientId) {
+        if client_id == HOST_CLIENT_ID {
+            return;
+        }
+        if let Some(memory) = &self.memory {
+            memory.0.disconnect(client_id);
+        } else if let Some(endpoint) = self.server.as_mut().and_then(|server| server.get_endpoint_mut()) {
+            endpoint.disconnect_client(client_id).ok();
+        }
+    }
+}
+
+/// Delivers the messages the host's server and client halves sent each other last frame.
+pub(crate) fn run_host_messages(world: &mut World) {
+    let messages = std::mem::take(&mut world.resource_mut::<HostQueue>().messages);
+    for message in messages {
+        message(world);
+    }
+}
+
+/// Decodes a message from a client that passed the handshake. Anything else a client sends before
+/// it's accepted, or after it was kicked, is dropped unread.
+pub(crate) fn receive_from_client<M: ServerMessage + TypeName + DeserializeOwned>(
+    world: &mut World,
+    msg_bytes: &[u8],
+    client_id: ClientId,
+) -> Option<M> {
+    let accepted = world
+        .get_resource::<AcceptedClients>()
+        .map_or(true, |accepted| accepted.0.contains(&client_id));
+    if !accepted {
+        record_dropped(world, Peer::Client(client_id));
+        return None;
+    }
+    receive_handshake_from_client(world, msg_bytes, client_id)
+}
+
+/// Like [`receive_from_client`], for the handshake itself. A client whose message doesn't decode
+/// is broken or hostile, either way it's disconnected.
+pub(crate) fn receive_handshake_from_client<M: ServerMessage + TypeName + DeserializeOwned>(
+    world: &mut World,
+    msg_bytes: &[u8],
+    client_id: ClientId,
+) -> Option<M> {
+    match bincode::deserialize::<M>(msg_bytes) {
+        Ok(msg) => {
+            record_received::<M>(world, Peer::Client(client_id), msg.channel_type(), msg_bytes);
+            Some(msg)
+        }
+        Err(error) => {
+            warn!("disconnecting client {client_id}, its {} didn't decode: {error}", M::get_type_name());
+            if let Some(mut accepted) = world.get_resource_mut::<AcceptedClients>() {
+                accepted.0.remove(&client_id);
+            }
+            let mut system_state: SystemState<NetServer> = SystemState::new(world);
+            system_state.get_mut(world).disconnect(client_id);
+            None
+        }
+    }
+}
+
+/// Decodes a message from the server, one that doesn't decode is dropped.
+pub(crate) fn receive_from_server<M: ClientMessage + TypeName + DeserializeOwned>(
+    world: &mut World,
+    msg_bytes: &[u8],
+) -> Option<M> {
+    match bincode::deserialize::<M>(msg_bytes) {
+        Ok(msg) => {
+            record_received::<M>(world, Peer::Server, msg.channel_type(), msg_bytes);
+            Some(msg)
+        }
+        Err(error) => {
+            warn!("dropped a {} from the server that didn't decode: {error}", M::get_type_name());
+            record_dropped(world, Peer::Server);
+            None
+        }
+    }
+}
@@ -0,0 +1,183 @@
+use crate::networking::interpolation::{Sequence, SequenceCounter};
+use crate::networking::transport::NetClient;
+use crate::networking::voice_server::VoiceMsgServer;
+use bevy_app::{App, Plugin};
+use bevy_ecs::prelude::{Commands, Component, Entity, Local, Query, Res, ResMut, Resource};
+use bevy_transform::prelude::GlobalTransform;
+use stereokit::{Sk, Sound, SoundInstance, StereoKitMultiThread};
+
+/// StereoKit records and plays at 48kHz mono.
+pub const SAMPLE_RATE: usize = 48_000;
+/// Voice is sent at a third of that, plenty for speech.
+const DECIMATION: usize = 3;
+/// 20ms of audio per message.
+pub const FRAME_SAMPLES: usize = SAMPLE_RATE / 50;
+/// An encoded frame, the server drops longer ones.
+pub const FRAME_BYTES: usize = FRAME_SAMPLES / DECIMATION;
+/// Received audio beyond this much is dropped so a stalled stream can't grow without bound.
+const MAX_BUFFERED_SAMPLES: usize = SAMPLE_RATE / 2;
+
+/// Packs a frame into one byte per sample with mu-law companding, after dropping to 16kHz.
+pub fn encode_frame(samples: &[f32]) -> Vec<u8> {
+    samples
+        .chunks(DECIMATION)
+        .map(|chunk| mu_law_encode(chunk.iter().sum::<f32>() / chunk.len() as f32))
+        .collect()
+}
+
+/// Back to 48kHz samples, linearly interpolated between the sent ones.
+pub fn decode_frame(bytes: &[u8]) -> Vec<f32> {
+    let samples: Vec<f32> = bytes.iter().map(|byte| mu_law_decode(*byte)).collect();
+    let mut decoded = Vec::with_capacity(samples.len() * DECIMATION);
+    for (i, sample) in samples.iter().enumerate() {
+        let next = samples.get(i + 1).unwrap_or(sample);
+        for step in 0..DECIMATION {
+            let t = step as f32 / DECIMATION as f32;
+            decoded.push(sample + (next - sample) * t);
+        }
+    }
+    decoded
+}
+
+const MU: f32 = 255.0;
+
+fn mu_law_encode(sample: f32) -> u8 {
+    let sample = sample.clamp(-1.0, 1.0);
+    let compressed = sample.signum() * (1.0 + MU * sample.abs()).ln() / (1.0 + MU).ln();
+    ((compressed + 1.0) * 0.5 * 255.0).round() as u8
+}
+
+fn mu_law_decode(byte: u8) -> f32 {
+    let compressed = byte as f32 / 255.0 * 2.0 - 1.0;
+    compressed.signum() * ((1.0 + MU).powf(compressed.abs()) - 1.0) / MU
+}
+
+/// Mute and push-to-talk for the local microphone, and the volume of everyone else.
+#[derive(Resource, Clone, Debug)]
+pub struct VoiceControls {
+    pub muted: bool,
+    /// Only send while `talking` is set, bind it to a button of your choice.
+    pub push_to_talk: bool,
+    pub talking: bool,
+    pub volume: f32,
+}
+
+impl Default for VoiceControls {
+    fn default() -> Self {
+        Self {
+            muted: false,
+            push_to_talk: false,
+            talking: false,
+            volume: 1.0,
+        }
+    }
+}
+
+/// Received voice of a remote player waiting to be played.
+#[derive(Component, Default, Debug)]
+pub struct VoiceBuffer {
+    pub samples: Vec<f32>,
+    pub last: Option<Sequence>,
+}
+
+impl VoiceBuffer {
+    pub fn push(&mut self, sequence: Sequence, bytes: &[u8]) {
+        if let Some(last) = self.last {
+            if !sequence.is_newer_than(last) {
+                return;
+            }
+        }
+        self.last = Some(sequence);
+        self.samples.extend(decode_frame(bytes));
+        let overflow = self.samples.len().saturating_sub(MAX_BUFFERED_SAMPLES);
+        self.samples.drain(..overflow);
+    }
+}
+
+/// Put this on a remote player to stop hearing them.
+#[derive(Component)]
+pub struct VoiceMuted;
+
+#[derive(Component)]
+pub struct VoicePlayback {
+    sound: Sound,
+    instance: SoundInstance,
+}
+
+/// Sends the microphone to the server and plays everyone else's voice from their head.
+/// Needs StereoKit, so it goes with the client or host plugins.
+pub struct VoicePlugin;
+
+impl Plugin for VoicePlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<VoiceControls>();
+        app.add_system(capture_voice);
+        app.add_system(play_voice);
+    }
+}
+
+#[derive(Default)]
+struct MicCapture {
+    started: bool,
+    pending: Vec<f32>,
+}
+
+fn capture_voice(
+    sk: Res<Sk>,
+    controls: Res<VoiceControls>,
+    mut capture: Local<MicCapture>,
+    mut client: NetClient,
+    mut sequence_counter: ResMut<SequenceCounter>,
+) {
+    if !capture.started {
+        capture.started = sk.mic_start(None);
+        return;
+    }
+    let stream = sk.mic_get_stream();
+    let mut samples = vec![0.0; sk.sound_unread_samples(&stream) as usize];
+    let read = sk.sound_read_samples(&stream, &mut samples) as usize;
+    let live = !controls.muted && (!controls.push_to_talk || controls.talking) && client.is_connected();
+    if !live {
+        capture.pending.clear();
+        return;
+    }
+    capture.pending.extend_from_slice(&samples[..read]);
+    while capture.pending.len() >= FRAME_SAMPLES {
+        let frame: Vec<f32> = capture.pending.drain(..FRAME_SAMPLES).collect();
+        client.send(VoiceMsgServer::Frame(sequence_counter.next(), encode_frame(&frame)));
+    }
+}
+
+fn play_voice(
+    sk: Res<Sk>,
+    controls: Res<VoiceControls>,
+    mut query: Query<(
+        Entity,
+        &mut VoiceBuffer,
+        &GlobalTransform,
+        Option<&VoicePlayback>,
+        Option<&VoiceMuted>,
+    )>,
+    mut commands: Commands,
+) {
+    for (entity, mut buffer, transform, playback, muted) in query.iter_mut() {
+        let position = transform.translation();
+        let playback = match playback {
+            Some(playback) => playback,
+            None => {
+                let sound = sk.sound_create_stream(0.5);
+                let instance = sk.sound_play(&sound, position, controls.volume);
+                commands.entity(entity).insert(VoicePlayback { sound, instance });
+                continue;
+            }
+        };
+        if muted.is_some() {
+            buffer.samples.clear();
+            continue;
+        }
+        sk.sound_write_samples(&playback.sound, &buffer.samples);
+        buffer.samples.clear();
+        sk.sound_inst_set_position(playback.instance, position);
+        sk.sound_inst_set_volume(playback.instance, controls.volume);
+    }
+}
@@ -0,0 +1,63 @@
+use crate::networking::interpolation::Sequence;
+use crate::networking::transport::receive_from_server;
+use crate::networking::voice::VoiceBuffer;
+use bevy_app::App;
+use bevy_ecs::prelude::World;
+use bevy_quinnet::shared::channel::ChannelType;
+use leknet::{ClientMessage, EntityMap, ServerEntity, TypeName};
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum VoiceMsgClient {
+    /// One encoded frame spoken by the player entity.
+    Frame(ServerEntity, Sequence, Vec<u8>),
+}
+
+impl TypeName for VoiceMsgClient {
+    fn get_type_name() -> String {
+        "stereokit_bevy::networking::VoiceMsgClient".to_string()
+    }
+}
+
+impl ClientMessage for VoiceMsgClient {
+    fn client(self, world: &mut World) {
+        match self {
+            VoiceMsgClient::Frame(server_entity, sequence, frame) => {
+                frame_msg(world, server_entity, sequence, frame)
+            }
+        }
+    }
+
+    fn _client(world: &mut World, msg_bytes: &[u8]) {
+        if let Some(msg) = receive_from_server::<Self>(world, msg_bytes) {
+            msg.client(world)
+        }
+    }
+
+    fn channel_type(&self) -> ChannelType {
+        match self {
+            VoiceMsgClient::Frame(_, _, _) => ChannelType::Unreliable,
+        }
+    }
+
+    fn plugin(_app: &mut App) {}
+}
+
+fn frame_msg(world: &mut World, server_entity: ServerEntity, sequence: Sequence, frame: Vec<u8>) {
+    let client_entity = match world.resource::<EntityMap>().get_by_right(&server_entity) {
+        None => return,
+        Some(client_entity) => client_entity.0,
+    };
+    let mut entity = match world.get_entity_mut(client_entity) {
+        None => return,
+        Some(entity) => entity,
+    };
+    match entity.get_mut::<VoiceBuffer>() {
+        Some(mut buffer) => buffer.push(sequence, &frame),
+        None => {
+            let mut buffer = VoiceBuffer::default();
+            buffer.push(sequence, &frame);
+            entity.insert(buffer);
+        }
+    }
+}
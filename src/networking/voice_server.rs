@@ -0,0 +1,85 @@
+use crate::networking::interpolation::{accept_sequence_of, Sequence, SequenceCounter};
+use crate::networking::transport::{receive_from_client, NetServer};
+use crate::networking::voice::FRAME_BYTES;
+use crate::networking::voice_client::VoiceMsgClient;
+use crate::networking::{OnServer, PlayerId};
+use bevy_app::App;
+use bevy_ecs::prelude::{Component, Entity, ResMut, With, World};
+use bevy_ecs::system::SystemState;
+use bevy_quinnet::shared::channel::ChannelType;
+use bevy_quinnet::shared::ClientId;
+use leknet::{ServerEntity, ServerMessage, TypeName};
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum VoiceMsgServer {
+    /// One encoded frame from the sender's microphone.
+    Frame(Sequence, Vec<u8>),
+}
+
+impl TypeName for VoiceMsgServer {
+    fn get_type_name() -> String {
+        "stereokit_bevy::networking::VoiceMsgServer".to_string()
+    }
+}
+
+impl ServerMessage for VoiceMsgServer {
+    fn server(self, world: &mut World, client_id: ClientId) {
+        match self {
+            VoiceMsgServer::Frame(sequence, frame) => frame_msg(world, client_id, sequence, frame),
+        }
+    }
+
+    fn _server(world: &mut World, msg_bytes: &[u8], client_id: ClientId) {
+        if let Some(msg) = receive_from_client::<Self>(world, msg_bytes, client_id) {
+            msg.server(world, client_id);
+        }
+    }
+
+    fn channel_type(&self) -> ChannelType {
+        match self {
+            VoiceMsgServer::Frame(_, _) => ChannelType::Unreliable,
+        }
+    }
+
+    fn plugin(_app: &mut App) {}
+}
+
+/// The sequence of the last voice frame relayed for a player.
+#[derive(Component, Clone, Copy, Debug)]
+struct VoiceSequence(Sequence);
+
+impl From<Sequence> for VoiceSequence {
+    fn from(sequence: Sequence) -> Self {
+        Self(sequence)
+    }
+}
+
+impl From<VoiceSequence> for Sequence {
+    fn from(sequence: VoiceSequence) -> Self {
+        sequence.0
+    }
+}
+
+/// Relays the frame as coming from the sender's player, so clients know where to play it.
+fn frame_msg(world: &mut World, client_id: ClientId, sequence: Sequence, frame: Vec<u8>) {
+    if frame.len() > FRAME_BYTES {
+        return;
+    }
+    let mut players = world.query_filtered::<(Entity, &PlayerId), With<OnServer>>();
+    let player = players
+        .iter(world)
+        .find(|(_, player_id)| player_id.0 == client_id)
+        .map(|(entity, _)| ServerEntity(entity));
+    let player = match player {
+        None => return,
+        Some(player) => player,
+    };
+    if !accept_sequence_of::<VoiceSequence>(world, client_id, player, sequence) {
+        return;
+    }
+    let mut system_state: SystemState<(NetServer, ResMut<SequenceCounter>)> = SystemState::new(world);
+    let (mut server, mut sequence_counter) = system_state.get_mut(world);
+    let sequence = sequence_counter.next();
+    server.broadcast_from(client_id, VoiceMsgClient::Frame(player, sequence, frame));
+}